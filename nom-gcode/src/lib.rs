@@ -16,22 +16,73 @@ pub use parse_args::parse_kv_arg;
 mod parse_comments;
 pub use parse_comments::*;
 
+mod parse_extended;
+pub use parse_extended::*;
+
+mod parse_checksum;
+pub use parse_checksum::*;
+
 mod parse_gcode;
 pub use parse_gcode::parse_gcode;
 
+mod rewrite;
+pub use rewrite::*;
+
 #[derive(Error, Debug)]
 pub enum GCodeParseError {
-    #[error("Invalid GCode. GCodes must start with a letter, a number and a space. Got: {0}")]
-    InvalidGCode(String),
-    #[error("Badly formatted GCode arguments. Got: {0}")]
-    InvalidArguments(String),
-    #[error("Badly formatted GCode comment. Got: {0}")]
-    InvalidComment(String),
+    #[error("Invalid GCode. GCodes must start with a letter, a number and a space. Got: {line}")]
+    InvalidGCode {
+        line: String,
+        /// Byte offset in `line` where parsing stopped.
+        offset: usize,
+    },
+    #[error("Badly formatted GCode arguments. Got: {line}")]
+    InvalidArguments {
+        line: String,
+        /// Byte offset in `line` where parsing stopped.
+        offset: usize,
+    },
+    #[error("Badly formatted GCode comment. Got: {line}")]
+    InvalidComment {
+        line: String,
+        /// Byte offset in `line` where parsing stopped.
+        offset: usize,
+    },
+    #[error("GCode checksum mismatch. Expected: {expected}, computed: {computed}. Got: {line}")]
+    ChecksumMismatch {
+        line: String,
+        expected: u8,
+        computed: u8,
+        /// Byte offset of the `*` in `line`.
+        offset: usize,
+    },
+}
+
+impl GCodeParseError {
+    /// Byte offset in the line where the error was found.
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::InvalidGCode { offset, .. }
+            | Self::InvalidArguments { offset, .. }
+            | Self::InvalidComment { offset, .. }
+            | Self::ChecksumMismatch { offset, .. } => *offset,
+        }
+    }
+}
+
+/// How a comment was written, so that it can be printed back unchanged.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CommentDelimiter {
+    /// `; comment` running to the end of the line
+    Semicolon,
+    /// `(comment)`
+    Parentheses,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Comment<'r>(
-    pub &'r str
+    pub &'r str,
+    pub CommentDelimiter,
 );
 
 #[derive(Debug, PartialEq, Clone)]
@@ -43,7 +94,10 @@ pub enum GCodeLine<'r> {
     /// http://linuxcnc.org/docs/html/gcode/overview.html
     FileDemarcator,
     GCode(GCode<'r>),
+    /// A line that is neither a GCode nor a Klipper-style extended command.
     GCodeMacro(&'r str),
+    /// Klipper-style extended command (eg. "SET_VELOCITY_LIMIT ACCEL=3000").
+    Extended(ExtendedCommand<'r>),
     Comment(Comment<'r>),
 }
 
@@ -56,6 +110,8 @@ impl<'r> From<Comment<'r>> for GCodeLine<'r> {
 #[derive(Debug, PartialEq, Clone)]
 pub struct GCode<'r> {
     pub line_number: Option<u32>,
+    /// `*NN` suffix, already validated against the line.
+    pub checksum: Option<u8>,
     pub mnemonic: Mnemonic,
     pub major: u32,
     pub minor: u32,
@@ -71,24 +127,65 @@ pub enum ArgOrComment<'r> {
 
 pub type KeyValue = (char, Option<f32>);
 
+/*
+ * Shortest representation, limited to 5 decimals so that computed values
+ * (eg. scaled E) do not show f32 noise like "0.094877996".
+ */
+fn format_value(v: f32) -> String {
+    let s = v.to_string();
+    match s.find('.') {
+        Some(dot) if s.len() - dot - 1 > 5 => {
+            let s = format!("{:.5}", v);
+            let s = s.trim_end_matches('0').trim_end_matches('.');
+            if s == "-0" { "0".to_string() } else { s.to_string() }
+        }
+        _ => s,
+    }
+}
+
 impl<'r> fmt::Display for GCode<'r> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let gcode = format!("{}{}.{}", self.mnemonic, self.major, self.minor);
-
-        let mut words = vec![gcode];
-
-        let arg_words = self.arguments()
-            .map(|(k, v)| {
-                format!("{}{}", k, v.map(|f| f.to_string()).unwrap_or("".to_string()))
-            });
-
-        words.extend(arg_words);
-
-        if let Some(text) = self.text() {
-            words.push(text.to_string());
-        };
-
-        write!(f, "{}", words.join(" "))
+        let mut words = vec![];
+        let mut trailing = None;
+
+        if let Some(line_number) = self.line_number {
+            words.push(format!("N{}", line_number));
+        }
+
+        if self.minor == 0 {
+            words.push(format!("{}{}", self.mnemonic, self.major));
+        } else {
+            words.push(format!("{}{}.{}", self.mnemonic, self.major, self.minor));
+        }
+
+        for ac in self.args_or_comments_iter() {
+            let word = match ac {
+                ArgOrComment::KeyValue((k, v)) => {
+                    format!("{}{}", k, v.map(format_value).unwrap_or("".to_string()))
+                }
+                ArgOrComment::TextArg(text) => text.to_string(),
+                // A semicolon comment runs to the end of the line
+                ArgOrComment::Comment(Comment(text, CommentDelimiter::Semicolon)) => {
+                    trailing = Some(format!(";{}", text));
+                    continue;
+                }
+                ArgOrComment::Comment(Comment(text, CommentDelimiter::Parentheses)) => {
+                    format!("({})", text)
+                }
+            };
+            words.push(word);
+        }
+
+        let code = words.join(" ");
+        write!(f, "{}", code)?;
+        // The arguments may have changed since the checksum was parsed
+        if self.checksum.is_some() {
+            write!(f, "*{}", checksum(&code))?;
+        }
+        if let Some(trailing) = trailing {
+            write!(f, " {}", trailing)?;
+        }
+        Ok(())
     }
 }
 
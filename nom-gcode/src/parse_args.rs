@@ -9,7 +9,7 @@ use nom::sequence::*;
 use nom::multi::*;
 use nom::AsChar;
 
-use crate::{seimcolon_comment, Comment, comment};
+use crate::{seimcolon_comment, Comment, CommentDelimiter, comment};
 
 use super::{
     ArgOrComment,
@@ -57,7 +57,7 @@ fn combine_args_and_comments<'r>(
 
     if let Some(final_comment) = final_comment {
         args_or_comments.push(
-            ArgOrComment::Comment(Comment(final_comment)),
+            ArgOrComment::Comment(Comment(final_comment, CommentDelimiter::Semicolon)),
         );
     }
 
@@ -94,10 +94,11 @@ pub fn parse_args<'r>(
                             space1,
                             key_value_arg,
                         ),
-                        map(comment, |c| ArgOrComment::Comment(c)),
+                        // Comments may be separated from the args by spaces (eg. "G1 X1 ; move")
+                        map(preceded(space0, comment), |c| ArgOrComment::Comment(c)),
                     )),
                 ),
-                opt(seimcolon_comment),
+                opt(preceded(space0, seimcolon_comment)),
             )),
             combine_args_and_comments,
         )(input)
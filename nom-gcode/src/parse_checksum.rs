@@ -0,0 +1,74 @@
+/// XOR checksum of every byte of `input`, as used by the RepRap serial protocol
+/// (eg. the `71` in `N123 G1 X10*71`).
+pub fn checksum(input: &str) -> u8 {
+    input.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+pub struct ChecksumSplit<'r> {
+    /// Everything before the `*`, the part the checksum is computed over.
+    pub checked: &'r str,
+    pub checksum: u8,
+    /// Whatever follows the checksum digits (eg. a line ending).
+    pub rest: &'r str,
+}
+
+/*
+ * Start of a `;` comment, skipping any `;` inside a `(...)` comment
+ */
+fn code_end(input: &str) -> usize {
+    let mut in_parens = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '(' => in_parens = true,
+            ')' => in_parens = false,
+            ';' if !in_parens => return i,
+            _ => {}
+        }
+    }
+    input.len()
+}
+
+/*
+ * Splits a trailing `*NN` checksum off a line. Hosts only send checksums
+ * together with an `N` line number, so other lines are left alone (eg.
+ * `M117 Layer 3*4` is a message). Only the last `*` before any `;` comment is
+ * considered, and it must be followed by 1-3 digits.
+ */
+// #[inline(always)]
+pub fn split_checksum<'r>(input: &'r str) -> Option<ChecksumSplit<'r>> {
+    let numbered = input
+        .trim_start_matches([' ', '\t'])
+        .strip_prefix('N')
+        .is_some_and(|n| n.starts_with(|c: char| c.is_ascii_digit()));
+    if !numbered {
+        return None;
+    }
+
+    let star = input[..code_end(input)].rfind('*')?;
+
+    let after = &input[star + 1..];
+    let digits = after
+        .bytes()
+        .take_while(u8::is_ascii_digit)
+        .count();
+    if digits == 0 || digits > 3 {
+        return None;
+    }
+
+    let rest = &after[digits..];
+    let trailing = rest.trim_start_matches([' ', '\t']);
+    if !trailing.is_empty() && !trailing.starts_with([';', '\r', '\n']) {
+        return None;
+    }
+
+    let checksum = after[..digits].parse::<u16>().ok()?;
+    if checksum > u8::MAX as u16 {
+        return None;
+    }
+
+    Some(ChecksumSplit {
+        checked: &input[..star],
+        checksum: checksum as u8,
+        rest,
+    })
+}
@@ -44,6 +44,7 @@ pub fn parse_command<'r>(input: &'r str) -> IResult<&'r str, GCode<'r>> {
 
             let gcode = GCode {
                 line_number,
+                checksum: None,
                 mnemonic,
                 major,
                 minor: minor.unwrap_or(0),
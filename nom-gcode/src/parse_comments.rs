@@ -9,6 +9,7 @@ use nom::sequence::*;
 use nom::multi::*;
 use super::{
     Comment,
+    CommentDelimiter,
 };
 
 // #[inline(always)]
@@ -76,9 +77,9 @@ pub fn seimcolon_comment<'r>(input: &'r str,) -> IResult<&'r str, &'r str> {
 
 // #[inline(always)]
 pub fn comment<'r>(input: &'r str) -> IResult<&'r str, Comment<'r>> {
-    map(
-        alt((seimcolon_comment, parentheses_comment)),
-        |comment| Comment(comment),
-    )(input)
+    alt((
+        map(seimcolon_comment, |c| Comment(c, CommentDelimiter::Semicolon)),
+        map(parentheses_comment, |c| Comment(c, CommentDelimiter::Parentheses)),
+    ))(input)
 }
 
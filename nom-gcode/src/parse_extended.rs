@@ -0,0 +1,79 @@
+use nom::{
+    IResult,
+    character::complete::*,
+    bytes::complete::*,
+};
+use nom::branch::*;
+use nom::combinator::*;
+use nom::sequence::*;
+use nom::multi::*;
+
+use super::seimcolon_comment;
+
+/// Klipper-style extended command, eg. `SET_PRESSURE_ADVANCE ADVANCE=0.04`.
+///
+/// https://www.klipper3d.org/G-Codes.html
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExtendedCommand<'r> {
+    pub name: &'r str,
+    /// `KEY=VALUE` pairs, in order. Quotes around values are stripped.
+    pub params: Vec<(&'r str, &'r str)>,
+}
+
+impl<'r> ExtendedCommand<'r> {
+    /// Parameter lookup, case-insensitive as in Klipper.
+    pub fn param(&self, key: &str) -> Option<&'r str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| *v)
+    }
+
+    pub fn param_f32(&self, key: &str) -> Option<Result<f32, std::num::ParseFloatError>> {
+        self.param(key).map(|v| v.parse::<f32>())
+    }
+
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(
+        pair(
+            satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        ),
+    )(input)
+}
+
+fn param_value(input: &str) -> IResult<&str, &str> {
+    alt((
+        delimited(char('"'), take_till(|c| c == '"'), char('"')),
+        is_not(" \t\n\r;"),
+        // Empty value (eg. "NAME=")
+        success(""),
+    ))(input)
+}
+
+fn param(input: &str) -> IResult<&str, (&str, &str)> {
+    separated_pair(
+        identifier,
+        char('='),
+        param_value,
+    )(input)
+}
+
+// #[inline(always)]
+pub fn extended_command<'r>(input: &'r str) -> IResult<&'r str, ExtendedCommand<'r>> {
+    map(
+        terminated(
+            pair(
+                identifier,
+                many0(preceded(space1, param)),
+            ),
+            pair(space0, opt(seimcolon_comment)),
+        ),
+        |(name, params)| ExtendedCommand { name, params },
+    )(input)
+}
@@ -1,9 +1,16 @@
 use nom::branch::*;
 use nom::combinator::*;
 use nom::sequence::*;
-use nom::{character::complete::*, error::VerboseError};
+use nom::{character::complete::*, Err};
 
 use super::{
+    extended_command,
+    seimcolon_comment,
+    ArgOrComment,
+    Comment,
+    CommentDelimiter,
+    checksum,
+    split_checksum,
     comment,
     parse_args,
     parse_command,
@@ -23,6 +30,67 @@ const STRING_ARG_MCODES: [u32; 7] = [
 // #[inline(always)]
 pub fn parse_gcode<'a>(
     input: &'a str,
+) -> Result<(&'a str, Option<GCodeLine<'a>>), GCodeParseError> {
+    /*
+     * Validate and strip the checksum (eg. "*71" of "N123 G1 X10*71")
+     */
+    let split = match split_checksum(input) {
+        Some(split) => split,
+        None => return parse_line(input),
+    };
+
+    let computed = checksum(split.checked);
+    if computed != split.checksum {
+        return Err(ChecksumMismatch {
+            line: input.to_string(),
+            expected: split.checksum,
+            computed,
+            offset: split.checked.len(),
+        });
+    }
+
+    let (rest, gcode_line) = parse_line(split.checked)?;
+
+    // Hosts do not send comments, but tolerate one after the checksum
+    let trailing = split.rest.trim_start_matches([' ', '\t']);
+    let (rest, comment) = if !rest.trim().is_empty() {
+        (rest, None)
+    } else if let Ok((_, text)) = seimcolon_comment(trailing) {
+        (
+            &split.rest[split.rest.len()..],
+            Some(Comment(text, CommentDelimiter::Semicolon)),
+        )
+    } else {
+        (split.rest, None)
+    };
+
+    let gcode_line = gcode_line.map(|gcode_line| match gcode_line {
+        GCodeLine::GCode(mut gcode) => {
+            gcode.checksum = Some(split.checksum);
+            if let Some(comment) = comment {
+                gcode
+                    .args_or_comments
+                    .get_or_insert_with(Vec::new)
+                    .push(ArgOrComment::Comment(comment));
+            }
+            GCodeLine::GCode(gcode)
+        }
+        gcode_line => gcode_line,
+    });
+
+    Ok((rest, gcode_line))
+}
+
+/*
+ * Byte offset of `rest`, the input a parser stopped at, in `line`
+ */
+fn offset_of(line: &str, rest: &str) -> usize {
+    line.len().saturating_sub(rest.len())
+}
+
+// #[inline(always)]
+fn parse_line<'a>(
+    input: &'a str,
 ) -> Result<(&'a str, Option<GCodeLine<'a>>), GCodeParseError> {
     let original_input = input;
     let demarcator = map(pair(char('%'), not_line_ending), |_: (char, &str)| {
@@ -30,8 +98,7 @@ pub fn parse_gcode<'a>(
     });
 
     // Strip leading whitespace
-    let (input, _) = space0::<_, VerboseError<&str>>(input)
-        .map_err(|_| InvalidGCode(original_input.to_string()))?;
+    let input = input.trim_start_matches([' ', '\t']);
 
     // empty lines without a newline character
     if input.is_empty() {
@@ -69,11 +136,23 @@ pub fn parse_gcode<'a>(
         let string_arg_mcode =
             gcode.mnemonic == M && gcode.minor == 0 && STRING_ARG_MCODES.contains(&gcode.major);
 
-        let (input, args_or_comments) = parse_args(string_arg_mcode, input)
-            .map_err(|_| InvalidArguments(original_input.to_string()))?;
+        let (input, args_or_comments) =
+            parse_args(string_arg_mcode, input).map_err(|e| InvalidArguments {
+                line: original_input.to_string(),
+                offset: match e {
+                    Err::Error(e) | Err::Failure(e) => offset_of(original_input, e.input),
+                    Err::Incomplete(_) => original_input.len(),
+                },
+            })?;
 
         gcode.args_or_comments = args_or_comments;
         Ok((input, Some(GCodeLine::GCode(gcode))))
+    } else if let Ok((rest, command)) = extended_command(input) {
+        if rest.trim().is_empty() {
+            Ok((rest, Some(GCodeLine::Extended(command))))
+        } else {
+            Ok((input, Some(GCodeLine::GCodeMacro(input))))
+        }
     } else {
         Ok((input, Some(GCodeLine::GCodeMacro(input))))
     }
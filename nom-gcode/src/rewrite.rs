@@ -0,0 +1,238 @@
+use super::{
+    parse_gcode,
+    ArgOrComment,
+    Comment,
+    CommentDelimiter,
+    GCode,
+    GCodeLine,
+    Mnemonic,
+};
+
+/// A G-code file kept as its original lines, so that it can be written back
+/// byte-for-byte. Only lines touched by a transformation are re-serialized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GCodeDocument {
+    lines: Vec<String>,
+    line_ending: &'static str,
+    trailing_newline: bool,
+}
+
+/// Modal state a move is interpreted in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MoveContext {
+    /// Index of the line in the document
+    pub line: usize,
+    /// G91: X/Y/Z are relative
+    pub relative: bool,
+    /// M83 (or G91): E is relative
+    pub e_relative: bool,
+}
+
+/// Editable view of a G0/G1/G2/G3/G92 command, see `GCodeDocument::map_moves`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveEdit {
+    pub major: u32,
+    pub context: MoveContext,
+    args: Vec<(char, Option<f32>)>,
+}
+
+impl MoveEdit {
+    pub fn get(&self, letter: char) -> Option<f32> {
+        self.args
+            .iter()
+            .find(|(k, _)| *k == letter)
+            .and_then(|(_, v)| *v)
+    }
+
+    /// Sets (or appends) the argument `letter`.
+    pub fn set(&mut self, letter: char, value: f32) {
+        match self.args.iter_mut().find(|(k, _)| *k == letter) {
+            Some((_, v)) => *v = Some(value),
+            None => self.args.push((letter, Some(value))),
+        }
+    }
+
+    pub fn update<F: FnOnce(f32) -> f32>(&mut self, letter: char, f: F) {
+        if let Some(v) = self.get(letter) {
+            self.set(letter, f(v));
+        }
+    }
+
+    /// G0/G1/G2/G3, as opposed to G92
+    pub fn is_move(&self) -> bool {
+        self.major != 92
+    }
+}
+
+impl GCodeDocument {
+    pub fn parse(src: &str) -> Self {
+        let line_ending = if src.contains("\r\n") { "\r\n" } else { "\n" };
+        let trailing_newline = src.ends_with('\n');
+
+        let lines = src
+            .lines()
+            .map(|line| line.to_string())
+            .collect();
+
+        Self {
+            lines,
+            line_ending,
+            trailing_newline,
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn insert_before(&mut self, line: usize, text: &str) {
+        self.lines.insert(line, text.to_string());
+    }
+
+    pub fn insert_after(&mut self, line: usize, text: &str) {
+        self.lines.insert(line + 1, text.to_string());
+    }
+
+    pub fn push(&mut self, text: &str) {
+        self.lines.push(text.to_string());
+    }
+
+    /*
+     * Calls `f` for every G0/G1/G2/G3/G92 line, tracking G90/G91 and M82/M83.
+     * Lines are re-serialized only when `f` changed an argument; lines which
+     * fail to parse are left untouched.
+     */
+    pub fn map_moves<F: FnMut(&mut MoveEdit)>(&mut self, mut f: F) {
+        let mut context = MoveContext::default();
+
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            let gcode = match parse_gcode(line) {
+                Ok((_, Some(GCodeLine::GCode(gcode)))) => gcode,
+                _ => continue,
+            };
+
+            match (gcode.mnemonic, gcode.major) {
+                (Mnemonic::General, 90) => {
+                    context.relative = false;
+                    context.e_relative = false;
+                }
+                (Mnemonic::General, 91) => {
+                    context.relative = true;
+                    context.e_relative = true;
+                }
+                (Mnemonic::Miscellaneous, 82) => context.e_relative = false,
+                (Mnemonic::Miscellaneous, 83) => context.e_relative = true,
+                _ => (),
+            }
+
+            if gcode.mnemonic != Mnemonic::General || ![0, 1, 2, 3, 92].contains(&gcode.major) {
+                continue;
+            }
+
+            context.line = i;
+            let mut edit = MoveEdit {
+                major: gcode.major,
+                context,
+                args: gcode.arguments().cloned().collect(),
+            };
+            f(&mut edit);
+
+            if edit.args != gcode.arguments().cloned().collect::<Vec<_>>() {
+                *line = with_arguments(gcode, &edit.args).to_string();
+            }
+        }
+    }
+
+    /// Shifts absolute X/Y/Z coordinates, including G92 origins.
+    pub fn translate(&mut self, dx: f32, dy: f32, dz: f32) {
+        self.map_moves(|m| {
+            if m.is_move() && m.context.relative {
+                return;
+            }
+            m.update('X', |v| v + dx);
+            m.update('Y', |v| v + dy);
+            m.update('Z', |v| v + dz);
+        });
+    }
+
+    /// Scales X/Y/Z (and arc centers I/J) around the origin. Extrusion is left
+    /// as-is, combine with `scale_flow` if needed.
+    pub fn scale(&mut self, factor: f32) {
+        self.map_moves(|m| {
+            for letter in ['X', 'Y', 'Z', 'I', 'J'] {
+                m.update(letter, |v| v * factor);
+            }
+        });
+    }
+
+    /// Multiplies feedrates, eg. 1.1 for +10 %.
+    pub fn scale_feedrate(&mut self, factor: f32) {
+        self.map_moves(|m| {
+            m.update('F', |v| v * factor);
+        });
+    }
+
+    /// Multiplies extrusion, eg. 0.9 for -10 % flow. Scaling every E value,
+    /// G92 included, scales extruded lengths in both absolute and relative
+    /// extrusion modes.
+    pub fn scale_flow(&mut self, factor: f32) {
+        self.map_moves(|m| {
+            m.update('E', |v| v * factor);
+        });
+    }
+}
+
+/*
+ * Replaces the arguments of `gcode` in place, keeping comments (and their
+ * position) intact. New arguments are appended after the existing ones.
+ */
+fn with_arguments<'r>(mut gcode: GCode<'r>, args: &[(char, Option<f32>)]) -> GCode<'r> {
+    let mut args = args.iter();
+    let mut out = vec![];
+
+    for ac in gcode.args_or_comments.take().unwrap_or_default() {
+        match ac {
+            ArgOrComment::KeyValue(_) => {
+                if let Some(arg) = args.next() {
+                    out.push(ArgOrComment::KeyValue(*arg));
+                }
+            }
+            ac => out.push(ac),
+        }
+    }
+
+    // Appended arguments go before a trailing semicolon comment
+    let insert_at = match out.last() {
+        Some(ArgOrComment::Comment(Comment(_, CommentDelimiter::Semicolon))) => out.len() - 1,
+        _ => out.len(),
+    };
+    for (i, arg) in args.enumerate() {
+        out.insert(insert_at + i, ArgOrComment::KeyValue(*arg));
+    }
+
+    gcode.args_or_comments = if out.is_empty() { None } else { Some(out) };
+    gcode
+}
+
+impl std::fmt::Display for GCodeDocument {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str(self.line_ending)?;
+            }
+            f.write_str(line)?;
+        }
+        if self.trailing_newline && !self.lines.is_empty() {
+            f.write_str(self.line_ending)?;
+        }
+        Ok(())
+    }
+}
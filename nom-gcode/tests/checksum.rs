@@ -0,0 +1,72 @@
+use nom_gcode::{
+    checksum,
+    parse_gcode,
+    GCodeLine,
+    GCodeParseError,
+};
+
+fn with_checksum(line: &str) -> String {
+    format!("{}*{}", line, checksum(line))
+}
+
+#[test]
+fn valid_checksum() {
+    let line = with_checksum("N123 G1 X10");
+    let (remainder, gcode_line) = parse_gcode(&line).unwrap();
+    assert_eq!(remainder, "");
+
+    if let Some(GCodeLine::GCode(gcode)) = gcode_line {
+        assert_eq!(gcode.line_number, Some(123));
+        assert_eq!(gcode.checksum, Some(checksum("N123 G1 X10")));
+        assert_eq!(gcode.arguments().collect::<Vec<_>>(), vec![&('X', Some(10.0))]);
+    } else {
+        panic!("Expected a gcode");
+    }
+}
+
+#[test]
+fn checksum_mismatch() {
+    let line = format!("N123 G1 X10*{}", checksum("N123 G1 X10") ^ 1);
+    match parse_gcode(&line) {
+        Err(GCodeParseError::ChecksumMismatch { expected, computed, offset, .. }) => {
+            assert_eq!(expected ^ 1, computed);
+            assert_eq!(offset, "N123 G1 X10".len());
+        }
+        other => panic!("Expected a checksum mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn without_checksum() {
+    let (_, gcode_line) = parse_gcode("G1 X10 ; *12").unwrap();
+
+    if let Some(GCodeLine::GCode(gcode)) = gcode_line {
+        assert_eq!(gcode.checksum, None);
+    } else {
+        panic!("Expected a gcode");
+    }
+}
+
+#[test]
+fn star_without_line_number() {
+    // a message, not a checksum
+    let (_, gcode_line) = parse_gcode("M117 Layer 3*4").unwrap();
+
+    if let Some(GCodeLine::GCode(gcode)) = gcode_line {
+        assert_eq!(gcode.checksum, None);
+        assert_eq!(gcode.text(), Some("Layer 3*4"));
+    } else {
+        panic!("Expected a gcode");
+    }
+}
+
+#[test]
+fn star_in_trailing_comment() {
+    let line = format!("N7 G1 X10*{} ; x*2", checksum("N7 G1 X10") ^ 1);
+    match parse_gcode(&line) {
+        Err(GCodeParseError::ChecksumMismatch { offset, .. }) => {
+            assert_eq!(offset, "N7 G1 X10".len());
+        }
+        other => panic!("Expected a checksum mismatch, got {:?}", other),
+    }
+}
@@ -0,0 +1,38 @@
+use nom_gcode::{
+    parse_gcode,
+    GCodeLine,
+};
+
+#[test]
+fn extended_commands() {
+    let (_, line) = parse_gcode("SET_PRESSURE_ADVANCE ADVANCE=0.04 SMOOTH_TIME=0.02").unwrap();
+    if let Some(GCodeLine::Extended(command)) = line {
+        assert!(command.is("set_pressure_advance"));
+        assert_eq!(command.param("advance"), Some("0.04"));
+        assert_eq!(command.param_f32("SMOOTH_TIME").unwrap().unwrap(), 0.02);
+    } else {
+        panic!("Expected an extended command");
+    }
+
+    let (_, line) = parse_gcode(
+        "EXCLUDE_OBJECT_DEFINE NAME=\"part 1\" CENTER=105,105 POLYGON=[[95,95],[115,95]] ; c",
+    ).unwrap();
+    if let Some(GCodeLine::Extended(command)) = line {
+        assert_eq!(command.param("NAME"), Some("part 1"));
+        assert_eq!(command.param("POLYGON"), Some("[[95,95],[115,95]]"));
+    } else {
+        panic!("Expected an extended command");
+    }
+
+    let (_, line) = parse_gcode("TIMELAPSE_TAKE_FRAME").unwrap();
+    assert!(matches!(line, Some(GCodeLine::Extended(_))));
+}
+
+#[test]
+fn not_extended() {
+    let (_, line) = parse_gcode("some free text").unwrap();
+    assert!(matches!(line, Some(GCodeLine::GCodeMacro(_))));
+
+    let (_, line) = parse_gcode("G1 X1").unwrap();
+    assert!(matches!(line, Some(GCodeLine::GCode(_))));
+}
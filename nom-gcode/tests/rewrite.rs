@@ -0,0 +1,62 @@
+use nom_gcode::{
+    parse_gcode,
+    GCodeDocument,
+    GCodeLine,
+};
+
+const SRC: &str = "\
+;LAYER:0
+M83
+G1 F1200 X10 Y5.5 E0.5 ; wall
+G0 X1.0 Y2
+G92 E0
+G91
+G1 X1 E0.25
+";
+
+#[test]
+fn round_trip_unchanged() {
+    let mut doc = GCodeDocument::parse(SRC);
+    assert_eq!(doc.to_string(), SRC);
+
+    // a no-op transformation must not touch formatting
+    doc.scale_flow(1.0);
+    assert_eq!(doc.to_string(), SRC);
+}
+
+#[test]
+fn display_round_trip() {
+    for line in [
+        "G1 X10 Y5.5 E0.5 ; wall",
+        "N12 G1.1 X1 (note) Y2",
+        "M117 Hello",
+        "G1 X1 (note)",
+        // the checksum ends at the trailing comment, not at a `;` in parentheses
+        "N3 G1 X1 (a;b) Y2*48 ; tail",
+    ] {
+        let (_, gcode_line) = parse_gcode(line).unwrap();
+        if let Some(GCodeLine::GCode(gcode)) = gcode_line {
+            assert_eq!(gcode.to_string(), line);
+        } else {
+            panic!("Expected a gcode");
+        }
+    }
+}
+
+#[test]
+fn transform() {
+    let mut doc = GCodeDocument::parse(SRC);
+    doc.scale_flow(0.5);
+    doc.translate(1.0, 0.0, 0.0);
+    doc.scale_feedrate(2.0);
+
+    let lines = doc.lines();
+    assert_eq!(lines[0], ";LAYER:0");
+    assert_eq!(lines[2], "G1 F2400 X11 Y5.5 E0.25 ; wall");
+    assert_eq!(lines[3], "G0 X2 Y2");
+    // relative moves are not translated
+    assert_eq!(lines[6], "G1 X1 E0.125");
+
+    doc.insert_after(1, "M221 S110");
+    assert_eq!(doc.lines()[2], "M221 S110");
+}
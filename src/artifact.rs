@@ -0,0 +1,340 @@
+use super::*;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+
+/// Lead screw wobble: the bed or gantry moves in a circle once per turn of
+/// the screw, shifting X/Y as a function of Z.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Wobble {
+    /// mm of Z per screw turn
+    #[serde(default = "Wobble::default_pitch")]
+    pub pitch: f32,
+    /// mm of X/Y displacement
+    pub amplitude: [f32; 2],
+    /// degrees, angle of the displacement at Z=0
+    #[serde(default)]
+    pub phase: f32,
+}
+
+impl Wobble {
+    fn default_pitch() -> f32 {
+        8.0
+    }
+
+    fn offset(&self, z: f32) -> [f32; 2] {
+        let a = z / self.pitch * TAU + self.phase.to_radians();
+        [self.amplitude[0] * a.cos(), self.amplitude[1] * a.sin()]
+    }
+}
+
+/// Z-banding from microstep non-linearity: Z is off by a periodic error
+/// which repeats every full step of the Z motor.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Banding {
+    /// mm of Z per full step, e.g. 8mm pitch / 200 steps
+    #[serde(default = "Banding::default_period")]
+    pub period: f32,
+    /// mm
+    pub amplitude: f32,
+}
+
+impl Banding {
+    fn default_period() -> f32 {
+        0.04
+    }
+
+    fn offset(&self, z: f32) -> f32 {
+        self.amplitude * (z / self.period * TAU).sin()
+    }
+}
+
+/// Lost steps at a given layer (or height); everything printed afterwards
+/// is displaced by `offset`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LayerShift {
+    pub layer: Option<usize>,
+    /// mm, for files without layer markers
+    pub z: Option<f32>,
+    /// mm of X/Y
+    pub offset: [f32; 2],
+}
+
+/// Lost steps at random layers, along X or Y.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RandomShifts {
+    /// chance per layer
+    pub probability: f32,
+    /// mm, largest shift
+    pub max: f32,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// Mechanical artifacts to inject into the motion, loaded from TOML:
+///
+/// ```toml
+/// [wobble]
+/// pitch = 8.0
+/// amplitude = [0.05, 0.05]
+///
+/// [banding]
+/// amplitude = 0.01
+///
+/// [[shifts]]
+/// layer = 40
+/// offset = [0.8, 0.0]
+///
+/// [random_shifts]
+/// probability = 0.01
+/// max = 1.0
+/// seed = 7
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ArtifactConfig {
+    pub wobble: Option<Wobble>,
+    pub banding: Option<Banding>,
+    #[serde(default)]
+    pub shifts: Vec<LayerShift>,
+    pub random_shifts: Option<RandomShifts>,
+}
+
+impl ArtifactConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let src = std::fs::read_to_string(path)?;
+        Self::parse(&src)
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        let config: Self = toml::from_str(src)?;
+        if let Some(wobble) = &config.wobble {
+            if wobble.pitch.is_nan() || wobble.pitch <= 0.0 {
+                bail!("wobble pitch must be positive, got {}", wobble.pitch);
+            }
+        }
+        if let Some(banding) = &config.banding {
+            if banding.period.is_nan() || banding.period <= 0.0 {
+                bail!("banding period must be positive, got {}", banding.period);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A layer shift which was applied.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AppliedShift {
+    pub layer: Option<usize>,
+    /// mm, commanded Z when the shift happened
+    pub z: f32,
+    pub offset: [f32; 2],
+}
+
+/// Rewrites commanded moves into where the machine actually goes. Sits
+/// between the parsed G-code and `ExtrudeState::handle_gcode`.
+#[derive(Clone, Debug)]
+pub struct ArtifactInjector {
+    config: ArtifactConfig,
+    // commanded position
+    pos: Vector3<f32>,
+    layer: Option<usize>,
+    // accumulated layer shifts
+    shift: [f32; 2],
+    fired: Vec<bool>,
+    // X/Y displacement per mm a motor slips
+    axes: [[f32; 2]; 2],
+    rng: u64,
+    applied: Vec<AppliedShift>,
+}
+
+impl ArtifactInjector {
+    pub fn new(config: ArtifactConfig) -> Self {
+        let rng = config.random_shifts.as_ref().map_or(0, |r| r.seed);
+        Self {
+            fired: vec![false; config.shifts.len()],
+            config,
+            pos: Vector3::zeros(),
+            layer: None,
+            shift: [0.0; 2],
+            axes: [[1.0, 0.0], [0.0, 1.0]],
+            rng,
+            applied: Vec::new(),
+        }
+    }
+
+    /// Directions random shifts go in, see `Kinematics::shift_axes`.
+    pub fn set_shift_axes(&mut self, axes: [[f32; 2]; 2]) {
+        self.axes = axes;
+    }
+
+    pub fn applied(&self) -> &[AppliedShift] {
+        &self.applied
+    }
+
+    // splitmix64, uniform in [0, 1)
+    fn random(&mut self) -> f32 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        (z ^ (z >> 31)) as f32 / 2f32.powi(64)
+    }
+
+    fn apply_shift(&mut self, offset: [f32; 2]) {
+        info!(
+            "layer shift at layer {:?}, z={:.2}: {:?}",
+            self.layer, self.pos.z, offset
+        );
+        self.shift[0] += offset[0];
+        self.shift[1] += offset[1];
+        self.applied.push(AppliedShift {
+            layer: self.layer,
+            z: self.pos.z,
+            offset,
+        });
+    }
+
+    /// A layer marker.
+    pub fn layer(&mut self, idx: usize) {
+        self.layer = Some(idx);
+        for i in 0..self.config.shifts.len() {
+            if !self.fired[i] && self.config.shifts[i].layer == Some(idx) {
+                self.fired[i] = true;
+                self.apply_shift(self.config.shifts[i].offset);
+            }
+        }
+
+        let Some(random) = self.config.random_shifts.clone() else {
+            return;
+        };
+        if idx > 0 && self.random() < random.probability {
+            let axis = self.axes[((self.random() * 2.0) as usize).min(1)];
+            let slip = (self.random() * 2.0 - 1.0) * random.max;
+            let offset = axis.map(|a| a * slip);
+            self.apply_shift(offset);
+        }
+    }
+
+    /// The move the machine makes for the commanded `code`.
+    pub fn apply(&mut self, code: GCode1Coord) -> GCode1Coord {
+        if ![0, 1, 92].contains(&code.major) {
+            return code;
+        }
+        for (i, v) in [code.x, code.y, code.z].iter().enumerate() {
+            if let Some(v) = v {
+                self.pos[i] = *v;
+            }
+        }
+
+        for i in 0..self.config.shifts.len() {
+            let shift = &self.config.shifts[i];
+            if !self.fired[i] && shift.layer.is_none() && shift.z.is_some_and(|z| self.pos.z >= z) {
+                self.fired[i] = true;
+                self.apply_shift(shift.offset);
+            }
+        }
+
+        let z = self.pos.z;
+        let wobble = self
+            .config
+            .wobble
+            .as_ref()
+            .map_or([0.0; 2], |w| w.offset(z));
+        let dz = self.config.banding.as_ref().map_or(0.0, |b| b.offset(z));
+        let dx = self.shift[0] + wobble[0];
+        let dy = self.shift[1] + wobble[1];
+
+        let mut out = code;
+        if code.major == 92 {
+            // the machine stays where it is; only the commanded frame moves
+            out.x = code.x.map(|x| x + dx);
+            out.y = code.y.map(|y| y + dy);
+            out.z = code.z.map(|z| z + dz);
+        } else if dx != 0.0 || dy != 0.0 || dz != 0.0 {
+            out.x = Some(self.pos.x + dx);
+            out.y = Some(self.pos.y + dy);
+            out.z = Some(self.pos.z + dz);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn coord(x: f32, y: f32, z: f32) -> GCode1Coord {
+        GCode1Coord {
+            major: 1,
+            x: Some(x),
+            y: Some(y),
+            z: Some(z),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let config = ArtifactConfig::parse(
+            "
+            [wobble]
+            pitch = 2.0
+            amplitude = [0.1, 0.0]
+
+            [[shifts]]
+            layer = 3
+            offset = [0.5, -0.5]
+
+            [[shifts]]
+            z = 1.5
+            offset = [0.0, 1.0]
+            ",
+        )
+        .unwrap();
+        let mut injector = ArtifactInjector::new(config);
+
+        let out = injector.apply(coord(10.0, 10.0, 0.0));
+        assert!((out.x.unwrap() - 10.1).abs() < 1e-5);
+        // half a screw turn
+        let out = injector.apply(coord(10.0, 10.0, 1.0));
+        assert!((out.x.unwrap() - 9.9).abs() < 1e-5);
+
+        injector.layer(3);
+        let out = injector.apply(GCode1Coord {
+            major: 1,
+            y: Some(20.0),
+            ..Default::default()
+        });
+        assert!((out.x.unwrap() - 10.4).abs() < 1e-5);
+        assert!((out.y.unwrap() - 19.5).abs() < 1e-5);
+
+        injector.apply(coord(10.0, 10.0, 2.0));
+        assert_eq!(injector.applied().len(), 2);
+        assert_eq!(injector.applied()[1].offset, [0.0, 1.0]);
+
+        // random shifts are reproducible
+        let random = ArtifactConfig {
+            random_shifts: Some(RandomShifts {
+                probability: 0.5,
+                max: 1.0,
+                seed: 3,
+            }),
+            ..Default::default()
+        };
+        let run = || {
+            let mut injector = ArtifactInjector::new(random.clone());
+            (0..20).for_each(|i| injector.layer(i));
+            injector.applied().to_vec()
+        };
+        let shifts = run();
+        assert!(!shifts.is_empty() && shifts.len() < 20);
+        assert_eq!(shifts, run());
+
+        // no division by a zero pitch or period
+        let wobble = "[wobble]\npitch = 0.0\namplitude = [0.1, 0.0]";
+        assert!(ArtifactConfig::parse(wobble).is_err());
+        assert!(ArtifactConfig::parse("[banding]\nperiod = 0.0\namplitude = 0.01").is_err());
+        assert!(ArtifactConfig::parse("[banding]\nperiod = -0.04\namplitude = 0.01").is_err());
+        assert!(ArtifactConfig::parse("[banding]\namplitude = 0.01").is_ok());
+    }
+}
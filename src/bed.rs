@@ -0,0 +1,472 @@
+use super::*;
+use anyhow::bail;
+use serde::Serialize;
+
+/// Shape of the bed surface, in machine coordinates.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum BedSurface {
+    /// level at Z=0
+    #[default]
+    Flat,
+    /// a plane through `height` at X=0, Y=0, rising `slope` mm per mm along
+    /// X and Y: an unleveled bed
+    Tilted { height: f32, slope: [f32; 2] },
+    /// probed heights, interpolated between the points
+    Mesh(BedMesh),
+}
+
+impl BedSurface {
+    /// Surface height (mm) at (x, y).
+    pub fn height(&self, x: f32, y: f32) -> f32 {
+        match *self {
+            BedSurface::Flat => 0.0,
+            BedSurface::Tilted { height, slope } => height + slope[0] * x + slope[1] * y,
+            BedSurface::Mesh(ref mesh) => mesh.height(x, y),
+        }
+    }
+}
+
+/// Bed heights probed on a regular grid, as printed by Klipper's
+/// BED_MESH_OUTPUT (or saved to printer.cfg) and Marlin's G29 T / M420 V.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BedMesh {
+    /// mm, X,Y of the first probe point
+    pub min: [f32; 2],
+    /// mm, X,Y of the last probe point
+    pub max: [f32; 2],
+    /// mm, one row per Y from `min` to `max`, each from min X to max X
+    pub points: Vec<Vec<f32>>,
+}
+
+impl BedMesh {
+    pub fn new(min: [f32; 2], max: [f32; 2], points: Vec<Vec<f32>>) -> Result<Self> {
+        let cols = points.first().map_or(0, |row| row.len());
+        if cols == 0 || points.iter().any(|row| row.len() != cols) {
+            bail!("bed mesh is not a rectangular grid");
+        }
+        Ok(Self { min, max, points })
+    }
+
+    /// Reads the first grid of numbers in `src`. Rows are taken in order of
+    /// increasing Y; Marlin's row and column indices are dropped. The probed
+    /// area comes from min_x/max_x/min_y/max_y or mesh_min/mesh_max entries,
+    /// or from `area` when the output has none.
+    pub fn parse(src: &str, area: Option<([f32; 2], [f32; 2])>) -> Result<Self> {
+        let mut min = [None; 2];
+        let mut max = [None; 2];
+        let mut points = vec![];
+        let mut header = None;
+        let mut done = false;
+
+        for line in src.lines() {
+            let line = line.trim_start_matches("#*#").trim();
+            let line = ["//", "echo:", ";"]
+                .iter()
+                .fold(line, |line, prefix| {
+                    line.strip_prefix(prefix).unwrap_or(line)
+                })
+                .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let values = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>();
+            match values {
+                Ok(values) if !done => {
+                    let is_index = values.iter().enumerate().all(|(i, v)| *v == i as f32);
+                    if points.is_empty() && header.is_none() && is_index && values.len() > 1 {
+                        header = Some(values.len());
+                    } else if header.is_some_and(|n| values.len() == n + 1) {
+                        points.push(values[1..].to_vec());
+                    } else {
+                        points.push(values);
+                    }
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(_) => done |= !points.is_empty(),
+            }
+
+            let Some((key, value)) = line.split_once(['=', ':']) else {
+                continue;
+            };
+            let numbers = value
+                .split(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+                .filter_map(|v| v.parse::<f32>().ok())
+                .collect::<Vec<_>>();
+            match (key.trim().to_ascii_lowercase().as_str(), &numbers[..]) {
+                ("min_x", [v]) => min[0] = Some(*v),
+                ("min_y", [v]) => min[1] = Some(*v),
+                ("max_x", [v]) => max[0] = Some(*v),
+                ("max_y", [v]) => max[1] = Some(*v),
+                ("mesh_min", [x, y]) => min = [Some(*x), Some(*y)],
+                ("mesh_max", [x, y]) => max = [Some(*x), Some(*y)],
+                _ => {}
+            }
+        }
+
+        let (min, max) = match (min, max, area) {
+            ([Some(x0), Some(y0)], [Some(x1), Some(y1)], _) => ([x0, y0], [x1, y1]),
+            (_, _, Some(area)) => area,
+            _ => bail!("bed mesh area unknown, give it as X0,Y0,X1,Y1"),
+        };
+        Self::new(min, max, points)
+    }
+
+    pub fn load(path: &str, area: Option<([f32; 2], [f32; 2])>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?, area)
+    }
+
+    /// Bilinear interpolation between the probe points; the edge values
+    /// extend beyond the probed area.
+    pub fn height(&self, x: f32, y: f32) -> f32 {
+        let rows = self.points.len();
+        let cols = self.points[0].len();
+        let at = |v: f32, min: f32, max: f32, n: usize| {
+            if n < 2 || max <= min {
+                return (0, 0.0);
+            }
+            let t = ((v - min) / (max - min)).clamp(0.0, 1.0) * (n - 1) as f32;
+            let i = (t as usize).min(n - 2);
+            (i, t - i as f32)
+        };
+        let (i, tx) = at(x, self.min[0], self.max[0], cols);
+        let (j, ty) = at(y, self.min[1], self.max[1], rows);
+        let row = |j: usize| {
+            let row = &self.points[j.min(rows - 1)];
+            row[i] + (row[(i + 1).min(cols - 1)] - row[i]) * tx
+        };
+        row(j) + (row(j + 1) - row(j)) * ty
+    }
+}
+
+/// Bed contact: where the first layer ends up, and how hard it is squished.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BedParams {
+    pub surface: BedSurface,
+    /// mm added to every commanded Z, positive raises the nozzle. M290 and
+    /// SET_GCODE_OFFSET adjust it further while printing.
+    pub z_offset: f32,
+    /// mm of height the bottom layer loses to the weight and heat of the
+    /// layers above, making it flare out
+    pub elephant_foot: f32,
+    /// mm above the bed at which the elephant foot squish fades out
+    pub elephant_foot_height: f32,
+    /// auto bed leveling: Z follows the bed surface. Set by G29, M420 and
+    /// BED_MESH_* commands while printing.
+    pub leveling: bool,
+    /// mm of Z over which leveling fades out, 0 to never fade
+    pub fade_height: f32,
+}
+
+impl Default for BedParams {
+    fn default() -> Self {
+        Self {
+            surface: BedSurface::Flat,
+            z_offset: 0.0,
+            elephant_foot: 0.1,
+            elephant_foot_height: 0.3,
+            leveling: false,
+            fade_height: 0.0,
+        }
+    }
+}
+
+impl BedParams {
+    /// Surface height under `pos` (machine coordinates).
+    pub fn height_at(&self, pos: Vector3<f32>) -> f32 {
+        self.surface.height(pos.x, pos.y)
+    }
+
+    /// Z (mm) added by bed leveling at the commanded `pos`.
+    pub fn compensation(&self, pos: Vector3<f32>) -> f32 {
+        if !self.leveling {
+            return 0.0;
+        }
+        let fade = if self.fade_height > 0.0 {
+            (1.0 - pos.z / self.fade_height).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        self.height_at(pos) * fade
+    }
+
+    /// Extra squish (mm) of a strand whose bottom is `above` mm over the bed.
+    pub fn squish(&self, above: f32) -> f32 {
+        if self.elephant_foot_height <= 0.0 || above >= self.elephant_foot_height {
+            return 0.0;
+        }
+        self.elephant_foot * (1.0 - above.max(0.0) / self.elephant_foot_height)
+    }
+}
+
+/// First-layer thickness over one cell of the plate.
+#[derive(Clone, Debug, Serialize)]
+pub struct FirstLayerCell {
+    /// mm, center of the cell
+    pub x: f32,
+    pub y: f32,
+    /// number of voxel columns printed in the cell
+    pub count: usize,
+    /// mm, mean, thinnest and thickest
+    pub thickness: f32,
+    pub min: f32,
+    pub max: f32,
+    /// mm, mean distance between the bed and the bottom of the layer
+    pub gap: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FirstLayerReport {
+    /// mm, the commanded height of the first layer
+    pub nominal: f32,
+    /// mm, cell size
+    pub cell: f32,
+    pub cells: Vec<FirstLayerCell>,
+}
+
+impl FirstLayerReport {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl std::fmt::Display for FirstLayerReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let range = self.cells.iter().fold(None, |acc: Option<(f32, f32)>, c| {
+            Some(acc.map_or((c.min, c.max), |(lo, hi)| (lo.min(c.min), hi.max(c.max))))
+        });
+        writeln!(f, "nominal first layer: {:.3}mm", self.nominal)?;
+        if let Some((lo, hi)) = range {
+            writeln!(f, "thickness: {:.3}mm .. {:.3}mm", lo, hi)?;
+        }
+
+        // mean thickness per cell, Y up
+        let mut xs = self.cells.iter().map(|c| c.x).collect::<Vec<_>>();
+        let mut ys = self.cells.iter().map(|c| c.y).collect::<Vec<_>>();
+        for v in [&mut xs, &mut ys] {
+            v.sort_by(f32::total_cmp);
+            v.dedup();
+        }
+        writeln!(f)?;
+        write!(f, "{:>8}", "y \\ x")?;
+        for x in &xs {
+            write!(f, " {:>7.1}", x)?;
+        }
+        writeln!(f)?;
+        for y in ys.iter().rev() {
+            write!(f, "{:>8.1}", y)?;
+            for x in &xs {
+                match self.cells.iter().find(|c| c.x == *x && c.y == *y) {
+                    Some(c) => write!(f, " {:>7.3}", c.thickness)?,
+                    None => write!(f, " {:>7}", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first layer of a print, and per printed voxel the deviation (mm) of
+/// the layer's thickness from nominal.
+pub struct FirstLayer {
+    pub report: FirstLayerReport,
+    pub deviations: Vec<(VoxelIdx, f32)>,
+}
+
+/// Simulates `gcode` up to its second layer marker and maps the thickness of
+/// the material on the bed, in cells of `cell` mm. Files without layer
+/// markers are simulated whole.
+pub fn first_layer_gcode<V: Voxel + Default>(
+    gcode: &str,
+    options: &GenerateOptions,
+    cell: f32,
+) -> Result<FirstLayer> {
+    let mut state = ExtrudeState::<V>::default();
+    options.apply(&mut state)?;
+
+    let mut nominal = None;
+    simulate_file_with(&mut state, gcode, options.parse_mode, |state, item| {
+        match item {
+            GCode1::Layer(idx) if *idx > 0 && nominal.is_some() => return Ok(false),
+            GCode1::Coord(coord) => {
+                // the first extrusion which is not a prime or unretract
+                let moves = coord.x.is_some() || coord.y.is_some();
+                if nominal.is_none() && coord.major == 1 && coord.e.is_some() && moves {
+                    nominal = Some(state.pos.z);
+                }
+            }
+            _ => {}
+        }
+        Ok(true)
+    })?;
+    let nominal = nominal.ok_or_else(|| anyhow::anyhow!("nothing printed in {}", gcode))?;
+
+    let unit = state.params.unit;
+    let home = state.home();
+    let bed = &state.params.bed_contact;
+    let bb = state.voxel().bounding_box();
+    // highest row the first layer may reach, above a bed leveled up to 1mm
+    let top = state.params.intpos(home.z + nominal + bed.z_offset + 1.0);
+
+    let mut cells = std::collections::BTreeMap::<(i32, i32), Vec<(f32, f32)>>::new();
+    let mut deviations = vec![];
+    for x in bb.bound_min[0]..=bb.bound_max[0] {
+        for y in bb.bound_min[1]..=bb.bound_max[1] {
+            let Some(z0) =
+                (bb.bound_min[2]..=top).find(|z| state.voxel().occupied(VoxelIdx::new([x, y, *z])))
+            else {
+                continue;
+            };
+            let z1 = (z0..=top)
+                .take_while(|z| state.voxel().occupied(VoxelIdx::new([x, y, *z])))
+                .last()
+                .unwrap_or(z0);
+
+            let pos = Vector3::new(x as f32, y as f32, 0.0) * unit - home;
+            let bottom = (z0 as f32 - 0.5) * unit - home.z;
+            let thickness = (z1 - z0 + 1) as f32 * unit;
+            let gap = (bottom - bed.height_at(pos)).max(0.0);
+            let key = ((pos.x / cell).floor() as i32, (pos.y / cell).floor() as i32);
+            cells.entry(key).or_default().push((thickness, gap));
+            for z in z0..=z1 {
+                deviations.push((VoxelIdx::new([x, y, z]), thickness - nominal));
+            }
+        }
+    }
+
+    let cells = cells
+        .into_iter()
+        .map(|((i, j), samples)| {
+            let n = samples.len() as f32;
+            FirstLayerCell {
+                x: (i as f32 + 0.5) * cell,
+                y: (j as f32 + 0.5) * cell,
+                count: samples.len(),
+                thickness: samples.iter().map(|s| s.0).sum::<f32>() / n,
+                min: samples.iter().map(|s| s.0).fold(f32::MAX, f32::min),
+                max: samples.iter().map(|s| s.0).fold(f32::MIN, f32::max),
+                gap: samples.iter().map(|s| s.1).sum::<f32>() / n,
+            }
+        })
+        .collect();
+
+    Ok(FirstLayer {
+        report: FirstLayerReport {
+            nominal,
+            cell,
+            cells,
+        },
+        deviations,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_bed() {
+        let bed = BedParams {
+            surface: BedSurface::Tilted {
+                height: 0.1,
+                slope: [0.001, -0.002],
+            },
+            ..BedParams::default()
+        };
+        assert!((bed.height_at(Vector3::new(100.0, 50.0, 0.0)) - 0.1).abs() < 1e-6);
+        assert!((bed.height_at(Vector3::new(200.0, 0.0, 0.0)) - 0.3).abs() < 1e-6);
+
+        assert!((bed.squish(0.0) - 0.1).abs() < 1e-6);
+        assert!((bed.squish(0.15) - 0.05).abs() < 1e-6);
+        assert_eq!(bed.squish(0.4), 0.0);
+    }
+
+    #[test]
+    fn test_mesh() {
+        let klipper = "\
+#*# [bed_mesh default]
+#*# version = 1
+#*# points =
+#*# \t  -0.100000, 0.000000, 0.100000
+#*# \t  0.100000, 0.200000, 0.300000
+#*# x_count = 3
+#*# y_count = 2
+#*# min_x = 10.0
+#*# max_x = 210.0
+#*# min_y = 20.0
+#*# max_y = 220.0
+";
+        let mesh = BedMesh::parse(klipper, None).unwrap();
+        assert_eq!(mesh.min, [10.0, 20.0]);
+        assert_eq!(mesh.points.len(), 2);
+        assert!((mesh.height(10.0, 20.0) + 0.1).abs() < 1e-6);
+        assert!((mesh.height(110.0, 120.0) - 0.1).abs() < 1e-6);
+        // clamped outside the probed area
+        assert!((mesh.height(300.0, 300.0) - 0.3).abs() < 1e-6);
+
+        let marlin = "\
+Bilinear Leveling Grid:
+      0      1      2
+ 0 -0.100 +0.000 +0.100
+ 1 +0.100 +0.200 +0.300
+";
+        assert!(BedMesh::parse(marlin, None).is_err());
+        let area = ([10.0, 20.0], [210.0, 220.0]);
+        assert_eq!(BedMesh::parse(marlin, Some(area)).unwrap(), mesh);
+
+        let mut bed = BedParams {
+            surface: BedSurface::Mesh(mesh),
+            leveling: true,
+            fade_height: 1.0,
+            ..BedParams::default()
+        };
+        let pos = Vector3::new(210.0, 220.0, 0.5);
+        assert!((bed.compensation(pos) - 0.15).abs() < 1e-6);
+        assert_eq!(bed.compensation(Vector3::new(210.0, 220.0, 2.0)), 0.0);
+        bed.leveling = false;
+        assert_eq!(bed.compensation(pos), 0.0);
+    }
+
+    #[test]
+    fn test_z_offset() {
+        // a few lines of a first layer, 0.4mm apart
+        let mut src = "M83\nG1 Z0.2 F1200\n".to_string();
+        for i in 0..5 {
+            let y = 10.0 + i as f32 * 0.4;
+            src += &format!("G1 X10 Y{:.1}\nG1 X30 E0.7\n", y);
+        }
+
+        // voxel columns touching the bed, and all printed columns
+        let run = |z_offset: f32| {
+            let state = simulated(&src, |state| {
+                state.params.bed_contact.z_offset = z_offset;
+                state.params.e_alpha = 1.0;
+            });
+            let floor = state.params.intpos(0.0) + 1;
+            let cells = occupied_cells(&state);
+            let columns = |touching: bool| {
+                cells
+                    .iter()
+                    .filter(|c| c[2] == floor || (!touching && c[2] > floor))
+                    .map(|c| (c[0], c[1]))
+                    .collect::<std::collections::BTreeSet<_>>()
+                    .len()
+            };
+            (columns(true), columns(false))
+        };
+
+        let (touching, printed) = run(0.0);
+        assert!(touching * 10 > printed * 9, "{} of {}", touching, printed);
+        // too high: the strands land on the bed only in places, if at all
+        let (touching, printed) = run(0.4);
+        assert!(printed > 0);
+        assert!(touching * 2 < printed, "{} of {}", touching, printed);
+    }
+}
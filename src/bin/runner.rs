@@ -1,8 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use argh::FromArgs;
 use log::*;
+use nalgebra::Vector3;
 use simple_stopwatch::Stopwatch;
 use std::rc::Rc;
+use std::sync::Arc;
 use tdp_tl::*;
 
 #[derive(FromArgs)]
@@ -20,6 +22,12 @@ enum SubCommandEnum {
     DemoExtrude(DemoExtrude),
     Gcode(SubCommandGcode),
     GcodeLayers(SubCommandGcodeLayers),
+    Rewrite(SubCommandRewrite),
+    Estimate(SubCommandEstimate),
+    Compare(SubCommandCompare),
+    Measure(SubCommandMeasure),
+    Collisions(SubCommandCollisions),
+    FirstLayer(SubCommandFirstLayer),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -80,6 +88,106 @@ struct SubCommandGcode {
     /// target number of layers
     #[argh(option)]
     layer: Option<usize>,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+
+    /// skip an object by name (repeatable)
+    #[argh(option)]
+    exclude: Vec<String>,
+
+    /// deposit only the named object (repeatable)
+    #[argh(option)]
+    only: Vec<String>,
+
+    /// export one mesh per object
+    #[argh(switch)]
+    split_objects: bool,
+
+    /// export one mesh per tool, purge towers separately
+    #[argh(switch)]
+    split_tools: bool,
+
+    /// do not deposit wipe/prime towers
+    #[argh(switch)]
+    skip_purge: bool,
+
+    /// nozzle offset of a tool, e.g. 1:0.1,0,0 (repeatable)
+    #[argh(option)]
+    tool_offset: Vec<String>,
+
+    /// filament diameter of a tool, e.g. 1:2.85 (repeatable)
+    #[argh(option)]
+    filament_diameter: Vec<String>,
+
+    /// color of a tool, e.g. 1:#00FF00 (repeatable)
+    #[argh(option)]
+    tool_color: Vec<String>,
+
+    /// scales all extrusion, e.g. 0.9 for -10 % flow
+    #[argh(option)]
+    extrusion_multiplier: Option<f32>,
+
+    /// write commanded vs deposited volume to mass_report.json
+    #[argh(switch)]
+    mass_report: bool,
+
+    /// hotend melting capacity in mm3/s; faster extrusions starve, written
+    /// to flow_report.json
+    #[argh(option)]
+    max_flow: Option<f32>,
+
+    /// deposit unsupported extrusions in place, without sagging
+    #[argh(switch)]
+    no_sag: bool,
+
+    /// no blobs where outer wall loops end
+    #[argh(switch)]
+    no_seam: bool,
+
+    /// warp the exported mesh as the material cools: pla, petg or abs
+    #[argh(option)]
+    warp: Option<String>,
+
+    /// printer Z offset in mm, positive raises the nozzle
+    #[argh(option)]
+    z_offset: Option<f32>,
+
+    /// bed leveling error as X,Y slopes in mm per 100 mm
+    #[argh(option)]
+    bed_tilt: Option<String>,
+
+    /// bed mesh from Klipper BED_MESH_OUTPUT/printer.cfg or Marlin G29 T
+    #[argh(option)]
+    bed_mesh: Option<String>,
+
+    /// probed area of the bed mesh as X0,Y0,X1,Y1, when the file has none
+    #[argh(option)]
+    bed_mesh_area: Option<String>,
+
+    /// auto bed leveling from the start, fading out at this height in mm
+    /// (0: no fade); G29, M420 and BED_MESH_* in the G-code also toggle it
+    #[argh(option)]
+    abl: Option<f32>,
+
+    /// mechanical artifacts to inject (TOML): Z-wobble, layer shifts, Z-banding
+    #[argh(option)]
+    artifacts: Option<String>,
+
+    /// belt printer with infinite Z: gantry angle to the belt in degrees
+    #[argh(option)]
+    belt: Option<f32>,
+
+    /// axis skew as XY,XZ,YZ in mm per 100 mm
+    #[argh(option)]
+    shear: Option<String>,
+
+    /// motion system for move times and layer shifts: cartesian or corexy,
+    /// optionally with per-motor limits :V1,V2,V3[,A1,A2,A3] in mm/s and
+    /// mm/s^2, delta:ARM,RADIUS[,VELOCITY,ACCEL[,SEGMENTS]] or polar
+    #[argh(option)]
+    kinematics: Option<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -121,6 +229,310 @@ struct SubCommandGcodeLayers {
     /// fsn
     #[argh(switch)]
     vdb: bool,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+
+    /// skip an object by name (repeatable)
+    #[argh(option)]
+    exclude: Vec<String>,
+
+    /// deposit only the named object (repeatable)
+    #[argh(option)]
+    only: Vec<String>,
+
+    /// export one mesh per object
+    #[argh(switch)]
+    split_objects: bool,
+
+    /// export one mesh per tool, purge towers separately
+    #[argh(switch)]
+    split_tools: bool,
+
+    /// do not deposit wipe/prime towers
+    #[argh(switch)]
+    skip_purge: bool,
+
+    /// nozzle offset of a tool, e.g. 1:0.1,0,0 (repeatable)
+    #[argh(option)]
+    tool_offset: Vec<String>,
+
+    /// filament diameter of a tool, e.g. 1:2.85 (repeatable)
+    #[argh(option)]
+    filament_diameter: Vec<String>,
+
+    /// color of a tool, e.g. 1:#00FF00 (repeatable)
+    #[argh(option)]
+    tool_color: Vec<String>,
+
+    /// scales all extrusion, e.g. 0.9 for -10 % flow
+    #[argh(option)]
+    extrusion_multiplier: Option<f32>,
+
+    /// write commanded vs deposited volume to mass_report.json
+    #[argh(switch)]
+    mass_report: bool,
+
+    /// hotend melting capacity in mm3/s; faster extrusions starve, written
+    /// to flow_report.json
+    #[argh(option)]
+    max_flow: Option<f32>,
+
+    /// deposit unsupported extrusions in place, without sagging
+    #[argh(switch)]
+    no_sag: bool,
+
+    /// no blobs where outer wall loops end
+    #[argh(switch)]
+    no_seam: bool,
+
+    /// warp the exported mesh as the material cools: pla, petg or abs
+    #[argh(option)]
+    warp: Option<String>,
+
+    /// printer Z offset in mm, positive raises the nozzle
+    #[argh(option)]
+    z_offset: Option<f32>,
+
+    /// bed leveling error as X,Y slopes in mm per 100 mm
+    #[argh(option)]
+    bed_tilt: Option<String>,
+
+    /// bed mesh from Klipper BED_MESH_OUTPUT/printer.cfg or Marlin G29 T
+    #[argh(option)]
+    bed_mesh: Option<String>,
+
+    /// probed area of the bed mesh as X0,Y0,X1,Y1, when the file has none
+    #[argh(option)]
+    bed_mesh_area: Option<String>,
+
+    /// auto bed leveling from the start, fading out at this height in mm
+    /// (0: no fade); G29, M420 and BED_MESH_* in the G-code also toggle it
+    #[argh(option)]
+    abl: Option<f32>,
+
+    /// mechanical artifacts to inject (TOML): Z-wobble, layer shifts, Z-banding
+    #[argh(option)]
+    artifacts: Option<String>,
+
+    /// belt printer with infinite Z: gantry angle to the belt in degrees
+    #[argh(option)]
+    belt: Option<f32>,
+
+    /// axis skew as XY,XZ,YZ in mm per 100 mm
+    #[argh(option)]
+    shear: Option<String>,
+
+    /// motion system for move times and layer shifts: cartesian or corexy,
+    /// optionally with per-motor limits :V1,V2,V3[,A1,A2,A3] in mm/s and
+    /// mm/s^2, delta:ARM,RADIUS[,VELOCITY,ACCEL[,SEGMENTS]] or polar
+    #[argh(option)]
+    kinematics: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// estimate print time and filament use, compared with the slicer
+#[argh(subcommand, name = "estimate")]
+struct SubCommandEstimate {
+    /// input filename
+    #[argh(option)]
+    gcode: String,
+
+    /// filament density in g/cm^3, defaults to PLA
+    #[argh(option, default = "DEFAULT_DENSITY")]
+    density: f32,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// include commanded vs deposited volume per feature and layer
+    #[argh(switch)]
+    mass_report: bool,
+
+    /// hotend melting capacity in mm3/s; reports where extrusions starve
+    #[argh(option)]
+    max_flow: Option<f32>,
+
+    /// motion system for move times: cartesian or corexy, optionally with
+    /// per-motor limits :V1,V2,V3[,A1,A2,A3] in mm/s and mm/s^2,
+    /// delta:ARM,RADIUS[,VELOCITY,ACCEL[,SEGMENTS]] or polar
+    #[argh(option)]
+    kinematics: Option<String>,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// compare the simulated print with a reference STL/OBJ
+#[argh(subcommand, name = "compare")]
+struct SubCommandCompare {
+    /// input filename
+    #[argh(option)]
+    gcode: String,
+
+    /// reference mesh, STL or OBJ
+    #[argh(option)]
+    model: String,
+
+    /// deviation heat-map output filename (.glb)
+    #[argh(option)]
+    out: Option<String>,
+
+    /// reference placement in G-code coordinates as X,Y,Z; centered on the
+    /// print by default
+    #[argh(option)]
+    offset: Option<String>,
+
+    /// deviation (mm) at which the heat-map saturates
+    #[argh(option, default = "0.5")]
+    range: f32,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// measure X/Y/Z sizes of the simulated part, e.g. a calibration cube
+#[argh(subcommand, name = "measure")]
+struct SubCommandMeasure {
+    /// input filename
+    #[argh(option)]
+    gcode: String,
+
+    /// nominal size in mm, one value or X,Y,Z
+    #[argh(option, default = "String::from(\"20\")")]
+    nominal: String,
+
+    /// height (G-code Z) of a cross-section to measure, repeatable;
+    /// defaults to 1/4, 1/2 and 3/4 of the part's height
+    #[argh(option)]
+    z: Vec<f32>,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// check moves for toolhead collisions with the printed parts
+#[argh(subcommand, name = "collisions")]
+struct SubCommandCollisions {
+    /// input filename
+    #[argh(option)]
+    gcode: String,
+
+    /// toolhead shape (TOML); a V6-style nozzle, heater block and X gantry by
+    /// default
+    #[argh(option)]
+    toolhead: Option<String>,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// map the first layer's thickness over the bed
+#[argh(subcommand, name = "first-layer")]
+struct SubCommandFirstLayer {
+    /// input filename
+    #[argh(option)]
+    gcode: String,
+
+    /// thickness heat-map output filename (.glb)
+    #[argh(option)]
+    out: Option<String>,
+
+    /// cell size of the thickness map in mm
+    #[argh(option, default = "10.0")]
+    cell: f32,
+
+    /// deviation from nominal (mm) at which the heat-map saturates
+    #[argh(option, default = "0.1")]
+    range: f32,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// skip malformed lines instead of aborting
+    #[argh(switch)]
+    lenient: bool,
+
+    /// printer Z offset in mm, positive raises the nozzle
+    #[argh(option)]
+    z_offset: Option<f32>,
+
+    /// bed leveling error as X,Y slopes in mm per 100 mm
+    #[argh(option)]
+    bed_tilt: Option<String>,
+
+    /// bed mesh from Klipper BED_MESH_OUTPUT/printer.cfg or Marlin G29 T
+    #[argh(option)]
+    bed_mesh: Option<String>,
+
+    /// probed area of the bed mesh as X0,Y0,X1,Y1, when the file has none
+    #[argh(option)]
+    bed_mesh_area: Option<String>,
+
+    /// auto bed leveling from the start, fading out at this height in mm
+    /// (0: no fade); G29, M420 and BED_MESH_* in the G-code also toggle it
+    #[argh(option)]
+    abl: Option<f32>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// rewrite gcode, e.g. to generate flow/speed variants of a print
+#[argh(subcommand, name = "rewrite")]
+struct SubCommandRewrite {
+    /// input filename
+    #[argh(option)]
+    gcode: String,
+
+    /// output filename
+    #[argh(option)]
+    out: String,
+
+    /// flow multiplier, e.g. 0.9 for -10%
+    #[argh(option)]
+    flow: Option<f32>,
+
+    /// feedrate multiplier
+    #[argh(option)]
+    feedrate: Option<f32>,
+
+    /// uniform XYZ scale
+    #[argh(option)]
+    scale: Option<f32>,
+
+    /// offset along x, in millimeters
+    #[argh(option, default = "0.0")]
+    dx: f32,
+
+    /// offset along y, in millimeters
+    #[argh(option, default = "0.0")]
+    dy: f32,
+
+    /// offset along z, in millimeters
+    #[argh(option, default = "0.0")]
+    dz: f32,
 }
 
 const SIZE: i32 = 100i32;
@@ -287,6 +699,362 @@ fn generate_frames<V: Voxel>(outdir: &str, render: bool) -> Result<()> {
     Ok(())
 }
 
+fn rewrite_gcode(opt: &SubCommandRewrite) -> Result<()> {
+    let src = std::fs::read_to_string(&opt.gcode)?;
+    let mut doc = nom_gcode::GCodeDocument::parse(&src);
+
+    if let Some(flow) = opt.flow {
+        doc.scale_flow(flow);
+    }
+    if let Some(feedrate) = opt.feedrate {
+        doc.scale_feedrate(feedrate);
+    }
+    if let Some(scale) = opt.scale {
+        doc.scale(scale);
+    }
+    if opt.dx != 0.0 || opt.dy != 0.0 || opt.dz != 0.0 {
+        doc.translate(opt.dx, opt.dy, opt.dz);
+    }
+
+    std::fs::write(&opt.out, doc.to_string())?;
+    Ok(())
+}
+
+/// Splits `<tool>:<value>`.
+fn parse_tool_arg(arg: &str) -> Result<(usize, &str)> {
+    let (tool, value) = arg
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected <tool>:<value>, got {:?}", arg))?;
+    Ok((tool.trim_start_matches('T').parse()?, value))
+}
+
+fn tool_overrides(
+    offsets: &[String],
+    diameters: &[String],
+    colors: &[String],
+) -> Result<Vec<ToolOverride>> {
+    let mut tools = Vec::<ToolOverride>::new();
+    fn tool(tools: &mut Vec<ToolOverride>, idx: usize) -> &mut ToolOverride {
+        if tools.len() <= idx {
+            tools.resize(idx + 1, ToolOverride::default());
+        }
+        &mut tools[idx]
+    }
+
+    for arg in offsets {
+        let (idx, value) = parse_tool_arg(arg)?;
+        let v = value
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if v.len() != 3 {
+            bail!("expected X,Y,Z offset, got {:?}", value);
+        }
+        tool(&mut tools, idx).offset = Some(Vector3::new(v[0], v[1], v[2]));
+    }
+    for arg in diameters {
+        let (idx, value) = parse_tool_arg(arg)?;
+        tool(&mut tools, idx).filament_diameter = Some(value.parse()?);
+    }
+    for arg in colors {
+        let (idx, value) = parse_tool_arg(arg)?;
+        let color = parse_color(value).ok_or_else(|| anyhow!("invalid color {:?}", value))?;
+        tool(&mut tools, idx).color = Some(color);
+    }
+    Ok(tools)
+}
+
+fn parse_bed_tilt(arg: &str) -> Result<BedSurface> {
+    let v = arg
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if v.len() != 2 {
+        bail!("expected X,Y bed tilt, got {:?}", arg);
+    }
+    Ok(BedSurface::Tilted {
+        height: 0.0,
+        slope: [v[0] / 100.0, v[1] / 100.0],
+    })
+}
+
+fn parse_transform(belt: Option<f32>, shear: Option<&str>) -> Result<Option<MachineTransform>> {
+    let shear = match shear {
+        Some(arg) => {
+            let v = arg
+                .split(',')
+                .map(|v| v.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()?;
+            if v.len() != 3 {
+                bail!("expected XY,XZ,YZ shear, got {:?}", arg);
+            }
+            [v[0] / 100.0, v[1] / 100.0, v[2] / 100.0]
+        }
+        None if belt.is_none() => return Ok(None),
+        None => [0.0; 3],
+    };
+    Ok(Some(MachineTransform {
+        belt_angle: belt,
+        shear,
+    }))
+}
+
+fn parse_bed_mesh(path: &str, area: Option<&str>) -> Result<BedSurface> {
+    let area = match area {
+        Some(area) => {
+            let v = area
+                .split(',')
+                .map(|v| v.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()?;
+            if v.len() != 4 {
+                bail!("expected X0,Y0,X1,Y1 mesh area, got {:?}", area);
+            }
+            Some(([v[0], v[1]], [v[2], v[3]]))
+        }
+        None => None,
+    };
+    Ok(BedSurface::Mesh(BedMesh::load(path, area)?))
+}
+
+fn parse_kinematics(arg: &str) -> Result<Arc<dyn Kinematics>> {
+    let (name, params) = arg.split_once(':').unwrap_or((arg, ""));
+    let v = if params.is_empty() {
+        vec![]
+    } else {
+        params
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    // per-axis speed and optionally acceleration limits
+    let limits = |usage: &str| -> Result<([f32; 3], [f32; 3])> {
+        let unlimited = [f32::INFINITY; 3];
+        match v.len() {
+            0 => Ok((unlimited, unlimited)),
+            3 => Ok(([v[0], v[1], v[2]], unlimited)),
+            6 => Ok(([v[0], v[1], v[2]], [v[3], v[4], v[5]])),
+            _ => bail!("expected {}, got {:?}", usage, arg),
+        }
+    };
+    Ok(match name {
+        "cartesian" => {
+            let (max_velocity, max_accel) = limits("cartesian:VX,VY,VZ[,AX,AY,AZ]")?;
+            Arc::new(Cartesian {
+                max_velocity,
+                max_accel,
+            })
+        }
+        "corexy" => {
+            let (max_velocity, max_accel) = limits("corexy:VA,VB,VZ[,AA,AB,AZ]")?;
+            Arc::new(CoreXY {
+                max_velocity,
+                max_accel,
+            })
+        }
+        "delta" => {
+            if ![2, 4, 5].contains(&v.len()) {
+                bail!(
+                    "expected delta:ARM,RADIUS[,VELOCITY,ACCEL[,SEGMENTS]], got {:?}",
+                    arg
+                );
+            }
+            let mut delta = Delta::new(v[0], v[1]);
+            if v.len() >= 4 {
+                delta.max_velocity = v[2];
+                delta.max_accel = v[3];
+            }
+            if v.len() == 5 {
+                delta.segments_per_second = v[4];
+            }
+            Arc::new(delta)
+        }
+        "polar" if v.is_empty() => Arc::new(Polar::default()),
+        _ => bail!("unknown kinematics {:?}", arg),
+    })
+}
+
+// --bed-tilt or --bed-mesh
+macro_rules! bed_surface {
+    ($opt:expr) => {{
+        let opt = &$opt;
+        match (&opt.bed_tilt, &opt.bed_mesh) {
+            (Some(_), Some(_)) => bail!("--bed-tilt and --bed-mesh are exclusive"),
+            (Some(tilt), None) => Some(parse_bed_tilt(tilt)?),
+            (None, Some(mesh)) => Some(parse_bed_mesh(mesh, opt.bed_mesh_area.as_deref())?),
+            (None, None) => None,
+        }
+    }};
+}
+
+// gcode and gcode-layers share these options
+macro_rules! generate_options {
+    ($opt:expr) => {{
+        let opt = &$opt;
+        let split = match (opt.split_objects, opt.split_tools) {
+            (true, true) => bail!("--split-objects and --split-tools are exclusive"),
+            (true, false) => SplitBy::Object,
+            (false, true) => SplitBy::Tool,
+            (false, false) => SplitBy::None,
+        };
+        GenerateOptions {
+            parse_mode: if opt.lenient {
+                ParseMode::Lenient
+            } else {
+                ParseMode::Strict
+            },
+            exclude_objects: opt.exclude.clone(),
+            only_objects: opt.only.clone(),
+            split,
+            tools: tool_overrides(&opt.tool_offset, &opt.filament_diameter, &opt.tool_color)?,
+            skip_purge: opt.skip_purge,
+            extrusion_multiplier: opt.extrusion_multiplier,
+            mass_report: opt.mass_report,
+            max_flow: opt.max_flow.map(FlowLimit::new),
+            sag: opt.no_sag.then(SagParams::disabled),
+            seam: opt.no_seam.then(SeamParams::disabled),
+            warp: match &opt.warp {
+                Some(name) => Some(
+                    WarpParams::preset(name)
+                        .ok_or_else(|| anyhow!("unknown warp material {:?}", name))?,
+                ),
+                None => None,
+            },
+            z_offset: opt.z_offset,
+            bed_surface: bed_surface!(opt),
+            leveling: opt.abl,
+            artifacts: opt
+                .artifacts
+                .as_deref()
+                .map(ArtifactConfig::load)
+                .transpose()?,
+            transform: parse_transform(opt.belt, opt.shear.as_deref())?,
+            kinematics: opt
+                .kinematics
+                .as_deref()
+                .map(parse_kinematics)
+                .transpose()?,
+        }
+    }};
+}
+
+fn compare(opt: &SubCommandCompare) -> Result<()> {
+    let options = GenerateOptions {
+        parse_mode: if opt.lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..Default::default()
+    };
+    let offset = match &opt.offset {
+        Some(offset) => {
+            let v = offset
+                .split(',')
+                .map(|v| v.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()?;
+            if v.len() != 3 {
+                bail!("expected X,Y,Z offset, got {:?}", offset);
+            }
+            Some(Vector3::new(v[0], v[1], v[2]))
+        }
+        None => None,
+    };
+
+    let comparison = compare_gcode::<MonotonicVoxel>(&opt.gcode, &opt.model, &options, offset)?;
+    if opt.json {
+        println!("{}", comparison.report.to_json()?);
+    } else {
+        print!("{}", comparison.report);
+    }
+
+    if let Some(out) = &opt.out {
+        let groups = heatmap_groups::<MonotonicVoxel>(&comparison.deviations, opt.range);
+        let params = Parameters::default();
+        model_serialize_gltf_groups(&groups, out, [-90f32, -90f32, 0f32], params.unit)?;
+    }
+    Ok(())
+}
+
+fn measure(opt: &SubCommandMeasure) -> Result<()> {
+    let options = GenerateOptions {
+        parse_mode: if opt.lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..Default::default()
+    };
+    let v = opt
+        .nominal
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let nominal = match v[..] {
+        [n] => [n; 3],
+        [x, y, z] => [x, y, z],
+        _ => bail!("expected one or three nominal sizes, got {:?}", opt.nominal),
+    };
+
+    let report = measure_gcode::<MonotonicVoxel>(&opt.gcode, &options, nominal, &opt.z)?;
+    if opt.json {
+        println!("{}", report.to_json()?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn collisions(opt: &SubCommandCollisions) -> Result<()> {
+    let options = GenerateOptions {
+        parse_mode: if opt.lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..Default::default()
+    };
+    let shape = match &opt.toolhead {
+        Some(path) => ToolheadShape::load(path)?,
+        None => ToolheadShape::default(),
+    };
+
+    let report = check_collisions::<MonotonicVoxel>(&opt.gcode, &options, shape)?;
+    if opt.json {
+        println!("{}", report.to_json()?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn first_layer(opt: &SubCommandFirstLayer) -> Result<()> {
+    let options = GenerateOptions {
+        parse_mode: if opt.lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        z_offset: opt.z_offset,
+        bed_surface: bed_surface!(opt),
+        leveling: opt.abl,
+        ..Default::default()
+    };
+
+    let first = first_layer_gcode::<MonotonicVoxel>(&opt.gcode, &options, opt.cell)?;
+    if opt.json {
+        println!("{}", first.report.to_json()?);
+    } else {
+        print!("{}", first.report);
+    }
+
+    if let Some(out) = &opt.out {
+        let groups = heatmap_groups::<MonotonicVoxel>(&first.deviations, opt.range);
+        let params = Parameters::default();
+        model_serialize_gltf_groups(&groups, out, [-90f32, -90f32, 0f32], params.unit)?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     env_logger::init();
 
@@ -323,29 +1091,63 @@ fn main() -> Result<()> {
 
         SubCommandEnum::Gcode(opt) => {
             let layer = opt.layer.unwrap_or(std::usize::MAX);
-            generate_gcode::<MonotonicVoxel>(&opt.gcode, &opt.out, layer, false)
+            let options = generate_options!(opt);
+            generate_gcode::<MonotonicVoxel>(&opt.gcode, &opt.out, layer, false, &options)
         }
 
         SubCommandEnum::GcodeLayers(opt) => {
             let layer = std::usize::MAX;
+            let options = generate_options!(opt);
             if opt.rangeset {
-                generate_gcode::<RangeSetVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<RangeSetVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             } else if opt.svo {
-                generate_gcode::<SVOVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<SVOVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             } else if opt.chunked {
-                generate_gcode::<ChunkedVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<ChunkedVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             } else if opt.lod {
-                generate_gcode::<LodVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<LodVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             } else if opt.iso {
-                generate_gcode::<IsoVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<IsoVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             } else if opt.fsn {
-                generate_gcode::<FSNVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<FSNVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             } else if opt.vdb {
-                // generate_gcode::<VDBVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                // generate_gcode::<VDBVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
                 Ok(())
             } else {
-                generate_gcode::<MonotonicVoxel>(&opt.gcode, &opt.outdir, layer, true)
+                generate_gcode::<MonotonicVoxel>(&opt.gcode, &opt.outdir, layer, true, &options)
             }
         }
+
+        SubCommandEnum::Rewrite(opt) => rewrite_gcode(&opt),
+
+        SubCommandEnum::Compare(opt) => compare(&opt),
+        SubCommandEnum::Measure(opt) => measure(&opt),
+        SubCommandEnum::Collisions(opt) => collisions(&opt),
+        SubCommandEnum::FirstLayer(opt) => first_layer(&opt),
+
+        SubCommandEnum::Estimate(opt) => {
+            let options = GenerateOptions {
+                parse_mode: if opt.lenient {
+                    ParseMode::Lenient
+                } else {
+                    ParseMode::Strict
+                },
+                mass_report: opt.mass_report,
+                max_flow: opt.max_flow.map(FlowLimit::new),
+                kinematics: opt
+                    .kinematics
+                    .as_deref()
+                    .map(parse_kinematics)
+                    .transpose()?,
+                ..Default::default()
+            };
+            let estimate = estimate_gcode::<MonotonicVoxel>(&opt.gcode, &options, opt.density)?;
+            if opt.json {
+                println!("{}", estimate.to_json()?);
+            } else {
+                print!("{}", estimate);
+            }
+            Ok(())
+        }
     }
 }
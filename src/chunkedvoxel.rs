@@ -44,6 +44,20 @@ impl ChunkedBase {
         }
         true
     }
+
+    pub fn remove(&mut self, coord: VoxelIdx) -> bool {
+        let Some(cell) = self.chunks.get_mut(&chunk_idx(coord)) else {
+            return false;
+        };
+        let [x, y, z] = cell_idx(coord);
+        if !cell.get(x, y, z) {
+            return false;
+        }
+        // empty chunks are kept, they may be dirty
+        cell.clear(x, y, z);
+        self.bb.remove();
+        true
+    }
 }
 
 fn write_cell0<W: std::io::Write>(idx: u64, cell: &BGMCell, mut writer: W) -> Result<()> {
@@ -161,6 +175,17 @@ impl Voxel for ChunkedVoxel {
         true
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        let removed = self.base.remove(coord);
+        if !removed {
+            return false;
+        }
+
+        let coord_dirty = coord.shift_down(CELL_SIZE_BITS);
+        self.setdirty(coord_dirty);
+        true
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let mut models = vec![];
         let mut voxels = [0; CS_P3];
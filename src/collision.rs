@@ -0,0 +1,475 @@
+use super::*;
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+
+/// A solid part of the toolhead, in millimeters relative to the nozzle tip.
+/// Parts extend upwards from their underside; only the underside matters
+/// when moving over printed geometry.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ToolheadPart {
+    /// Vertical cone or cylinder around the nozzle axis.
+    Cone {
+        bottom: f32,
+        top: f32,
+        radius_bottom: f32,
+        radius_top: f32,
+    },
+    /// Axis-aligned box, e.g. heater block or carriage. Use `inf` for a
+    /// gantry spanning the whole bed.
+    Box { min: [f32; 3], max: [f32; 3] },
+}
+
+impl ToolheadPart {
+    /// Height of the underside at horizontal offset (dx, dy), if the part
+    /// covers it.
+    fn underside(&self, dx: f32, dy: f32) -> Option<f32> {
+        match *self {
+            ToolheadPart::Cone {
+                bottom,
+                top,
+                radius_bottom,
+                radius_top,
+            } => {
+                let r = (dx * dx + dy * dy).sqrt();
+                if r <= radius_bottom {
+                    Some(bottom)
+                } else if r <= radius_top {
+                    // slanted side of a cone widening upwards
+                    Some(
+                        bottom
+                            + (r - radius_bottom) / (radius_top - radius_bottom) * (top - bottom),
+                    )
+                } else {
+                    None
+                }
+            }
+            ToolheadPart::Box { min, max } => {
+                if (min[0]..=max[0]).contains(&dx) && (min[1]..=max[1]).contains(&dy) {
+                    Some(min[2])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Horizontal extents reachable below height `h`: (min, max) offsets.
+    fn reach(&self, h: f32) -> Option<([f32; 2], [f32; 2])> {
+        match *self {
+            ToolheadPart::Cone {
+                bottom,
+                top,
+                radius_bottom,
+                radius_top,
+            } => {
+                if h <= bottom {
+                    return None;
+                }
+                let r = if h >= top || radius_top <= radius_bottom {
+                    radius_top.max(radius_bottom)
+                } else {
+                    radius_bottom + (h - bottom) / (top - bottom) * (radius_top - radius_bottom)
+                };
+                Some(([-r, -r], [r, r]))
+            }
+            ToolheadPart::Box { min, max } => {
+                if h <= min[2] {
+                    return None;
+                }
+                Some(([min[0], min[1]], [max[0], max[1]]))
+            }
+        }
+    }
+}
+
+/// Toolhead geometry for collision checks, loaded from TOML:
+///
+/// ```toml
+/// contact_band = 0.15
+///
+/// [[parts]]
+/// type = "cone"
+/// bottom = 0.0
+/// top = 2.5
+/// radius_bottom = 0.5
+/// radius_top = 3.0
+///
+/// [[parts]]
+/// type = "box"
+/// min = [-inf, -15.0, 30.0]
+/// max = [inf, 15.0, 60.0]
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ToolheadShape {
+    /// Material this far above the nozzle tip (mm) is expected contact,
+    /// e.g. the current layer squeezed out next to the nozzle.
+    #[serde(default = "ToolheadShape::default_contact_band")]
+    pub contact_band: f32,
+    pub parts: Vec<ToolheadPart>,
+}
+
+impl Default for ToolheadShape {
+    /// A V6-style nozzle and heater block under an X gantry.
+    fn default() -> Self {
+        Self {
+            contact_band: Self::default_contact_band(),
+            parts: vec![
+                ToolheadPart::Cone {
+                    bottom: 0.0,
+                    top: 2.5,
+                    radius_bottom: 0.5,
+                    radius_top: 3.0,
+                },
+                ToolheadPart::Box {
+                    min: [-8.0, -10.0, 4.5],
+                    max: [12.0, 10.0, 16.0],
+                },
+                ToolheadPart::Box {
+                    min: [f32::NEG_INFINITY, -15.0, 30.0],
+                    max: [f32::INFINITY, 15.0, 60.0],
+                },
+            ],
+        }
+    }
+}
+
+impl ToolheadShape {
+    fn default_contact_band() -> f32 {
+        0.15
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let src = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&src)?)
+    }
+}
+
+/// The toolhead ran into printed material.
+#[derive(Clone, Debug, Serialize)]
+pub struct Collision {
+    /// source line, when known
+    pub line: Option<usize>,
+    pub layer: Option<usize>,
+    pub travel: bool,
+    /// nozzle tip at the deepest point, G-code coordinates
+    pub pos: [f32; 3],
+    /// index into `ToolheadShape::parts`
+    pub part: usize,
+    /// mm
+    pub penetration: f32,
+}
+
+// columns are grouped into tiles of 2^TILE_BITS, to skip low areas quickly
+const TILE_BITS: i32 = 4;
+
+/// Tracks the top of the printed material per voxel column, and checks
+/// toolhead moves against it.
+#[derive(Clone, Debug)]
+pub struct CollisionChecker {
+    shape: ToolheadShape,
+    unit: f32,
+    heights: AHashMap<[i32; 2], i32>,
+    tiles: AHashMap<[i32; 2], i32>,
+    // range of the keys in `tiles`
+    tile_min: [i32; 2],
+    tile_max: [i32; 2],
+    max_height: i32,
+    collisions: Vec<Collision>,
+}
+
+impl CollisionChecker {
+    pub fn new(shape: ToolheadShape, unit: f32) -> Self {
+        Self {
+            shape,
+            unit,
+            heights: Default::default(),
+            tiles: Default::default(),
+            tile_min: [i32::MAX; 2],
+            tile_max: [i32::MIN; 2],
+            max_height: i32::MIN,
+            collisions: vec![],
+        }
+    }
+
+    pub fn collisions(&self) -> &[Collision] {
+        &self.collisions
+    }
+
+    /// A voxel was filled.
+    pub fn record(&mut self, pos: VoxelIdx) {
+        let z = pos[2];
+        let h = self.heights.entry([pos[0], pos[1]]).or_insert(z);
+        *h = (*h).max(z);
+        let key = [pos[0] >> TILE_BITS, pos[1] >> TILE_BITS];
+        let t = self.tiles.entry(key).or_insert(z);
+        *t = (*t).max(z);
+        for (i, k) in key.into_iter().enumerate() {
+            self.tile_min[i] = self.tile_min[i].min(k);
+            self.tile_max[i] = self.tile_max[i].max(k);
+        }
+        self.max_height = self.max_height.max(z);
+    }
+
+    /// Top of the material in voxel column `k`, mm.
+    fn top(&self, k: i32) -> f32 {
+        (k as f32 + 0.5) * self.unit
+    }
+
+    /// Deepest penetration of `part` with the nozzle tip at `tip` (voxel
+    /// space, mm), beyond the contact band.
+    fn penetration(&self, part: &ToolheadPart, tip: Vector3<f32>) -> Option<f32> {
+        let band = self.shape.contact_band;
+        let (lo, hi) = part.reach(self.top(self.max_height) - tip.z - band)?;
+        let to_idx = |v: f32| (v / self.unit).round() as i32;
+        let (x0, x1) = (to_idx(tip.x + lo[0]), to_idx(tip.x + hi[0]));
+        let (y0, y1) = (to_idx(tip.y + lo[1]), to_idx(tip.y + hi[1]));
+        let lowest = match *part {
+            ToolheadPart::Cone { bottom, .. } => bottom,
+            ToolheadPart::Box { min, .. } => min[2],
+        };
+
+        // tiles within reach, clamped to the printed area for parts
+        // spanning the whole bed
+        let (tx0, tx1) = (
+            (x0 >> TILE_BITS).max(self.tile_min[0]),
+            (x1 >> TILE_BITS).min(self.tile_max[0]),
+        );
+        let (ty0, ty1) = (
+            (y0 >> TILE_BITS).max(self.tile_min[1]),
+            (y1 >> TILE_BITS).min(self.tile_max[1]),
+        );
+        let tiles = (tx0..=tx1).flat_map(|tx| (ty0..=ty1).map(move |ty| [tx, ty]));
+
+        let mut deepest: Option<f32> = None;
+        for [tx, ty] in tiles {
+            let Some(&tile_top) = self.tiles.get(&[tx, ty]) else {
+                continue;
+            };
+            // tiles below the part
+            if self.top(tile_top) <= tip.z + lowest + band {
+                continue;
+            }
+            let (cx0, cy0) = (tx << TILE_BITS, ty << TILE_BITS);
+            let (cx1, cy1) = (cx0 + (1 << TILE_BITS) - 1, cy0 + (1 << TILE_BITS) - 1);
+            for x in cx0.max(x0)..=cx1.min(x1) {
+                for y in cy0.max(y0)..=cy1.min(y1) {
+                    let Some(&k) = self.heights.get(&[x, y]) else {
+                        continue;
+                    };
+                    let dx = x as f32 * self.unit - tip.x;
+                    let dy = y as f32 * self.unit - tip.y;
+                    let Some(underside) = part.underside(dx, dy) else {
+                        continue;
+                    };
+                    let depth = self.top(k) - (tip.z + underside) - band;
+                    if depth > 0.0 && deepest.is_none_or(|d| depth > d) {
+                        deepest = Some(depth);
+                    }
+                }
+            }
+        }
+        deepest
+    }
+
+    /// Checks the move of the nozzle tip from `from` to `to` (voxel space,
+    /// mm) before its material is deposited, and records the deepest
+    /// penetration. `home` converts positions back to G-code coordinates.
+    pub fn check(
+        &mut self,
+        from: Vector3<f32>,
+        to: Vector3<f32>,
+        home: Vector3<f32>,
+        line: Option<usize>,
+        layer: Option<usize>,
+        travel: bool,
+    ) {
+        if self.heights.is_empty() {
+            return;
+        }
+        let len = (to - from).magnitude();
+        let steps = ((len / self.unit).ceil() as usize).max(1);
+
+        let mut worst: Option<(f32, usize, Vector3<f32>)> = None;
+        for step in 0..=steps {
+            let tip = from + (to - from) * (step as f32 / steps as f32);
+            for (i, part) in self.shape.parts.iter().enumerate() {
+                if let Some(depth) = self.penetration(part, tip) {
+                    if worst.is_none_or(|(d, _, _)| depth > d) {
+                        worst = Some((depth, i, tip));
+                    }
+                }
+            }
+        }
+
+        if let Some((penetration, part, tip)) = worst {
+            let pos = tip - home;
+            self.collisions.push(Collision {
+                line,
+                layer,
+                travel,
+                pos: [pos.x, pos.y, pos.z],
+                part,
+                penetration,
+            });
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CollisionReport {
+    pub shape: ToolheadShape,
+    pub collisions: Vec<Collision>,
+}
+
+impl CollisionReport {
+    pub fn max_penetration(&self) -> f32 {
+        self.collisions
+            .iter()
+            .map(|c| c.penetration)
+            .fold(0.0, f32::max)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl std::fmt::Display for CollisionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.collisions.is_empty() {
+            return writeln!(f, "no collisions");
+        }
+        writeln!(
+            f,
+            "{} collisions, deepest {:.3}mm",
+            self.collisions.len(),
+            self.max_penetration()
+        )?;
+        writeln!(
+            f,
+            "{:>8} {:>6} {:<6} {:>28} {:>5} {:>12}",
+            "line", "layer", "move", "position", "part", "penetration"
+        )?;
+        for c in &self.collisions {
+            writeln!(
+                f,
+                "{:>8} {:>6} {:<6} {:>28} {:>5} {:>10.3}mm",
+                c.line.map_or("-".to_string(), |l| l.to_string()),
+                c.layer.map_or("-".to_string(), |l| l.to_string()),
+                if c.travel { "travel" } else { "print" },
+                format!("({:.2}, {:.2}, {:.2})", c.pos[0], c.pos[1], c.pos[2]),
+                c.part,
+                c.penetration
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Simulates `gcode` and checks every move against the printed material.
+pub fn check_collisions<V: Voxel + Default>(
+    gcode: &str,
+    options: &GenerateOptions,
+    shape: ToolheadShape,
+) -> Result<CollisionReport> {
+    let mut state = ExtrudeState::<V>::default();
+    options.apply(&mut state)?;
+    state.enable_collision_check(shape.clone());
+    simulate_file(&mut state, gcode, options.parse_mode)?;
+
+    Ok(CollisionReport {
+        shape,
+        collisions: state.collisions().to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_collision() {
+        let shape = ToolheadShape {
+            contact_band: 0.1,
+            parts: vec![ToolheadPart::Cone {
+                bottom: 0.0,
+                top: 1.0,
+                radius_bottom: 0.5,
+                radius_top: 1.5,
+            }],
+        };
+        let mut checker = CollisionChecker::new(shape, 0.1);
+        // a 1mm wide wall, 2mm tall, at x = 10
+        for x in 95..105 {
+            for z in 0..20 {
+                checker.record(VoxelIdx::new([x, 0, z]));
+            }
+        }
+
+        // passing over it
+        let home = Vector3::zeros();
+        let y = |z: f32, x: f32| Vector3::new(x, 0.0, z);
+        checker.check(y(3.0, 0.0), y(3.0, 20.0), home, Some(1), None, true);
+        assert!(checker.collisions().is_empty());
+
+        // printing the next layer on top of it is expected contact
+        checker.check(y(2.0, 9.0), y(2.0, 11.0), home, Some(2), None, false);
+        assert!(checker.collisions().is_empty());
+
+        // crossing it 1mm too low
+        checker.check(y(1.0, 0.0), y(1.0, 20.0), home, Some(3), Some(4), true);
+        let c = &checker.collisions()[0];
+        assert_eq!(
+            (c.line, c.layer, c.travel, c.part),
+            (Some(3), Some(4), true, 0)
+        );
+        assert!((c.penetration - 0.85).abs() < 1e-4, "{}", c.penetration);
+
+        // the slanted side of the cone clips the wall, or just misses it
+        checker.check(y(1.0, 7.5), y(1.0, 7.5), home, Some(5), None, true);
+        assert_eq!(checker.collisions().len(), 1);
+        checker.check(y(1.0, 8.5), y(1.0, 8.5), home, Some(6), None, true);
+        let c = &checker.collisions()[1];
+        assert!((c.penetration - 0.35).abs() < 1e-4, "{}", c.penetration);
+    }
+
+    #[test]
+    fn test_check_collisions() {
+        // a 20mm wall 2mm tall, then a travel back across it at the first
+        // layer, as if printing a second object
+        let mut src = "M83\nG1 F1200\n".to_string();
+        for layer in 1..=10 {
+            src += &format!("G1 X10 Y10 Z{:.1}\nG1 X10 Y30 E0.67\n", layer as f32 * 0.2);
+        }
+        src += "G1 X20 Y20\nG1 Z0.2\nG1 X0 Y20\n";
+        let last = src.lines().count();
+
+        let state = simulated(&src, |state| {
+            state.params.e_alpha = 1.0;
+            state.enable_collision_check(ToolheadShape::default());
+        });
+        let collisions = state.collisions();
+        assert_eq!(collisions.len(), 1, "{:?}", collisions);
+        let c = &collisions[0];
+        assert_eq!((c.line, c.travel), (Some(last), true));
+        assert!(c.penetration > 1.5, "{}", c.penetration);
+        assert!((c.pos[0] - 10.0).abs() < 1.0, "{:?}", c.pos);
+    }
+
+    #[test]
+    fn test_shape_toml() {
+        let shape: ToolheadShape = toml::from_str(
+            r#"
+            [[parts]]
+            type = "box"
+            min = [-inf, -15.0, 30.0]
+            max = [inf, 15.0, 60.0]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(shape.contact_band, 0.15);
+        assert_eq!(shape.parts[0].underside(1000.0, 0.0), Some(30.0),);
+    }
+}
@@ -0,0 +1,538 @@
+use super::*;
+use ahash::{AHashMap, AHashSet};
+use serde::Serialize;
+
+/// Fills the voxels whose centers lie inside `mesh`, on the grid used by the
+/// simulation (voxel `i` is centered at `i * unit`). The mesh has to be
+/// closed.
+pub fn voxelize(mesh: &TriMesh, unit: f32) -> AHashSet<VoxelIdx> {
+    // sample columns slightly off the grid, so that they never hit shared
+    // edges or vertices exactly and the crossing parity stays intact
+    const JITTER: [f32; 2] = [1.234e-3, 2.345e-3];
+
+    let mut columns = AHashMap::<(i32, i32), Vec<f32>>::default();
+    for [a, b, c] in &mesh.triangles {
+        let min = a.inf(b).inf(c);
+        let max = a.sup(b).sup(c);
+
+        let v0 = b - a;
+        let v1 = c - a;
+        let den = v0.x * v1.y - v1.x * v0.y;
+        if den.abs() < f32::EPSILON {
+            // vertical, never crossed by a column
+            continue;
+        }
+
+        let i0 = (min.x / unit - JITTER[0]).ceil() as i32;
+        let i1 = (max.x / unit - JITTER[0]).floor() as i32;
+        let j0 = (min.y / unit - JITTER[1]).ceil() as i32;
+        let j1 = (max.y / unit - JITTER[1]).floor() as i32;
+        for i in i0..=i1 {
+            for j in j0..=j1 {
+                let px = (i as f32 + JITTER[0]) * unit - a.x;
+                let py = (j as f32 + JITTER[1]) * unit - a.y;
+                let u = (px * v1.y - v1.x * py) / den;
+                let v = (v0.x * py - px * v0.y) / den;
+                if u < 0.0 || v < 0.0 || u + v > 1.0 {
+                    continue;
+                }
+                let z = a.z + u * v0.z + v * v1.z;
+                columns.entry((i, j)).or_default().push(z);
+            }
+        }
+    }
+
+    let mut out = AHashSet::default();
+    for ((i, j), mut zs) in columns {
+        zs.sort_by(|a, b| a.total_cmp(b));
+        for pair in zs.chunks_exact(2) {
+            let k0 = (pair[0] / unit).ceil() as i32;
+            let k1 = (pair[1] / unit).floor() as i32;
+            for k in k0..=k1 {
+                out.insert(VoxelIdx::new([i, j, k]));
+            }
+        }
+    }
+    out
+}
+
+const NEIGHBORS: [[i32; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+/// Splits `voxels` into exterior and solid: anything not reachable from
+/// outside, i.e. the voxels plus enclosed voids such as sparse infill.
+/// Returns the solid voxels and those of them facing the exterior.
+///
+/// Voids are found among the gaps between the runs of each voxel column, so
+/// memory follows the voxels rather than their bounding box.
+pub fn solidify(voxels: &AHashSet<VoxelIdx>) -> (AHashSet<VoxelIdx>, Vec<VoxelIdx>) {
+    let mut columns = AHashMap::<[i32; 2], Vec<i32>>::default();
+    for v in voxels {
+        columns.entry([v[0], v[1]]).or_default().push(v[2]);
+    }
+    // empty rows between the voxels of a column, inclusive
+    let mut gaps = AHashMap::<[i32; 2], Vec<(i32, i32)>>::default();
+    for (key, zs) in columns.iter_mut() {
+        zs.sort_unstable();
+        let between = zs
+            .windows(2)
+            .filter(|w| w[1] > w[0] + 1)
+            .map(|w| (w[0] + 1, w[1] - 1))
+            .collect::<Vec<_>>();
+        if !between.is_empty() {
+            gaps.insert(*key, between);
+        }
+    }
+
+    let sides = |[x, y]: [i32; 2]| [[x + 1, y], [x - 1, y], [x, y + 1], [x, y - 1]];
+    // gaps open to the side, to an empty column or the space above or below
+    // a column, reach the exterior
+    let mut exterior = AHashSet::<([i32; 2], usize)>::default();
+    let mut queue = vec![];
+    for (key, between) in &gaps {
+        for (i, &(z0, z1)) in between.iter().enumerate() {
+            let open = sides(*key).iter().any(|side| match columns.get(side) {
+                Some(zs) => z0 < zs[0] || z1 > zs[zs.len() - 1],
+                None => true,
+            });
+            if open && exterior.insert((*key, i)) {
+                queue.push((*key, i));
+            }
+        }
+    }
+    while let Some((key, i)) = queue.pop() {
+        let (z0, z1) = gaps[&key][i];
+        for side in sides(key) {
+            let Some(between) = gaps.get(&side) else {
+                continue;
+            };
+            for (j, &(w0, w1)) in between.iter().enumerate() {
+                if w0 <= z1 && z0 <= w1 && exterior.insert((side, j)) {
+                    queue.push((side, j));
+                }
+            }
+        }
+    }
+
+    let mut solid = voxels.clone();
+    for (key, between) in &gaps {
+        for (i, &(z0, z1)) in between.iter().enumerate() {
+            if !exterior.contains(&(*key, i)) {
+                solid.extend((z0..=z1).map(|z| VoxelIdx::new([key[0], key[1], z])));
+            }
+        }
+    }
+    let mut surface = solid
+        .iter()
+        .filter(|p| {
+            NEIGHBORS
+                .iter()
+                .any(|d| !solid.contains(&(**p + VoxelIdx::from(*d))))
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    surface.sort_unstable();
+    (solid, surface)
+}
+
+/// Bucketed point set for nearest-neighbor queries.
+struct SpatialHash {
+    cell: i32,
+    buckets: AHashMap<VoxelIdx, Vec<VoxelIdx>>,
+}
+
+impl SpatialHash {
+    fn new(points: &[VoxelIdx], cell: i32) -> Self {
+        let mut buckets = AHashMap::<VoxelIdx, Vec<VoxelIdx>>::default();
+        for p in points {
+            buckets.entry(Self::key(*p, cell)).or_default().push(*p);
+        }
+        Self { cell, buckets }
+    }
+
+    fn key(p: VoxelIdx, cell: i32) -> VoxelIdx {
+        VoxelIdx::new([0, 1, 2].map(|i| p[i].div_euclid(cell)))
+    }
+
+    /// Distance to the nearest point, in voxels.
+    fn nearest(&self, p: VoxelIdx) -> Option<f32> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let center = Self::key(p, self.cell);
+        let mut best = usize::MAX;
+        for r in 0i32.. {
+            for dx in -r..=r {
+                for dy in -r..=r {
+                    for dz in -r..=r {
+                        if dx.abs().max(dy.abs()).max(dz.abs()) != r {
+                            continue;
+                        }
+                        let key = center + VoxelIdx::new([dx, dy, dz]);
+                        for q in self.buckets.get(&key).into_iter().flatten() {
+                            best = best.min((*q - p).magnitude_squared());
+                        }
+                    }
+                }
+            }
+            // anything beyond ring r is at least r cells away
+            let reach = (r * self.cell) as usize;
+            if best <= reach * reach {
+                break;
+            }
+        }
+        Some((best as f32).sqrt())
+    }
+}
+
+/// Printed vs reference geometry. Lengths in millimeters, volumes in mm^3.
+#[derive(Clone, Debug, Serialize)]
+pub struct CompareReport {
+    pub unit: f32,
+    pub printed_volume: f32,
+    pub reference_volume: f32,
+    pub intersection_volume: f32,
+    /// intersection over union
+    pub iou: f32,
+    /// intersection over union with enclosed voids (sparse infill) filled
+    pub solid_iou: f32,
+    /// symmetric Hausdorff distance between the surfaces
+    pub hausdorff: f32,
+    /// mean distance between the surfaces, both directions
+    pub mean_deviation: f32,
+    /// mean signed distance of the printed surface, positive outside the
+    /// reference
+    pub mean_signed_deviation: f32,
+    /// the reference's translation into G-code coordinates
+    pub offset: [f32; 3],
+}
+
+impl CompareReport {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl std::fmt::Display for CompareReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>12.1}mm3",
+            "printed volume", self.printed_volume
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12.1}mm3",
+            "reference volume", self.reference_volume
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12.1}mm3",
+            "intersection", self.intersection_volume
+        )?;
+        writeln!(f, "{:<24} {:>14.4}", "IoU", self.iou)?;
+        writeln!(f, "{:<24} {:>14.4}", "IoU, voids filled", self.solid_iou)?;
+        writeln!(f, "{:<24} {:>12.3}mm", "Hausdorff distance", self.hausdorff)?;
+        writeln!(
+            f,
+            "{:<24} {:>12.3}mm",
+            "mean deviation", self.mean_deviation
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>+12.3}mm",
+            "mean signed deviation", self.mean_signed_deviation
+        )?;
+        writeln!(
+            f,
+            "{:<24} ({:.2}, {:.2}, {:.2})",
+            "reference offset", self.offset[0], self.offset[1], self.offset[2]
+        )
+    }
+}
+
+pub struct Comparison {
+    pub report: CompareReport,
+    /// signed deviation (mm) of every printed surface voxel
+    pub deviations: Vec<(VoxelIdx, f32)>,
+}
+
+/// Compares two voxel sets on the same grid.
+pub fn compare_voxels(
+    printed: &AHashSet<VoxelIdx>,
+    reference: &AHashSet<VoxelIdx>,
+    unit: f32,
+    offset: Vector3<f32>,
+) -> Comparison {
+    let voxel_volume = unit.powi(3);
+    let intersection = printed.intersection(reference).count();
+    let union = printed.len() + reference.len() - intersection;
+
+    // deviations are measured on the outer surfaces only
+    let (printed_solid, printed_surface) = solidify(printed);
+    let (reference_solid, reference_surface) = solidify(reference);
+    let solid_intersection = printed_solid.intersection(&reference_solid).count();
+    let solid_union = printed_solid.len() + reference_solid.len() - solid_intersection;
+    const CELL: i32 = 8;
+    let printed_hash = SpatialHash::new(&printed_surface, CELL);
+    let reference_hash = SpatialHash::new(&reference_surface, CELL);
+
+    let deviations = printed_surface
+        .iter()
+        .filter_map(|p| {
+            let d = reference_hash.nearest(*p)? * unit;
+            let sign = if reference_solid.contains(p) {
+                -1.0
+            } else {
+                1.0
+            };
+            Some((*p, d * sign))
+        })
+        .collect::<Vec<_>>();
+    let backward = reference_surface
+        .iter()
+        .filter_map(|p| printed_hash.nearest(*p))
+        .map(|d| d * unit)
+        .collect::<Vec<_>>();
+
+    let forward_max = deviations.iter().map(|(_, d)| d.abs()).fold(0.0, f32::max);
+    let backward_max = backward.iter().copied().fold(0.0, f32::max);
+    let count = deviations.len() + backward.len();
+    let sum = deviations.iter().map(|(_, d)| d.abs()).sum::<f32>() + backward.iter().sum::<f32>();
+    let mean = |sum: f32, n: usize| if n > 0 { sum / n as f32 } else { 0.0 };
+
+    let report = CompareReport {
+        unit,
+        printed_volume: printed.len() as f32 * voxel_volume,
+        reference_volume: reference.len() as f32 * voxel_volume,
+        intersection_volume: intersection as f32 * voxel_volume,
+        iou: mean(intersection as f32, union),
+        solid_iou: mean(solid_intersection as f32, solid_union),
+        hausdorff: forward_max.max(backward_max),
+        mean_deviation: mean(sum, count),
+        mean_signed_deviation: mean(deviations.iter().map(|(_, d)| d).sum(), deviations.len()),
+        offset: [offset[0], offset[1], offset[2]],
+    };
+    Comparison { report, deviations }
+}
+
+/// Printed surface voxels binned by deviation, blue (inside) to red
+/// (outside), saturating at `range` millimeters.
+pub fn heatmap_groups<V: Voxel + Default>(
+    deviations: &[(VoxelIdx, f32)],
+    range: f32,
+) -> Vec<ModelGroup> {
+    const BINS: usize = 9;
+    let mut bins = (0..BINS).map(|_| V::default()).collect::<Vec<_>>();
+    for (pos, d) in deviations {
+        let t = ((d / range).clamp(-1.0, 1.0) + 1.0) / 2.0;
+        let bin = ((t * BINS as f32) as usize).min(BINS - 1);
+        bins[bin].add(*pos);
+    }
+
+    bins.into_iter()
+        .enumerate()
+        .map(|(i, mut v)| {
+            let lo = (i as f32 / BINS as f32 * 2.0 - 1.0) * range;
+            let hi = ((i + 1) as f32 / BINS as f32 * 2.0 - 1.0) * range;
+            // blue -> white -> red
+            let t = (i as f32 + 0.5) / BINS as f32 * 2.0 - 1.0;
+            let color = if t < 0.0 {
+                [1.0 + t, 1.0 + t, 1.0, 1.0]
+            } else {
+                [1.0, 1.0 - t, 1.0 - t, 1.0]
+            };
+            ModelGroup {
+                name: format!("{:+.3}..{:+.3}mm", lo, hi),
+                color,
+                models: v.to_model(),
+            }
+        })
+        .collect()
+}
+
+/// Simulates `gcode` and compares the result with the mesh at `model`.
+/// Without an explicit `offset`, the mesh is centered on the slicer's
+/// bounding box (or the printed voxels) and placed on the bed.
+pub fn compare_gcode<V: Voxel + Default>(
+    gcode: &str,
+    model: &str,
+    options: &GenerateOptions,
+    offset: Option<Vector3<f32>>,
+) -> Result<Comparison> {
+    let mut state = ExtrudeState::<V>::default();
+    options.apply(&mut state)?;
+    // record objects, to leave out skirts and purge lines
+    state.set_split(SplitBy::Object);
+    let meta = simulate_file(&mut state, gcode, options.parse_mode)?;
+
+    let unit = state.params.unit;
+    let voxels = state
+        .meta()
+        .map(|m| m.iter().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let in_objects = voxels.iter().any(|(_, m)| m.object != 0);
+    let printed = voxels
+        .iter()
+        .filter(|(_, m)| !in_objects || m.object != 0)
+        .map(|(pos, _)| **pos)
+        .collect::<AHashSet<_>>();
+
+    let mut mesh = TriMesh::load(model)?;
+    let offset = match offset {
+        Some(offset) => offset,
+        None => {
+            let (min, max) = mesh
+                .bounding_box()
+                .ok_or_else(|| anyhow::anyhow!("empty mesh: {}", model))?;
+            let target = match meta.bounding_box {
+                Some((min, max)) => (min + max) / 2.0,
+                None => {
+                    let mut it = printed.iter();
+                    let first = it.next().copied().unwrap_or_default();
+                    let (lo, hi) =
+                        it.fold((first, first), |(lo, hi), p| (lo.bb_min(p), hi.bb_max(p)));
+                    (Vector3::from(lo.f32()) + Vector3::from(hi.f32())) * unit / 2.0 - state.home
+                }
+            };
+            let center = (min + max) / 2.0;
+            Vector3::new(target.x - center.x, target.y - center.y, -min.z)
+        }
+    };
+    // voxels are placed relative to the home offset
+    mesh.translate(offset + state.home);
+    let reference = voxelize(&mesh, unit);
+
+    Ok(compare_voxels(&printed, &reference, unit, offset))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CUBE: &str = "v 0 0 0\nv 2 0 0\nv 2 2 0\nv 0 2 0\nv 0 0 2\nv 2 0 2\nv 2 2 2\nv 0 2 2\n\
+        f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 2 3 7 6\nf 3 4 8 7\nf 4 1 5 8\n";
+
+    #[test]
+    fn test_voxelize() {
+        let mut mesh = TriMesh::from_obj(CUBE).unwrap();
+        // keep the faces off the voxel centers
+        mesh.translate(Vector3::repeat(0.1));
+        let voxels = voxelize(&mesh, 0.5);
+        assert_eq!(voxels.len(), 64);
+
+        // hollow it out: the void is enclosed, so it counts as solid
+        let mut shell = voxels.clone();
+        shell.retain(|v| (0..3).any(|i| v[i] == 1 || v[i] == 4));
+        let (solid, surface) = solidify(&shell);
+        assert_eq!(solid.len(), 64);
+        assert_eq!(surface.len(), 56);
+
+        // without its lid it is a cup, open to the outside
+        shell.retain(|v| v[2] != 4);
+        let (solid, surface) = solidify(&shell);
+        assert_eq!(solid.len(), shell.len());
+        assert_eq!(surface.len(), shell.len());
+    }
+
+    /// `n` voxels along each side, starting at `x0`.
+    fn cube(x0: i32, n: i32) -> AHashSet<VoxelIdx> {
+        let mut out = AHashSet::default();
+        for x in x0..x0 + n {
+            for y in 0..n {
+                for z in 0..n {
+                    out.insert(VoxelIdx::new([x, y, z]));
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_compare_voxels() {
+        let unit = 0.1;
+        let same = compare_voxels(&cube(0, 10), &cube(0, 10), unit, Vector3::zeros()).report;
+        assert_eq!((same.iou, same.solid_iou), (1.0, 1.0));
+        assert_eq!((same.hausdorff, same.mean_signed_deviation), (0.0, 0.0));
+
+        // shifted by one voxel along X
+        let shifted = compare_voxels(&cube(0, 10), &cube(1, 10), unit, Vector3::zeros());
+        let report = &shifted.report;
+        assert!((report.iou - 900.0 / 1100.0).abs() < 1e-6, "{}", report.iou);
+        assert!((report.intersection_volume - 0.9).abs() < 1e-4);
+        assert!(
+            (report.hausdorff - unit).abs() < 1e-6,
+            "{}",
+            report.hausdorff
+        );
+        // the face at x=0 sticks out, the middle of the one at x=9 falls
+        // short of the reference's x=10
+        let outside = shifted.deviations.iter().filter(|(_, d)| *d > 0.0).count();
+        let inside = shifted.deviations.iter().filter(|(_, d)| *d < 0.0).count();
+        assert_eq!((outside, inside), (100, 64));
+        let signed = (100.0 - 64.0) * unit / shifted.deviations.len() as f32;
+        assert!((report.mean_signed_deviation - signed).abs() < 1e-6);
+
+        let groups = heatmap_groups::<MonotonicVoxel>(&shifted.deviations, unit);
+        assert_eq!(groups.len(), 9);
+        assert_eq!(groups[0].name, "-0.100..-0.078mm");
+        // inside, on the surface, and outside
+        let filled = groups
+            .iter()
+            .map(|g| !g.models.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            filled,
+            [true, false, false, false, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_compare_gcode() {
+        // a 10x10x1mm block, solid
+        let mut src = "M83\nG1 F3000\n".to_string();
+        for layer in 1..=5 {
+            src += &format!("G1 Z{:.1}\n", layer as f32 * 0.2);
+            for i in 0..25 {
+                let y = 10.2 + i as f32 * 0.4;
+                src += &format!("G1 X10 Y{:.1}\nG1 X20 Y{:.1} E0.33\n", y, y);
+            }
+        }
+        let dir = std::env::temp_dir();
+        let gcode = dir.join(format!("tdp-tl-compare-{}.gcode", std::process::id()));
+        let model = dir.join(format!("tdp-tl-compare-{}.obj", std::process::id()));
+        std::fs::write(&gcode, src).unwrap();
+        let block = "v 0 0 0\nv 10 0 0\nv 10 10 0\nv 0 10 0\n\
+            v 0 0 1\nv 10 0 1\nv 10 10 1\nv 0 10 1\n";
+        let faces = &CUBE[CUBE.find('f').unwrap()..];
+        std::fs::write(&model, format!("{}{}", block, faces)).unwrap();
+
+        let comparison = compare_gcode::<MonotonicVoxel>(
+            gcode.to_str().unwrap(),
+            model.to_str().unwrap(),
+            &GenerateOptions::default(),
+            None,
+        );
+        std::fs::remove_file(&gcode).unwrap();
+        std::fs::remove_file(&model).unwrap();
+        let report = comparison.unwrap().report;
+
+        // placed onto the print, which matches it up to the rounded edges
+        // of the strands
+        let offset = Vector3::from(report.offset);
+        assert!(
+            (offset - Vector3::new(10.0, 10.0, 0.0)).magnitude() < 0.3,
+            "{:?}",
+            offset
+        );
+        assert!(report.iou > 0.75, "{}", report.iou);
+        assert!(report.hausdorff < 0.6, "{}", report.hausdorff);
+        assert!(report.mean_signed_deviation.abs() < 0.2);
+    }
+}
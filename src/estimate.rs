@@ -0,0 +1,270 @@
+use super::*;
+use serde::Serialize;
+
+/// PLA, g/cm^3
+pub const DEFAULT_DENSITY: f32 = 1.24;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LayerTime {
+    /// `None` for everything before the first layer marker
+    pub layer: Option<usize>,
+    pub seconds: f32,
+    /// average part cooling fan speed, 0 to 1; `None` while the G-code has
+    /// not set the fan
+    pub fan: Option<f32>,
+}
+
+/// Simulated print time and material use, next to the slicer's numbers.
+#[derive(Clone, Debug, Serialize)]
+pub struct Estimate {
+    pub seconds: f32,
+    pub layers: Vec<LayerTime>,
+    /// mm
+    pub filament_length: f32,
+    /// mm^3
+    pub filament_volume: f32,
+    /// g
+    pub filament_mass: f32,
+    /// mm^3
+    pub deposited_volume: f32,
+
+    /// `;TIME:`, seconds
+    pub slicer_seconds: Option<f32>,
+    /// `;Filament used:`, mm
+    pub slicer_filament_length: Option<f32>,
+
+    /// with `GenerateOptions::mass_report`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mass: Option<MassReport>,
+    /// with `GenerateOptions::max_flow`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow: Option<FlowReport>,
+}
+
+impl Estimate {
+    /// Simulated minus slicer time, in seconds.
+    pub fn time_delta(&self) -> Option<f32> {
+        self.slicer_seconds.map(|t| self.seconds - t)
+    }
+
+    /// Simulated minus slicer filament, in millimeters.
+    pub fn filament_delta(&self) -> Option<f32> {
+        self.slicer_filament_length
+            .map(|l| self.filament_length - l)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Report<'a> {
+            #[serde(flatten)]
+            estimate: &'a Estimate,
+            time_delta: Option<f32>,
+            filament_delta: Option<f32>,
+        }
+
+        let report = Report {
+            estimate: self,
+            time_delta: self.time_delta(),
+            filament_delta: self.filament_delta(),
+        };
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+}
+
+fn format_duration(seconds: f32) -> String {
+    let s = seconds.round() as u64;
+    format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+fn format_delta(delta: Option<f32>, reference: Option<f32>, unit: &str) -> String {
+    match (delta, reference) {
+        (Some(delta), Some(reference)) if reference != 0.0 => {
+            format!("{:+.1}{} ({:+.1}%)", delta, unit, delta / reference * 100.0)
+        }
+        (Some(delta), _) => format!("{:+.1}{}", delta, unit),
+        _ => "-".to_string(),
+    }
+}
+
+impl std::fmt::Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let slicer_time = self.slicer_seconds.map_or("-".to_string(), format_duration);
+        let slicer_length = self
+            .slicer_filament_length
+            .map_or("-".to_string(), |l| format!("{:.1}mm", l));
+
+        writeln!(
+            f,
+            "{:<18} {:>14} {:>14} {:>20}",
+            "", "simulated", "slicer", "delta"
+        )?;
+        writeln!(
+            f,
+            "{:<18} {:>14} {:>14} {:>20}",
+            "print time",
+            format_duration(self.seconds),
+            slicer_time,
+            format_delta(self.time_delta(), self.slicer_seconds, "s"),
+        )?;
+        writeln!(
+            f,
+            "{:<18} {:>14} {:>14} {:>20}",
+            "filament length",
+            format!("{:.1}mm", self.filament_length),
+            slicer_length,
+            format_delta(self.filament_delta(), self.slicer_filament_length, "mm"),
+        )?;
+        writeln!(
+            f,
+            "{:<18} {:>12.1}mm3",
+            "filament volume", self.filament_volume
+        )?;
+        writeln!(f, "{:<18} {:>14.2}g", "filament mass", self.filament_mass)?;
+        writeln!(
+            f,
+            "{:<18} {:>12.1}mm3",
+            "deposited volume", self.deposited_volume
+        )?;
+
+        writeln!(f)?;
+        writeln!(f, "{:>6} {:>10} {:>6}", "layer", "time", "fan")?;
+        for layer in &self.layers {
+            let name = layer.layer.map_or("start".to_string(), |l| l.to_string());
+            let fan = layer
+                .fan
+                .map_or("-".to_string(), |fan| format!("{:.0}%", fan * 100.0));
+            writeln!(f, "{:>6} {:>9.1}s {:>6}", name, layer.seconds, fan)?;
+        }
+
+        if let Some(mass) = &self.mass {
+            writeln!(f)?;
+            write!(f, "{}", mass)?;
+        }
+        if let Some(flow) = &self.flow {
+            writeln!(f)?;
+            write!(f, "{}", flow)?;
+        }
+        Ok(())
+    }
+}
+
+/// Simulates the whole file and reports time and material use. `density` is
+/// in g/cm^3.
+pub fn estimate_gcode<V: Voxel + Default>(
+    filename: &str,
+    options: &GenerateOptions,
+    density: f32,
+) -> Result<Estimate> {
+    let mut state = ExtrudeState::<V>::default();
+    options.apply(&mut state)?;
+
+    let mut layers = vec![];
+    let mut layer = None;
+    let mut layer_start = 0.0;
+    let mut duty_start = 0.0;
+    // average fan speed since `layer_start`
+    let fan = |state: &ExtrudeState<V>, layer_start: f32, duty_start: f64| {
+        let fan = state.fan();
+        let seconds = state.wall_seconds() - layer_start;
+        fan.target.map(|_| {
+            if seconds > 0.0 {
+                ((fan.duty() - duty_start) / seconds as f64) as f32
+            } else {
+                fan.speed
+            }
+        })
+    };
+
+    let meta = simulate_file_with(&mut state, filename, options.parse_mode, |state, item| {
+        if let GCode1::Layer(idx) = *item {
+            if layer != Some(idx) {
+                let now = state.wall_seconds();
+                layers.push(LayerTime {
+                    layer,
+                    seconds: now - layer_start,
+                    fan: fan(state, layer_start, duty_start),
+                });
+                layer = Some(idx);
+                layer_start = now;
+                duty_start = state.fan().duty();
+            }
+        }
+        Ok(true)
+    })?;
+    layers.push(LayerTime {
+        layer,
+        seconds: state.wall_seconds() - layer_start,
+        fan: fan(&state, layer_start, duty_start),
+    });
+
+    let filament_volume = state.filament_volume();
+    Ok(Estimate {
+        seconds: state.wall_seconds(),
+        layers,
+        filament_length: state.filament_length(),
+        filament_volume,
+        // mm^3 to cm^3
+        filament_mass: filament_volume / 1000.0 * density,
+        deposited_volume: state.deposited_volume(),
+
+        slicer_seconds: meta.time,
+        slicer_filament_length: meta.filament_used,
+
+        mass: state.mass_report().cloned(),
+        flow: state.flow_report().cloned(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        // without acceleration every move takes length / feedrate
+        let src = ";TIME:10\n\
+                   ;Filament used: 0.003m\n\
+                   SET_VELOCITY_LIMIT ACCEL=0\n\
+                   M83\n\
+                   G1 Z0.2 F600\n\
+                   ;LAYER:0\n\
+                   G1 X10 E0.5 F1200\n\
+                   G1 Y10 E0.5\n\
+                   G4 S2\n\
+                   ;LAYER:1\n\
+                   G1 Z0.4 F600\n\
+                   G1 X0 E1 F1200\n";
+        let path =
+            std::env::temp_dir().join(format!("tdp-tl-estimate-{}.gcode", std::process::id()));
+        std::fs::write(&path, src).unwrap();
+        let estimate = estimate_gcode::<MonotonicVoxel>(
+            path.to_str().unwrap(),
+            &GenerateOptions::default(),
+            DEFAULT_DENSITY,
+        );
+        std::fs::remove_file(&path).unwrap();
+        let estimate = estimate.unwrap();
+
+        assert!(
+            (estimate.seconds - 3.54).abs() < 1e-4,
+            "{}",
+            estimate.seconds
+        );
+        let layers = estimate
+            .layers
+            .iter()
+            .map(|l| (l.layer, (l.seconds * 100.0).round() / 100.0))
+            .collect::<Vec<_>>();
+        assert_eq!(layers, vec![(None, 0.02), (Some(0), 3.0), (Some(1), 0.52)]);
+
+        assert!((estimate.filament_length - 2.0).abs() < 1e-5);
+        let volume = 2.0 * ToolParams::new(0).cross_section();
+        assert!((estimate.filament_volume - volume).abs() < 1e-3);
+        assert!((estimate.filament_mass - volume / 1000.0 * DEFAULT_DENSITY).abs() < 1e-6);
+        assert_eq!(
+            estimate.time_delta().map(|d| (d * 100.0).round()),
+            Some(-646.0)
+        );
+        assert_eq!(estimate.filament_delta(), Some(-1.0));
+    }
+}
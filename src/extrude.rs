@@ -30,20 +30,33 @@ pub fn extrude_at<V: Voxel>(
     max_dist: usize,
     cells: &[VoxelIdx],
     n: usize,
+) -> usize {
+    extrude_at_with(v, zrange, max_dist, cells, n, |_| {})
+}
+
+/// Same as `extrude_at`, calling `on_add` for every newly filled voxel.
+pub fn extrude_at_with<V: Voxel, F: FnMut(VoxelIdx)>(
+    v: &mut V,
+    zrange: Range<i32>,
+    max_dist: usize,
+    cells: &[VoxelIdx],
+    n: usize,
+    mut on_add: F,
 ) -> usize {
     if true {
-        extrude_at_queue(v, zrange, max_dist, cells, n)
+        extrude_at_queue(v, zrange, max_dist, cells, n, &mut on_add)
     } else {
-        extrude_at_deque(v, zrange, max_dist, cells, n)
+        extrude_at_deque(v, zrange, max_dist, cells, n, &mut on_add)
     }
 }
 
-pub fn extrude_at_queue<V: Voxel>(
+pub fn extrude_at_queue<V: Voxel, F: FnMut(VoxelIdx)>(
     v: &mut V,
     zrange: Range<i32>,
     max_dist: usize,
     cells: &[VoxelIdx],
     n: usize,
+    on_add: &mut F,
 ) -> usize {
     use std::collections::BinaryHeap;
 
@@ -88,6 +101,7 @@ pub fn extrude_at_queue<V: Voxel>(
         }
 
         if v.add(pos) {
+            on_add(pos);
             extrudeed += 1;
             if n == extrudeed {
                 break;
@@ -128,12 +142,13 @@ pub fn extrude_at_queue<V: Voxel>(
     extrudeed
 }
 
-pub fn extrude_at_deque<V: Voxel>(
+pub fn extrude_at_deque<V: Voxel, F: FnMut(VoxelIdx)>(
     v: &mut V,
     zrange: Range<i32>,
     max_dist: usize,
     cells: &[VoxelIdx],
     n: usize,
+    on_add: &mut F,
 ) -> usize {
     use std::collections::VecDeque;
 
@@ -162,6 +177,7 @@ pub fn extrude_at_deque<V: Voxel>(
 
     while let Some(HeapItem { pos, depth }) = candidates.pop_front() {
         if v.add(pos) {
+            on_add(pos);
             extrudeed += 1;
             if n == extrudeed {
                 break;
@@ -0,0 +1,127 @@
+/// Part cooling fan: spins towards the commanded speed with a first-order
+/// lag. Cooling sets the strands sooner, so they sag less and keep a rounder
+/// bead; without it they stay liquid longer and flatten out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FanParams {
+    /// seconds, time constant of spinning up or down
+    pub spin_time: f32,
+    /// extra bead width, as a fraction, with the fan off
+    pub flatten: f32,
+}
+
+impl Default for FanParams {
+    fn default() -> Self {
+        Self {
+            spin_time: 1.0,
+            flatten: 0.5,
+        }
+    }
+}
+
+impl FanParams {
+    /// Multiplier of the bead width at `cooling` (0 to 1).
+    pub fn spread(&self, cooling: f32) -> f32 {
+        1.0 + self.flatten.max(0.0) * (1.0 - cooling.clamp(0.0, 1.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fan {
+    /// 0 to 1
+    pub speed: f32,
+    /// `None` until the G-code sets the fan; uncontrolled fans are assumed
+    /// to run as `SagParams::cooling` says
+    pub target: Option<f32>,
+    // integral of the speed over time, seconds
+    duty: f64,
+}
+
+impl Fan {
+    pub fn set(&mut self, target: f32) {
+        self.target = Some(target.clamp(0.0, 1.0));
+    }
+
+    pub fn advance(&mut self, params: &FanParams, seconds: f32) {
+        let Some(target) = self.target else {
+            return;
+        };
+        let before = self.speed;
+        let tau = params.spin_time.max(0.0);
+        let decay = if tau > 0.0 {
+            (-seconds / tau).exp()
+        } else {
+            0.0
+        };
+        self.speed = target + (before - target) * decay;
+        self.duty += (target * seconds + (before - target) * tau * (1.0 - decay)) as f64;
+    }
+
+    /// Cooling (0 to 1) the deposited material gets; `default` while the
+    /// fan is uncontrolled.
+    pub fn cooling(&self, default: f32) -> f32 {
+        match self.target {
+            Some(_) => self.speed,
+            None => default,
+        }
+    }
+
+    /// Integral of the speed over time, in seconds at full speed.
+    pub fn duty(&self) -> f64 {
+        self.duty
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_fan() {
+        let params = FanParams::default();
+        let mut fan = Fan::default();
+        assert_eq!(fan.cooling(1.0), 1.0);
+
+        fan.set(1.0);
+        assert_eq!(fan.cooling(1.0), 0.0);
+        fan.advance(&params, params.spin_time);
+        assert!((fan.speed - (1.0 - (-1f32).exp())).abs() < 1e-5);
+        fan.advance(&params, 10.0);
+        assert!(fan.speed > 0.99);
+        assert!(fan.duty() > 9.0 && fan.duty() < 11.0);
+
+        assert_eq!(params.spread(1.0), 1.0);
+        assert!(params.spread(0.0) > params.spread(0.5));
+    }
+
+    #[test]
+    fn test_sag() {
+        // a 12mm bridge between two pillars 1mm high
+        let mut src = "M83\nG1 F1200\n".to_string();
+        for layer in 1..=5 {
+            src += &format!("G1 Z{:.1}\n", layer as f32 * 0.2);
+            for x0 in [0.0, 14.0] {
+                for i in 0..=5 {
+                    let y = i as f32 * 0.4;
+                    src += &format!("G1 X{} Y{:.1}\nG1 X{} E0.07\n", x0, y, x0 + 2.0);
+                }
+            }
+        }
+        src += "G1 Z1.2\nG4 S20\nG1 X1 Y1\nG1 X15 E0.46\n";
+
+        // lowest point of the bridge
+        let run = |fan: &str| {
+            let state = simulated(&format!("{}\n{}", fan, src), |state| {
+                state.params.e_alpha = 1.0;
+            });
+            column(&state, 8.0, 1.0)[0]
+        };
+        let cooled = run("M106 S255");
+        let uncontrolled = run("");
+        let uncooled = run("M107");
+        // an unset fan cools fully
+        assert_eq!(cooled, uncontrolled);
+        // without the fan the strand stays liquid longer and sags further
+        assert!(uncooled < cooled, "{} {}", uncooled, cooled);
+    }
+}
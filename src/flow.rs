@@ -0,0 +1,219 @@
+use super::*;
+use serde::Serialize;
+
+/// Melting capacity of the hotend. Up to about `max`, all filament pushed
+/// in comes out molten; beyond it the output saturates and lines starve.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct FlowLimit {
+    /// mm^3/s
+    pub max: f32,
+    /// how abruptly the output saturates; large values give a hard limit
+    pub knee: f32,
+}
+
+impl FlowLimit {
+    pub fn new(max: f32) -> Self {
+        Self { max, knee: 4.0 }
+    }
+
+    /// Flow (mm^3/s) coming out of the nozzle for `commanded` mm^3/s.
+    pub fn delivered(&self, commanded: f32) -> f32 {
+        if commanded <= 0.0 || self.max <= 0.0 {
+            return commanded.max(0.0);
+        }
+        let k = self.knee.max(1.0);
+        commanded / (1.0 + (commanded / self.max).powf(k)).powf(1.0 / k)
+    }
+}
+
+/// A run of consecutive moves of one layer and feature over the limit.
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowExcess {
+    /// source lines of the first and last move
+    pub lines: [Option<usize>; 2],
+    pub layer: Option<usize>,
+    pub feature: Option<String>,
+    /// start and end of the run, mm
+    pub from: [f32; 3],
+    pub to: [f32; 3],
+    pub moves: usize,
+    /// mm^3/s, highest commanded flow
+    pub peak: f32,
+    /// mm^3 which did not come out
+    pub starved: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowReport {
+    pub limit: FlowLimit,
+    /// mm^3/s, highest commanded flow of the whole print
+    pub peak: f32,
+    /// mm^3 which did not come out
+    pub starved: f32,
+    pub excess: Vec<FlowExcess>,
+    // the last move was over the limit; it extends the last run
+    #[serde(skip)]
+    open: bool,
+}
+
+/// One extrusion move, for `FlowReport::add`.
+pub(crate) struct FlowSample<'a> {
+    pub line: Option<usize>,
+    pub layer: Option<usize>,
+    pub feature: Option<&'a str>,
+    pub from: Vector3<f32>,
+    pub to: Vector3<f32>,
+    /// mm^3/s
+    pub flow: f32,
+    /// mm^3
+    pub starved: f32,
+}
+
+impl FlowReport {
+    pub fn new(limit: FlowLimit) -> Self {
+        Self {
+            limit,
+            peak: 0.0,
+            starved: 0.0,
+            excess: Vec::new(),
+            open: false,
+        }
+    }
+
+    pub(crate) fn add(&mut self, s: FlowSample) {
+        self.peak = self.peak.max(s.flow);
+        self.starved += s.starved;
+        if s.flow <= self.limit.max {
+            self.open = false;
+            return;
+        }
+
+        let feature = s.feature.map(|f| f.to_string());
+        match self.excess.last_mut() {
+            Some(last) if self.open && last.layer == s.layer && last.feature == feature => {
+                last.lines[1] = s.line;
+                last.to = s.to.into();
+                last.moves += 1;
+                last.peak = last.peak.max(s.flow);
+                last.starved += s.starved;
+            }
+            _ => self.excess.push(FlowExcess {
+                lines: [s.line, s.line],
+                layer: s.layer,
+                feature,
+                from: s.from.into(),
+                to: s.to.into(),
+                moves: 1,
+                peak: s.flow,
+                starved: s.starved,
+            }),
+        }
+        self.open = true;
+    }
+
+    /// A move which does not extrude ends the current run.
+    pub(crate) fn interrupt(&mut self) {
+        self.open = false;
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl std::fmt::Display for FlowReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "max flow {:.1}mm3/s, peak commanded {:.1}mm3/s, starved {:.1}mm3",
+            self.limit.max, self.peak, self.starved
+        )?;
+        if self.excess.is_empty() {
+            return Ok(());
+        }
+
+        let mut excess = self.excess.iter().collect::<Vec<_>>();
+        excess.sort_by(|a, b| b.starved.total_cmp(&a.starved));
+        writeln!(
+            f,
+            "{} runs over the limit, most starved (mm3):",
+            self.excess.len()
+        )?;
+        for e in excess.iter().take(10) {
+            writeln!(
+                f,
+                "  line {:>7} layer {:>4} {:<16} {:>4} moves, peak {:>5.1}mm3/s: {:.3}",
+                e.lines[0].map_or("-".to_string(), |l| l.to_string()),
+                e.layer.map_or("-".to_string(), |l| l.to_string()),
+                e.feature.as_deref().unwrap_or("none"),
+                e.moves,
+                e.peak,
+                e.starved
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_flow_limit() {
+        let limit = FlowLimit::new(10.0);
+        assert!(limit.delivered(2.0) > 2.0 * 0.999);
+        assert!(limit.delivered(10.0) < 10.0 && limit.delivered(10.0) > 8.0);
+        assert!(limit.delivered(100.0) < 10.0);
+        assert!(limit.delivered(20.0) > limit.delivered(15.0));
+
+        let mut report = FlowReport::new(limit);
+        let sample = |line, flow| FlowSample {
+            line: Some(line),
+            layer: Some(1),
+            feature: Some("FILL"),
+            from: Vector3::zeros(),
+            to: Vector3::new(line as f32, 0.0, 0.0),
+            flow,
+            starved: (flow - limit.delivered(flow)) * 0.1,
+        };
+        report.add(sample(1, 12.0));
+        report.add(sample(2, 15.0));
+        report.add(sample(3, 5.0));
+        report.add(sample(4, 12.0));
+        assert_eq!(report.excess.len(), 2);
+        assert_eq!(report.excess[0].lines, [Some(1), Some(2)]);
+        assert_eq!(report.excess[0].peak, 15.0);
+        assert_eq!(report.peak, 15.0);
+        assert!(report.starved > 0.0);
+    }
+
+    #[test]
+    fn test_starved() {
+        // a 40mm line, 0.4mm wide and 0.2mm high
+        let run = |feed: u32, limit: Option<f32>| {
+            let src = format!("M83\nG1 X10 Y10 Z0.2\nG1 X50 E1.33 F{}\n", feed);
+            let state = simulated(&src, |state| {
+                state.params.e_alpha = 1.0;
+                if let Some(max) = limit {
+                    state.enable_flow_limit(FlowLimit::new(max));
+                }
+            });
+            let starved = state.flow_report().map_or(0.0, |r| r.starved);
+            (state.deposited_volume(), starved)
+        };
+
+        // about 1.6mm^3/s: well within the limit
+        let (free, _) = run(1200, None);
+        let (slow, starved) = run(1200, Some(8.0));
+        assert!((slow - free).abs() < free * 0.01, "{} {}", slow, free);
+        assert!(starved < 0.01);
+
+        // about 16mm^3/s: the line comes out thin
+        let (free, _) = run(12000, None);
+        let (fast, starved) = run(12000, Some(8.0));
+        assert!(fast < free * 0.9, "{} {}", fast, free);
+        assert!((free - fast - starved).abs() < free * 0.05, "{}", starved);
+    }
+}
@@ -60,6 +60,29 @@ impl FSNVoxel {
         }
     }
 
+    // the chunk of `coord`, and its neighbours if `coord` is on their border
+    fn setdirty_around(&mut self, coord: VoxelIdx) {
+        let coord_dirty = coord.shift_down(CELL_SIZE_BITS);
+        self.setdirty(coord_dirty);
+
+        let [xx, yy, zz] = cell_idx(coord);
+        if xx == 0 {
+            self.setdirty(coord_dirty + VoxelIdx::new([-1, 0, 0]));
+        } else if xx == (CELL_SIZE - 1) {
+            self.setdirty(coord_dirty + VoxelIdx::new([1, 0, 0]));
+        }
+        if yy == 0 {
+            self.setdirty(coord_dirty + VoxelIdx::new([0, -1, 0]));
+        } else if yy == (CELL_SIZE - 1) {
+            self.setdirty(coord_dirty + VoxelIdx::new([0, 1, 0]));
+        }
+        if zz == 0 {
+            self.setdirty(coord_dirty + VoxelIdx::new([0, 0, -1]));
+        } else if zz == (CELL_SIZE - 1) {
+            self.setdirty(coord_dirty + VoxelIdx::new([0, 0, 1]));
+        }
+    }
+
     fn rebuild_model(&mut self, idx: u64) -> Rc<Model> {
         let model = self.build_model(idx);
         self.model_cache.insert(idx, model.clone());
@@ -219,27 +242,16 @@ impl Voxel for FSNVoxel {
         if !added {
             return false;
         }
+        self.setdirty_around(coord);
+        true
+    }
 
-        let coord_dirty = coord.shift_down(CELL_SIZE_BITS);
-        self.setdirty(coord_dirty);
-
-        let [xx, yy, zz] = cell_idx(coord);
-        if xx == 0 {
-            self.setdirty(coord_dirty + VoxelIdx::new([-1, 0, 0]));
-        } else if xx == (CELL_SIZE - 1) {
-            self.setdirty(coord_dirty + VoxelIdx::new([1, 0, 0]));
-        }
-        if yy == 0 {
-            self.setdirty(coord_dirty + VoxelIdx::new([0, -1, 0]));
-        } else if yy == (CELL_SIZE - 1) {
-            self.setdirty(coord_dirty + VoxelIdx::new([0, 1, 0]));
-        }
-        if zz == 0 {
-            self.setdirty(coord_dirty + VoxelIdx::new([0, 0, -1]));
-        } else if zz == (CELL_SIZE - 1) {
-            self.setdirty(coord_dirty + VoxelIdx::new([0, 0, 1]));
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        let removed = self.base.remove(coord);
+        if !removed {
+            return false;
         }
-
+        self.setdirty_around(coord);
         true
     }
 
@@ -1,13 +1,73 @@
-use anyhow::Result;
+use crate::{HeaterId, SetTemperature, ToolConfig};
+use log::*;
 use nalgebra::Vector3;
-use nom_gcode::{GCodeLine::*, Mnemonic};
+use nom_gcode::{ExtendedCommand, GCodeLine::*, GCodeParseError, Mnemonic};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use thiserror::Error;
 
 #[derive(Clone)]
 pub enum GCode1 {
     Layer(usize),
     TypedComment(String, String),
     Coord(GCode1Coord),
+    /// M82, M83, M400
     Miscellaneous(u32),
+    /// SET_PRESSURE_ADVANCE ADVANCE=, in seconds
+    PressureAdvance(f32),
+    /// SET_VELOCITY_LIMIT
+    VelocityLimit(VelocityLimit),
+    /// EXCLUDE_OBJECT_START NAME=, `;MESH:` (Cura), `; printing object` (PrusaSlicer)
+    ObjectStart(String),
+    /// EXCLUDE_OBJECT_END, `;MESH:NONMESH`, `; stop printing object`
+    ObjectEnd,
+    /// EXCLUDE_OBJECT NAME=, or the current object with CURRENT=1
+    ExcludeObject(Option<String>),
+    /// Tn
+    ToolChange(usize),
+    /// per-tool slicer settings
+    ToolConfig(ToolConfig),
+    /// M220 S, feedrate override in percent
+    SpeedFactor(f32),
+    /// M221 S [T], flow override in percent, for the active tool unless T is given
+    FlowFactor {
+        percent: f32,
+        tool: Option<usize>,
+    },
+    /// G4 P (milliseconds) or S, in seconds
+    Dwell(f32),
+    /// M104/M109/M140/M190
+    Temperature(SetTemperature),
+    /// M106 S (0-255, full speed without S) and M107, for fan P or the part
+    /// cooling fan; `speed` 0 to 1
+    Fan {
+        speed: f32,
+        fan: Option<usize>,
+    },
+    /// M290 Z (babystep) and SET_GCODE_OFFSET Z_ADJUST= are relative,
+    /// SET_GCODE_OFFSET Z= absolute; in mm
+    ZOffset {
+        z: f32,
+        relative: bool,
+    },
+    /// bed leveling: M420 S Z, G29, BED_MESH_CALIBRATE, BED_MESH_PROFILE
+    /// LOAD=, BED_MESH_CLEAR; `fade` in mm
+    Leveling {
+        enable: Option<bool>,
+        fade: Option<f32>,
+    },
+}
+
+/// Motion limits set by SET_VELOCITY_LIMIT; unset fields are left unchanged.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct VelocityLimit {
+    /// mm/s
+    pub velocity: Option<f32>,
+    /// mm/s^2
+    pub accel: Option<f32>,
+    /// mm/s
+    pub square_corner_velocity: Option<f32>,
+    pub minimum_cruise_ratio: Option<f32>,
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -21,33 +81,27 @@ pub struct GCode1Coord {
 }
 
 impl GCode1Coord {
-    fn from_argument<'a>(code: nom_gcode::GCode<'a>) -> Self {
+    /// Returns the offending argument letter if one of the consumed arguments
+    /// has no value.
+    fn from_argument<'a>(code: nom_gcode::GCode<'a>) -> std::result::Result<Self, char> {
         let mut out = Self::default();
         out.major = code.major;
         for (letter, value) in code.arguments() {
             let letter = *letter;
-            let v = match value {
-                Some(v) => *v,
-                None => todo!(),
+            let slot = match letter {
+                'X' => &mut out.x,
+                'Y' => &mut out.y,
+                'Z' => &mut out.z,
+                'E' => &mut out.e,
+                'F' => &mut out.f,
+                _ => continue,
             };
-
-            if letter == 'X' {
-                out.x = Some(v);
-            }
-            if letter == 'Y' {
-                out.y = Some(v);
-            }
-            if letter == 'Z' {
-                out.z = Some(v);
-            }
-            if letter == 'E' {
-                out.e = Some(v);
-            }
-            if letter == 'F' {
-                out.f = Some(v);
+            match value {
+                Some(v) => *slot = Some(*v),
+                None => return Err(letter),
             }
         }
-        out
+        Ok(out)
     }
 
     pub fn apply(&self, other: &Self) -> Self {
@@ -71,47 +125,467 @@ impl GCode1Coord {
     }
 }
 
+/// Errors raised while reading G-code. Line and column numbers are 1-based.
+#[derive(Error, Debug)]
+pub enum GCodeError {
+    #[error("line {line}:{column}: {source}")]
+    Syntax {
+        line: usize,
+        column: usize,
+        text: String,
+        source: GCodeParseError,
+    },
+    #[error("line {line}:{column}: unexpected trailing input {:?}", &text[column - 1..])]
+    TrailingInput {
+        line: usize,
+        column: usize,
+        text: String,
+    },
+    #[error("line {line}:{column}: argument '{letter}' has no value: {text}")]
+    MissingValue {
+        line: usize,
+        column: usize,
+        letter: char,
+        text: String,
+    },
+    #[error("line {line}:{column}: invalid layer number: {text}")]
+    InvalidLayer {
+        line: usize,
+        column: usize,
+        text: String,
+    },
+    #[error("line {line}:{column}: invalid value for parameter {name}: {text}")]
+    InvalidParameter {
+        line: usize,
+        column: usize,
+        name: String,
+        text: String,
+    },
+    #[error("failed to read G-code: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl GCodeError {
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            GCodeError::Syntax { line, .. }
+            | GCodeError::TrailingInput { line, .. }
+            | GCodeError::MissingValue { line, .. }
+            | GCodeError::InvalidLayer { line, .. }
+            | GCodeError::InvalidParameter { line, .. } => Some(*line),
+            GCodeError::Io(_) => None,
+        }
+    }
+
+    pub fn column(&self) -> Option<usize> {
+        match self {
+            GCodeError::Syntax { column, .. }
+            | GCodeError::TrailingInput { column, .. }
+            | GCodeError::MissingValue { column, .. }
+            | GCodeError::InvalidLayer { column, .. }
+            | GCodeError::InvalidParameter { column, .. } => Some(*column),
+            GCodeError::Io(_) => None,
+        }
+    }
+
+    /// The offending source line.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            GCodeError::Syntax { text, .. }
+            | GCodeError::TrailingInput { text, .. }
+            | GCodeError::MissingValue { text, .. }
+            | GCodeError::InvalidLayer { text, .. }
+            | GCodeError::InvalidParameter { text, .. } => Some(text),
+            GCodeError::Io(_) => None,
+        }
+    }
+}
+
+type Result<T, E = GCodeError> = std::result::Result<T, E>;
+
+/// 1-based column of `part`, which must be a subslice of `line`.
+fn column_of(line: &str, part: &str) -> usize {
+    let offset = (part.as_ptr() as usize).wrapping_sub(line.as_ptr() as usize);
+    if offset <= line.len() {
+        offset + 1
+    } else {
+        1
+    }
+}
+
+/// 1-based column of the first `letter` argument without a parsable value.
+fn column_of_arg(line: &str, letter: char) -> usize {
+    let code = line.split(';').next().unwrap_or(line);
+    for token in code.split_whitespace() {
+        let mut chars = token.chars();
+        if chars.next().map(|c| c.to_ascii_uppercase()) != Some(letter) {
+            continue;
+        }
+        if chars.as_str().parse::<f32>().is_err() {
+            return column_of(line, token);
+        }
+    }
+    1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Fail on the first malformed line.
+    #[default]
+    Strict,
+    /// Log and skip malformed lines, collecting them as diagnostics.
+    Lenient,
+}
+
 const PREFIX_LAYER: &'static str = "LAYER:";
+const PREFIX_MESH: &str = "MESH:";
+const PREFIX_OBJECT_START: &str = "printing object ";
+const PREFIX_OBJECT_END: &str = "stop printing object";
 
-pub fn parse_gcode(filename: &str) -> Result<Vec<(usize, GCode1)>> {
-    let gcode = std::fs::read_to_string(filename)?;
-    let mut out = Vec::new();
-
-    let mut layer_change_idx = 0;
-    for (number, line) in gcode.lines().enumerate() {
-        let number = number + 1;
-        let parsed = nom_gcode::parse_gcode(&line)?;
-        match parsed {
+/// Line-by-line G-code parser. Holds the state that has to be carried across
+/// lines, so it can be fed from a file, an in-memory buffer or a socket.
+#[derive(Default)]
+pub struct GCodeLineParser {
+    layer_change_idx: usize,
+
+    mode: ParseMode,
+    diagnostics: Vec<GCodeError>,
+}
+
+impl GCodeLineParser {
+    pub fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Errors skipped in lenient mode, in the order they were encountered.
+    pub fn diagnostics(&self) -> &[GCodeError] {
+        &self.diagnostics
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<GCodeError> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    pub fn parse_line(&mut self, number: usize, line: &str) -> Result<Option<GCode1>> {
+        match self.parse_line0(number, line) {
+            Err(e) if self.mode == ParseMode::Lenient => {
+                warn!("skipping malformed G-code, {}", e);
+                self.diagnostics.push(e);
+                Ok(None)
+            }
+            res => res,
+        }
+    }
+
+    fn parse_line0(&mut self, number: usize, line: &str) -> Result<Option<GCode1>> {
+        let parsed = nom_gcode::parse_gcode(line).map_err(|source| GCodeError::Syntax {
+            line: number,
+            column: source.offset() + 1,
+            text: line.to_string(),
+            source,
+        })?;
+        let out = match parsed {
             (_, Some(Comment(comment))) => {
                 if comment.0.starts_with(PREFIX_LAYER) {
-                    let layer_idx = comment.0[PREFIX_LAYER.len()..].parse::<usize>()?;
-                    out.push((number, GCode1::Layer(layer_idx)));
+                    let value = &comment.0[PREFIX_LAYER.len()..];
+                    let layer_idx =
+                        value
+                            .trim()
+                            .parse::<usize>()
+                            .map_err(|_| GCodeError::InvalidLayer {
+                                line: number,
+                                column: column_of(line, value),
+                                text: line.to_string(),
+                            })?;
+                    Some(GCode1::Layer(layer_idx))
                 } else if comment.0 == "AFTER_LAYER_CHANGE" {
-                    out.push((number, GCode1::Layer(layer_change_idx)));
-                    layer_change_idx += 1;
+                    let layer_idx = self.layer_change_idx;
+                    self.layer_change_idx += 1;
+                    Some(GCode1::Layer(layer_idx))
+                } else if let Some(name) = comment.0.strip_prefix(PREFIX_MESH) {
+                    match name.trim() {
+                        "NONMESH" => Some(GCode1::ObjectEnd),
+                        name => Some(GCode1::ObjectStart(name.to_string())),
+                    }
+                } else if let Some(name) = comment.0.trim_start().strip_prefix(PREFIX_OBJECT_START)
+                {
+                    Some(GCode1::ObjectStart(name.trim().to_string()))
+                } else if comment.0.trim_start().starts_with(PREFIX_OBJECT_END) {
+                    Some(GCode1::ObjectEnd)
+                } else if let Some(config) = ToolConfig::parse(comment.0) {
+                    Some(GCode1::ToolConfig(config))
                 } else {
                     let mut parts = comment.0.splitn(2, ':');
                     let prefix = parts.next().unwrap_or("");
                     let value = parts.next().unwrap_or("");
-                    out.push((
-                        number,
-                        GCode1::TypedComment(prefix.to_string(), value.to_string()),
-                    ));
+                    Some(GCode1::TypedComment(prefix.to_string(), value.to_string()))
                 }
             }
-            (_, Some(GCode(code))) => {
+            (rest, Some(GCode(code))) => {
+                if !rest.trim().is_empty() {
+                    return Err(GCodeError::TrailingInput {
+                        line: number,
+                        column: column_of(line, rest),
+                        text: line.to_string(),
+                    });
+                }
+
                 if code.mnemonic == Mnemonic::General && [0, 1, 2, 3, 92].contains(&code.major) {
-                    out.push((number, GCode1::Coord(GCode1Coord::from_argument(code))));
+                    let coord = GCode1Coord::from_argument(code).map_err(|letter| {
+                        GCodeError::MissingValue {
+                            line: number,
+                            column: column_of_arg(line, letter),
+                            letter,
+                            text: line.to_string(),
+                        }
+                    })?;
+                    Some(GCode1::Coord(coord))
                 } else if code.mnemonic == Mnemonic::Miscellaneous && [82, 83].contains(&code.major)
                 {
-                    out.push((number, GCode1::Miscellaneous(code.major)));
+                    Some(GCode1::Miscellaneous(code.major))
+                } else if code.mnemonic == Mnemonic::Miscellaneous
+                    && [220, 221].contains(&code.major)
+                {
+                    let percent = Self::arg_of(number, line, &code, 'S')?;
+                    let tool = Self::arg_of(number, line, &code, 'T')?.map(|t| t as usize);
+                    // without S, M220/M221 only report the current value
+                    match (code.major, percent) {
+                        (_, None) => None,
+                        (220, Some(percent)) => Some(GCode1::SpeedFactor(percent)),
+                        (_, Some(percent)) => Some(GCode1::FlowFactor { percent, tool }),
+                    }
+                } else if code.mnemonic == Mnemonic::Miscellaneous
+                    && [104, 109, 140, 190].contains(&code.major)
+                {
+                    let s = Self::arg_of(number, line, &code, 'S')?;
+                    let r = Self::arg_of(number, line, &code, 'R')?;
+                    let tool = Self::arg_of(number, line, &code, 'T')?.map(|t| t as usize);
+                    let heater = match code.major {
+                        104 | 109 => HeaterId::Hotend(tool),
+                        _ => HeaterId::Bed,
+                    };
+                    let wait = [109, 190].contains(&code.major);
+                    s.or(r).map(|target| {
+                        GCode1::Temperature(SetTemperature {
+                            heater,
+                            target,
+                            wait,
+                            wait_cooling: wait && r.is_some(),
+                        })
+                    })
+                } else if code.mnemonic == Mnemonic::Miscellaneous
+                    && [106, 107].contains(&code.major)
+                {
+                    let s = Self::arg_of(number, line, &code, 'S')?;
+                    let fan = Self::arg_of(number, line, &code, 'P')?.map(|p| p as usize);
+                    let speed = match code.major {
+                        106 => s.unwrap_or(255.0).clamp(0.0, 255.0) / 255.0,
+                        _ => 0.0,
+                    };
+                    Some(GCode1::Fan { speed, fan })
+                } else if code.mnemonic == Mnemonic::Miscellaneous && code.major == 400 {
+                    Some(GCode1::Miscellaneous(code.major))
+                } else if code.mnemonic == Mnemonic::Miscellaneous && code.major == 290 {
+                    Self::arg_of(number, line, &code, 'Z')?
+                        .map(|z| GCode1::ZOffset { z, relative: true })
+                } else if code.mnemonic == Mnemonic::Miscellaneous && code.major == 420 {
+                    let enable = Self::arg_of(number, line, &code, 'S')?.map(|s| s != 0.0);
+                    let fade = Self::arg_of(number, line, &code, 'Z')?;
+                    (enable.is_some() || fade.is_some())
+                        .then_some(GCode1::Leveling { enable, fade })
+                } else if code.mnemonic == Mnemonic::General && code.major == 29 {
+                    // probing leaves the new mesh active
+                    Some(GCode1::Leveling {
+                        enable: Some(true),
+                        fade: None,
+                    })
+                } else if code.mnemonic == Mnemonic::General && code.major == 4 {
+                    // P in milliseconds takes precedence over S in seconds
+                    let p = Self::arg_of(number, line, &code, 'P')?;
+                    let s = Self::arg_of(number, line, &code, 'S')?;
+                    Some(GCode1::Dwell(p.map(|p| p / 1000.0).or(s).unwrap_or(0.0)))
+                } else if code.mnemonic == Mnemonic::ToolChange {
+                    Some(GCode1::ToolChange(code.major as usize))
+                } else {
+                    None
                 }
             }
-            (_, _) => (),
+            (_, Some(Extended(command))) => self.parse_extended(number, line, &command)?,
+            (_, _) => None,
+        };
+        Ok(out)
+    }
+
+    /// Value of argument `letter`; present without a value is an error.
+    fn arg_of(
+        number: usize,
+        line: &str,
+        code: &nom_gcode::GCode,
+        letter: char,
+    ) -> Result<Option<f32>> {
+        match code.arguments().find(|(l, _)| *l == letter) {
+            Some((_, Some(value))) => Ok(Some(*value)),
+            Some((_, None)) => Err(GCodeError::MissingValue {
+                line: number,
+                column: column_of_arg(line, letter),
+                letter,
+                text: line.to_string(),
+            }),
+            None => Ok(None),
         }
     }
 
-    Ok(out)
+    /// Klipper extended commands which affect the simulation.
+    fn parse_extended(
+        &mut self,
+        number: usize,
+        line: &str,
+        command: &ExtendedCommand,
+    ) -> Result<Option<GCode1>> {
+        let param_f32 = |name: &str| -> Result<Option<f32>> {
+            match command.param_f32(name) {
+                Some(Ok(v)) => Ok(Some(v)),
+                Some(Err(_)) => Err(GCodeError::InvalidParameter {
+                    line: number,
+                    column: command.param(name).map_or(1, |v| column_of(line, v)),
+                    name: name.to_string(),
+                    text: line.to_string(),
+                }),
+                None => Ok(None),
+            }
+        };
+
+        let out = if command.is("SET_PRESSURE_ADVANCE") {
+            param_f32("ADVANCE")?.map(GCode1::PressureAdvance)
+        } else if command.is("SET_VELOCITY_LIMIT") {
+            Some(GCode1::VelocityLimit(VelocityLimit {
+                velocity: param_f32("VELOCITY")?,
+                accel: param_f32("ACCEL")?,
+                square_corner_velocity: param_f32("SQUARE_CORNER_VELOCITY")?,
+                minimum_cruise_ratio: param_f32("MINIMUM_CRUISE_RATIO")?,
+            }))
+        } else if command.is("SET_GCODE_OFFSET") {
+            match (param_f32("Z")?, param_f32("Z_ADJUST")?) {
+                (Some(z), _) => Some(GCode1::ZOffset { z, relative: false }),
+                (None, Some(z)) => Some(GCode1::ZOffset { z, relative: true }),
+                _ => None,
+            }
+        } else if command.is("BED_MESH_CALIBRATE")
+            || (command.is("BED_MESH_PROFILE") && command.param("LOAD").is_some())
+        {
+            Some(GCode1::Leveling {
+                enable: Some(true),
+                fade: None,
+            })
+        } else if command.is("BED_MESH_CLEAR") {
+            Some(GCode1::Leveling {
+                enable: Some(false),
+                fade: None,
+            })
+        } else if command.is("EXCLUDE_OBJECT_START") {
+            command
+                .param("NAME")
+                .map(|name| GCode1::ObjectStart(name.to_string()))
+        } else if command.is("EXCLUDE_OBJECT_END") {
+            Some(GCode1::ObjectEnd)
+        } else if command.is("EXCLUDE_OBJECT") {
+            match (command.param("NAME"), command.param("CURRENT")) {
+                (Some(name), _) => Some(GCode1::ExcludeObject(Some(name.to_string()))),
+                (None, Some("1")) => Some(GCode1::ExcludeObject(None)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        Ok(out)
+    }
+}
+
+/// Streaming G-code reader, yields `(line, GCode1)` lazily from any `BufRead`.
+pub struct GCodeReader<R: BufRead> {
+    reader: R,
+    parser: GCodeLineParser,
+    buf: String,
+    number: usize,
+}
+
+impl GCodeReader<BufReader<File>> {
+    pub fn open(filename: &str) -> Result<Self> {
+        let file = File::open(filename)?;
+        Ok(Self::new(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> GCodeReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_mode(reader, ParseMode::Strict)
+    }
+
+    pub fn with_mode(reader: R, mode: ParseMode) -> Self {
+        Self {
+            reader,
+            parser: GCodeLineParser::new(mode),
+            buf: String::new(),
+            number: 0,
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[GCodeError] {
+        self.parser.diagnostics()
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<GCodeError> {
+        self.parser.take_diagnostics()
+    }
+}
+
+impl<R: BufRead> Iterator for GCodeReader<R> {
+    type Item = Result<(usize, GCode1)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buf.clear();
+            match self.reader.read_line(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            self.number += 1;
+
+            let line = self.buf.trim_end_matches(['\n', '\r']);
+            match self.parser.parse_line(self.number, line) {
+                Ok(Some(code)) => return Some(Ok((self.number, code))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+pub fn parse_gcode_reader<R: BufRead>(reader: R) -> Result<Vec<(usize, GCode1)>> {
+    GCodeReader::new(reader).collect()
+}
+
+pub fn parse_gcode_str(gcode: &str) -> Result<Vec<(usize, GCode1)>> {
+    parse_gcode_reader(gcode.as_bytes())
+}
+
+pub fn parse_gcode(filename: &str) -> Result<Vec<(usize, GCode1)>> {
+    GCodeReader::open(filename)?.collect()
+}
+
+type Lenient = (Vec<(usize, GCode1)>, Vec<GCodeError>);
+
+/// Parses the whole file in lenient mode; returns the parsed commands together
+/// with the skipped lines.
+pub fn parse_gcode_lenient(filename: &str) -> Result<Lenient> {
+    let mut reader =
+        GCodeReader::with_mode(BufReader::new(File::open(filename)?), ParseMode::Lenient);
+    let parsed = reader.by_ref().collect::<Result<Vec<_>>>()?;
+    Ok((parsed, reader.take_diagnostics()))
 }
 
 #[derive(Debug)]
@@ -128,6 +602,8 @@ pub struct GCodeMeta {
 impl GCodeMeta {
     pub fn from_comments(comments: &[(&str, &str)]) -> Self {
         let mut time = None;
+        // the last one, for files without a ;TIME: header
+        let mut time_elapsed = None;
         let mut flavor = None;
         let mut filament_used = None;
         let mut layer_height = None;
@@ -153,6 +629,11 @@ impl GCodeMeta {
                         time = Some(v);
                     }
                 }
+                "TIME_ELAPSED" => {
+                    if let Ok(v) = value.parse::<f32>() {
+                        time_elapsed = Some(v);
+                    }
+                }
                 "Filament used" => {
                     if value.trim().ends_with("m") {
                         let value = value.trim().trim_end_matches('m');
@@ -223,7 +704,7 @@ impl GCodeMeta {
 
         Self {
             flavor,
-            time,
+            time: time.or(time_elapsed),
             filament_used,
             layer_height,
             bounding_box,
@@ -232,3 +713,232 @@ impl GCodeMeta {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE: &str = ";LAYER:0\nG1 X1 Y2 E0.5\r\nM107\n;TYPE:FILL\nM83\n";
+
+    #[test]
+    pub fn test_parse_str() {
+        let parsed = parse_gcode_str(SAMPLE).unwrap();
+        let lines = parsed.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 2, 3, 4, 5]);
+
+        match &parsed[1].1 {
+            GCode1::Coord(coord) => {
+                assert_eq!(coord.x, Some(1.0));
+                assert_eq!(coord.e, Some(0.5));
+            }
+            _ => panic!("expected coord"),
+        }
+    }
+
+    #[test]
+    pub fn test_reader_lazy() {
+        let mut reader = GCodeReader::new(SAMPLE.as_bytes());
+        assert!(matches!(reader.next(), Some(Ok((1, GCode1::Layer(0))))));
+        assert!(matches!(reader.next(), Some(Ok((2, GCode1::Coord(_))))));
+        assert_eq!(reader.count(), 3);
+    }
+
+    #[test]
+    pub fn test_errors() {
+        let err = parse_gcode_str("G1 X1\nG1 X Y2\n").err().unwrap();
+        assert!(matches!(err, GCodeError::MissingValue { letter: 'X', .. }));
+        assert_eq!(err.line(), Some(2));
+        assert_eq!(err.column(), Some(4));
+
+        let err = parse_gcode_str(";LAYER:abc\n").err().unwrap();
+        assert!(matches!(err, GCodeError::InvalidLayer { .. }));
+        assert_eq!(err.column(), Some(8));
+
+        let err = parse_gcode_str("G1 X1 Y2#3\n").err().unwrap();
+        assert!(matches!(err, GCodeError::TrailingInput { .. }));
+
+        let err = parse_gcode_str("N123 G1 X10*80\n").err().unwrap();
+        assert!(matches!(
+            err,
+            GCodeError::Syntax {
+                source: GCodeParseError::ChecksumMismatch { .. },
+                ..
+            }
+        ));
+        assert_eq!(err.column(), Some(12));
+        assert!(parse_gcode_str("N123 G1 X10*81\n").is_ok());
+        let err = parse_gcode_str("N123 G1 X10*80 ; x*2\n").err().unwrap();
+        assert_eq!(err.column(), Some(12));
+        // not a checksum without a line number
+        assert!(parse_gcode_str("M117 Layer 3*4\n").is_ok());
+    }
+
+    #[test]
+    pub fn test_lenient() {
+        let src = "G1 X1\nG1 X Y2\n;LAYER:x\nG1 X3\n";
+        let mut reader = GCodeReader::with_mode(src.as_bytes(), ParseMode::Lenient);
+        let parsed = reader.by_ref().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(parsed.len(), 2);
+
+        let lines = reader
+            .diagnostics()
+            .iter()
+            .map(|e| e.line().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 3]);
+    }
+
+    #[test]
+    pub fn test_extended() {
+        let src = "SET_PRESSURE_ADVANCE ADVANCE=0.04\n\
+                   SET_VELOCITY_LIMIT VELOCITY=200 ACCEL=3000\n\
+                   EXCLUDE_OBJECT_START NAME=cube\n\
+                   EXCLUDE_OBJECT_END NAME=cube\n";
+        let parsed = parse_gcode_str(src).unwrap();
+        assert!(matches!(parsed[0].1, GCode1::PressureAdvance(v) if v == 0.04));
+        assert!(matches!(
+            parsed[1].1,
+            GCode1::VelocityLimit(VelocityLimit {
+                velocity: Some(_),
+                accel: Some(_),
+                square_corner_velocity: None,
+                ..
+            })
+        ));
+        assert!(matches!(&parsed[2].1, GCode1::ObjectStart(name) if name == "cube"));
+        assert!(matches!(parsed[3].1, GCode1::ObjectEnd));
+
+        let parsed =
+            parse_gcode_str(";MESH:cube.stl\n;MESH:NONMESH\n; printing object a id:0 copy 0\n")
+                .unwrap();
+        assert!(matches!(&parsed[0].1, GCode1::ObjectStart(name) if name == "cube.stl"));
+        assert!(matches!(parsed[1].1, GCode1::ObjectEnd));
+        assert!(matches!(&parsed[2].1, GCode1::ObjectStart(name) if name == "a id:0 copy 0"));
+
+        let err = parse_gcode_str("SET_PRESSURE_ADVANCE ADVANCE=x\n")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            GCodeError::InvalidParameter { column: 30, .. }
+        ));
+    }
+
+    #[test]
+    pub fn test_tools() {
+        let src = "T1\n; filament_diameter = 1.75,2.85\n; filament_colour = #FF0000;\n; extruder_offset = 0x0,20x-1.5\n";
+        let parsed = parse_gcode_str(src).unwrap();
+        assert!(matches!(parsed[0].1, GCode1::ToolChange(1)));
+        assert!(matches!(
+            &parsed[1].1,
+            GCode1::ToolConfig(ToolConfig::FilamentDiameter(v)) if v == &[1.75, 2.85]
+        ));
+        assert!(matches!(
+            &parsed[2].1,
+            GCode1::ToolConfig(ToolConfig::Color(v)) if v == &[Some([1.0, 0.0, 0.0, 1.0]), None]
+        ));
+        assert!(matches!(
+            &parsed[3].1,
+            GCode1::ToolConfig(ToolConfig::Offset(v)) if v[1] == Vector3::new(20.0, -1.5, 0.0)
+        ));
+    }
+
+    #[test]
+    pub fn test_overrides() {
+        let parsed = parse_gcode_str("M220 S150\nM221 S90\nM221 S95 T1\nM221\n").unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert!(matches!(parsed[0].1, GCode1::SpeedFactor(v) if v == 150.0));
+        assert!(matches!(
+            parsed[1].1,
+            GCode1::FlowFactor { percent, tool: None } if percent == 90.0
+        ));
+        assert!(matches!(
+            parsed[2].1,
+            GCode1::FlowFactor { percent, tool: Some(1) } if percent == 95.0
+        ));
+    }
+
+    #[test]
+    pub fn test_dwell_temperature() {
+        let src = "G4 P500\nG4 S2\nM109 S210 T1\nM190 R50\nM104\nM400\n";
+        let parsed = parse_gcode_str(src).unwrap();
+        assert_eq!(parsed.len(), 5);
+        assert!(matches!(parsed[0].1, GCode1::Dwell(s) if s == 0.5));
+        assert!(matches!(parsed[1].1, GCode1::Dwell(s) if s == 2.0));
+        assert!(matches!(
+            parsed[2].1,
+            GCode1::Temperature(SetTemperature {
+                heater: HeaterId::Hotend(Some(1)),
+                wait: true,
+                wait_cooling: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parsed[3].1,
+            GCode1::Temperature(SetTemperature {
+                heater: HeaterId::Bed,
+                wait_cooling: true,
+                ..
+            })
+        ));
+        assert!(matches!(parsed[4].1, GCode1::Miscellaneous(400)));
+    }
+
+    #[test]
+    pub fn test_fan() {
+        let src = "M106 S85\nM106\nM106 P1 S255\nM107\n";
+        let parsed = parse_gcode_str(src).unwrap();
+        assert_eq!(parsed.len(), 4);
+        assert!(
+            matches!(parsed[0].1, GCode1::Fan { speed, fan: None } if (speed - 1.0 / 3.0).abs() < 1e-6)
+        );
+        assert!(matches!(parsed[1].1, GCode1::Fan { speed, .. } if speed == 1.0));
+        assert!(matches!(parsed[2].1, GCode1::Fan { fan: Some(1), .. }));
+        assert!(matches!(parsed[3].1, GCode1::Fan { speed, .. } if speed == 0.0));
+    }
+
+    #[test]
+    pub fn test_z_offset() {
+        let src = "M290 Z-0.02\nSET_GCODE_OFFSET Z=0.1\nSET_GCODE_OFFSET Z_ADJUST=0.05\nM290\n";
+        let parsed = parse_gcode_str(src).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert!(matches!(parsed[0].1, GCode1::ZOffset { z, relative: true } if z == -0.02));
+        assert!(matches!(parsed[1].1, GCode1::ZOffset { z, relative: false } if z == 0.1));
+        assert!(matches!(parsed[2].1, GCode1::ZOffset { z, relative: true } if z == 0.05));
+    }
+
+    #[test]
+    pub fn test_leveling() {
+        let src = "G29\nM420 S0 Z10\nM420\nBED_MESH_PROFILE LOAD=default\nBED_MESH_CLEAR\n";
+        let parsed = parse_gcode_str(src).unwrap();
+        assert_eq!(parsed.len(), 4);
+        assert!(matches!(
+            parsed[0].1,
+            GCode1::Leveling {
+                enable: Some(true),
+                fade: None
+            }
+        ));
+        assert!(matches!(
+            parsed[1].1,
+            GCode1::Leveling { enable: Some(false), fade: Some(z) } if z == 10.0
+        ));
+        assert!(matches!(
+            parsed[3].1,
+            GCode1::Leveling {
+                enable: Some(false),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_demo_strict() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/demo/KK_xyzCalibration_cube.gcode"
+        );
+        assert!(parse_gcode(path).is_ok());
+    }
+}
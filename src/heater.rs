@@ -0,0 +1,166 @@
+/// Below this nozzle temperature, molten filament does not ooze
+/// (Marlin's EXTRUDE_MINTEMP).
+pub const MIN_EXTRUDE_TEMP: f32 = 170.0;
+
+/// Heating model: constant-rate heating, Newtonian cooling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeaterParams {
+    /// °C/s while heating
+    pub heat_rate: f32,
+    /// seconds, time constant of cooling towards ambient
+    pub cool_time: f32,
+    /// °C, how close M109/M190 wait to get to the target
+    pub tolerance: f32,
+}
+
+impl HeaterParams {
+    pub fn hotend() -> Self {
+        Self {
+            heat_rate: 2.5,
+            cool_time: 120.0,
+            tolerance: 1.0,
+        }
+    }
+
+    pub fn bed() -> Self {
+        Self {
+            heat_rate: 0.5,
+            cool_time: 600.0,
+            tolerance: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaterId {
+    /// nozzle of the given tool, the active one when `None`
+    Hotend(Option<usize>),
+    Bed,
+}
+
+/// M104/M109/M140/M190
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SetTemperature {
+    pub heater: HeaterId,
+    /// °C
+    pub target: f32,
+    /// M109/M190
+    pub wait: bool,
+    /// M109 R/M190 R also wait while cooling down
+    pub wait_cooling: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heater {
+    pub temp: f32,
+    /// `None` until the G-code sets a target; uncontrolled heaters are
+    /// assumed to be at printing temperature
+    pub target: Option<f32>,
+}
+
+impl Heater {
+    pub fn new(ambient: f32) -> Self {
+        Self {
+            temp: ambient,
+            target: None,
+        }
+    }
+
+    pub fn is_hot(&self) -> bool {
+        self.target.is_none() || self.temp >= MIN_EXTRUDE_TEMP
+    }
+
+    pub fn advance(&mut self, params: &HeaterParams, ambient: f32, seconds: f32) {
+        let target = match self.target {
+            Some(target) => target,
+            None => return,
+        };
+        if target > self.temp {
+            self.temp = (self.temp + params.heat_rate * seconds).min(target);
+        } else {
+            // heater off, cools down until it has to hold the target again
+            let cooled = ambient + (self.temp - ambient) * (-seconds / params.cool_time).exp();
+            self.temp = cooled.max(target.max(ambient));
+        }
+    }
+
+    /// Seconds until the temperature is within tolerance of the target.
+    pub fn time_to_target(&self, params: &HeaterParams, ambient: f32, cooling: bool) -> f32 {
+        let target = match self.target {
+            Some(target) => target,
+            None => return 0.0,
+        };
+        if self.temp < target - params.tolerance {
+            (target - params.tolerance - self.temp) / params.heat_rate
+        } else if cooling && self.temp > target + params.tolerance {
+            let floor = target + params.tolerance - ambient;
+            if floor <= 0.0 {
+                // never gets there; firmware would time out
+                return 0.0;
+            }
+            params.cool_time * ((self.temp - ambient) / floor).ln()
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_heater() {
+        let params = HeaterParams::hotend();
+        let mut heater = Heater::new(25.0);
+        assert!(heater.is_hot());
+        heater.target = Some(200.0);
+        assert!(!heater.is_hot());
+        assert!((heater.time_to_target(&params, 25.0, false) - 174.0 / 2.5).abs() < 1e-4);
+        heater.advance(&params, 25.0, 10.0);
+        assert_eq!(heater.temp, 50.0);
+        heater.advance(&params, 25.0, 100.0);
+        assert_eq!(heater.temp, 200.0);
+
+        // off: cools towards ambient, M109 R waits for it
+        heater.target = Some(100.0);
+        let wait = heater.time_to_target(&params, 25.0, true);
+        assert!((wait - 120.0 * (175.0f32 / 76.0).ln()).abs() < 1e-3);
+        assert_eq!(heater.time_to_target(&params, 25.0, false), 0.0);
+        heater.advance(&params, 25.0, 60.0);
+        assert!((heater.temp - (25.0 + 175.0 * (-0.5f32).exp())).abs() < 1e-3);
+        heater.advance(&params, 25.0, 1000.0);
+        assert_eq!(heater.temp, 100.0);
+    }
+
+    #[test]
+    fn test_waits() {
+        let mut state = ExtrudeState::<MonotonicVoxel>::default();
+        let ambient = state.params.ambient;
+        simulate_str(
+            &mut state,
+            "G4 P500\nG4 S2\nM104 S200\nM109 S200\nM190 S60\n",
+            ParseMode::Strict,
+        )
+        .unwrap();
+        let hotend = (199.0 - ambient) / HeaterParams::hotend().heat_rate;
+        let bed = (59.0 - ambient) / HeaterParams::bed().heat_rate;
+        assert!((state.wall_seconds() - (2.5 + hotend + bed)).abs() < 1e-3);
+        assert!(state.heater(HeaterId::Hotend(None)).temp >= 199.0);
+
+        // pressure left in a hot nozzle oozes while dwelling
+        let line = "M83\nG1 X0 Y0 Z0.2 F1200\nG1 X20 E1\n";
+        let mut moving = ExtrudeState::<MonotonicVoxel>::default();
+        simulate_str(&mut moving, line, ParseMode::Strict).unwrap();
+        let mut dwelling = ExtrudeState::<MonotonicVoxel>::default();
+        simulate_str(
+            &mut dwelling,
+            &format!("{}G4 S5\n", line),
+            ParseMode::Strict,
+        )
+        .unwrap();
+        assert!(dwelling.voxel().bounding_box().count > moving.voxel().bounding_box().count);
+        assert!((dwelling.wall_seconds() - moving.wall_seconds() - 5.0).abs() < 1e-4);
+    }
+}
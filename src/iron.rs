@@ -0,0 +1,172 @@
+use super::*;
+
+/// Ironing passes, as annotated by `;TYPE:` comments (PrusaSlicer,
+/// OrcaSlicer, Cura with ironing in its own feature).
+pub fn is_ironing_feature(feature: &str) -> bool {
+    feature.to_ascii_lowercase().contains("ironing")
+}
+
+/// Reflow of a top surface under the hot, flat face of the nozzle: material
+/// sticking out above the tip is pressed down, and flows together with the
+/// little that is extruded into the depressions around it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IroningParams {
+    /// mm, how far above the nozzle tip bumps are pressed flat
+    pub reach: f32,
+    /// mm, how far below the nozzle tip depressions are filled
+    pub depth: f32,
+    /// mm, width of the flat nozzle face
+    pub width: f32,
+}
+
+impl Default for IroningParams {
+    fn default() -> Self {
+        Self {
+            reach: 0.2,
+            depth: 0.2,
+            width: NOZZLE_SIZE,
+        }
+    }
+}
+
+/// Outcome of one ironing move, in voxels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reflow {
+    /// pressed down from above the tip
+    pub removed: usize,
+    /// depressions filled
+    pub filled: usize,
+    /// neither the surface nor its depressions had room for it
+    pub left: usize,
+}
+
+/// Irons the columns `footprint` (voxel X/Y) with the nozzle tip at voxel
+/// row `tip`: up to `reach` rows above it are cleared, and the material
+/// from there plus `extruded` voxels fills empty, supported voxels of the
+/// `depth` rows up to the tip, lowest first; rows below `floor` are the
+/// bed and support what is on them. `on_change` gets every voxel
+/// added (true) or removed (false).
+pub fn reflow<V: Voxel, F: FnMut(VoxelIdx, bool)>(
+    v: &mut V,
+    footprint: &[[i32; 2]],
+    tip: i32,
+    [reach, depth]: [i32; 2],
+    floor: i32,
+    extruded: usize,
+    mut on_change: F,
+) -> Reflow {
+    let mut out = Reflow::default();
+    for [x, y] in footprint {
+        for z in (tip + 1)..=(tip + reach) {
+            let pos = VoxelIdx::new([*x, *y, z]);
+            if v.remove(pos) {
+                on_change(pos, false);
+                out.removed += 1;
+            }
+        }
+    }
+
+    let mut budget = out.removed + extruded;
+    for z in (tip - depth + 1)..=tip {
+        for [x, y] in footprint {
+            if budget == 0 {
+                break;
+            }
+            let pos = VoxelIdx::new([*x, *y, z]);
+            let supported = z <= floor || v.occupied(VoxelIdx::new([*x, *y, z - 1]));
+            if supported && !v.occupied(pos) && v.add(pos) {
+                on_change(pos, true);
+                out.filled += 1;
+                budget -= 1;
+            }
+        }
+    }
+    out.left = budget;
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_reflow<V: Voxel>() {
+        // a 10x1 surface at row 4 with a bump at x=2 and a pit at x=7
+        let mut v = V::default();
+        for x in 0..10 {
+            for z in 0..=4 {
+                if !(x == 7 && z == 4) {
+                    v.add(VoxelIdx::new([x, 0, z]));
+                }
+            }
+        }
+        v.add(VoxelIdx::new([2, 0, 5]));
+        v.add(VoxelIdx::new([2, 0, 6]));
+        let before = v.bounding_box().count;
+
+        let footprint = (0..10).map(|x| [x, 0]).collect::<Vec<_>>();
+        let out = reflow(&mut v, &footprint, 4, [2, 2], 0, 0, |_, _| {});
+        assert_eq!(out.removed, 2);
+        assert_eq!(out.filled, 1);
+        assert_eq!(out.left, 1);
+        assert!(!v.occupied(VoxelIdx::new([2, 0, 5])));
+        assert!(v.occupied(VoxelIdx::new([7, 0, 4])));
+        assert_eq!(v.bounding_box().count, before - 1);
+        assert!(!v.remove(VoxelIdx::new([2, 0, 5])));
+    }
+
+    #[test]
+    fn test_reflow() {
+        check_reflow::<MonotonicVoxel>();
+        check_reflow::<RangeSetVoxel>();
+        check_reflow::<ChunkedVoxel>();
+        check_reflow::<SVOVoxel>();
+        check_reflow::<LodVoxel>();
+        check_reflow::<IsoVoxel>();
+        check_reflow::<FSNVoxel>();
+    }
+
+    // voxels above the nozzle tip of the ironing pass, away from the ends
+    fn ironed<V: Voxel>(ironing: bool) -> usize {
+        // two layers, and a short line sticking out of the top one
+        let mut src = "M83\nG1 F1200\n".to_string();
+        for z in [0.2, 0.4] {
+            for i in 0..6 {
+                let y = 10.0 + i as f32 * 0.4;
+                src += &format!("G1 X10 Y{:.1} Z{}\nG1 X20 E0.33\n", y, z);
+            }
+        }
+        src += "G1 X14 Y11.2 Z0.6\nG1 X16 E0.07\n";
+        if ironing {
+            src += ";TYPE:Ironing\n";
+            for i in 0..11 {
+                let y = 10.0 + i as f32 * 0.2;
+                src += &format!("G1 X10 Y{:.1} Z0.4\nG1 X20 E0.005\n", y);
+            }
+        }
+
+        let mut state = ExtrudeState::<V>::default();
+        simulate_str(&mut state, &src, ParseMode::Strict).unwrap();
+        let tip = state.params.intpos(0.4 + state.home.z);
+        let at = |mm: f32| state.params.intpos(mm);
+        let mut count = 0;
+        for x in at(12.0)..=at(18.0) {
+            for y in at(10.0)..=at(12.0) {
+                for z in (tip + 1)..(tip + 10) {
+                    if state.voxel().occupied(VoxelIdx::new([x, y, z])) {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_ironing() {
+        assert!(ironed::<MonotonicVoxel>(false) > 0);
+        // the bump is pressed flat, whatever the storage
+        assert_eq!(ironed::<MonotonicVoxel>(true), 0);
+        assert!(ironed::<ChunkedVoxel>(false) > 0);
+        assert_eq!(ironed::<ChunkedVoxel>(true), 0);
+    }
+}
@@ -71,6 +71,10 @@ impl Voxel for IsoVoxel {
         self.base.add(coord)
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        self.base.remove(coord)
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let mut models = vec![];
         const CELL_SIZE_F32: f32 = CELL_SIZE as f32;
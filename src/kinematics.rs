@@ -0,0 +1,336 @@
+use super::*;
+
+/// How the motors move the nozzle. Moves are straight lines in G-code
+/// coordinates; their duration depends on how fast each actuator has to go
+/// to follow them.
+pub trait Kinematics: std::fmt::Debug + Send + Sync {
+    /// Actuator positions (mm, or degrees for rotations) for the nozzle at
+    /// `pos`.
+    fn actuators(&self, pos: Vector3<f32>) -> [f32; 3];
+
+    /// Speed limit of each actuator per second; infinite when unlimited.
+    fn max_velocity(&self) -> [f32; 3];
+
+    /// Acceleration limit of each actuator per second squared.
+    fn max_accel(&self) -> [f32; 3] {
+        [f32::INFINITY; 3]
+    }
+
+    /// Segments per second the firmware splits moves into when straight
+    /// lines are curves for the actuators; 0 when they are not.
+    fn segments_per_second(&self) -> f32 {
+        0.0
+    }
+
+    /// Actuator travel between two positions.
+    fn actuator_delta(&self, from: [f32; 3], to: [f32; 3]) -> [f32; 3] {
+        [to[0] - from[0], to[1] - from[1], to[2] - from[2]]
+    }
+
+    /// X/Y displacement of the nozzle per mm one of the first two motors
+    /// slips, for layer shifts.
+    fn shift_axes(&self) -> [[f32; 2]; 2] {
+        [[1.0, 0.0], [0.0, 1.0]]
+    }
+
+    /// Duration of a straight move with `velocity` (mm/s), `accel` (mm/s^2)
+    /// and `junction` (mm/s) as in `move_time`, slowed down wherever an
+    /// actuator would exceed its limits.
+    fn move_time(
+        &self,
+        from: Vector3<f32>,
+        to: Vector3<f32>,
+        velocity: f32,
+        accel: f32,
+        junction: f32,
+    ) -> f32 {
+        let len = (to - from).magnitude();
+        if len <= 0.0 || velocity <= 0.0 {
+            return 0.0;
+        }
+        let sps = self.segments_per_second();
+        let segments = if sps > 0.0 {
+            (move_time(len, velocity, accel, junction) * sps)
+                .ceil()
+                .max(1.0) as usize
+        } else {
+            1
+        };
+
+        let max_velocity = self.max_velocity();
+        let max_accel = self.max_accel();
+        let step = len / segments as f32;
+        let mut cruise = 0.0;
+        let mut limited = false;
+        let mut accel = accel;
+        let mut prev = self.actuators(from);
+        for i in 1..=segments {
+            let next = self.actuators(from + (to - from) * (i as f32 / segments as f32));
+            let delta = self.actuator_delta(prev, next);
+            let mut t = step / velocity;
+            for j in 0..3 {
+                let d = delta[j].abs();
+                if d / max_velocity[j] > t {
+                    t = d / max_velocity[j];
+                    limited = true;
+                }
+                if d > 0.0 {
+                    accel = accel.min(max_accel[j] * step / d);
+                }
+            }
+            cruise += t;
+            prev = next;
+        }
+
+        let velocity = if limited { len / cruise } else { velocity };
+        move_time(len, velocity, accel, junction)
+    }
+}
+
+/// Independent X, Y and Z axes, with per-axis limits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cartesian {
+    pub max_velocity: [f32; 3],
+    pub max_accel: [f32; 3],
+}
+
+impl Default for Cartesian {
+    fn default() -> Self {
+        Self {
+            max_velocity: [f32::INFINITY; 3],
+            max_accel: [f32::INFINITY; 3],
+        }
+    }
+}
+
+impl Kinematics for Cartesian {
+    fn actuators(&self, pos: Vector3<f32>) -> [f32; 3] {
+        pos.into()
+    }
+
+    fn max_velocity(&self) -> [f32; 3] {
+        self.max_velocity
+    }
+
+    fn max_accel(&self) -> [f32; 3] {
+        self.max_accel
+    }
+}
+
+/// Two motors drive X and Y together through crossed belts: A = X + Y,
+/// B = X - Y. Limits are per motor (A, B, Z), in mm of belt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoreXY {
+    pub max_velocity: [f32; 3],
+    pub max_accel: [f32; 3],
+}
+
+impl Default for CoreXY {
+    fn default() -> Self {
+        Self {
+            max_velocity: [f32::INFINITY; 3],
+            max_accel: [f32::INFINITY; 3],
+        }
+    }
+}
+
+impl Kinematics for CoreXY {
+    fn actuators(&self, pos: Vector3<f32>) -> [f32; 3] {
+        [pos.x + pos.y, pos.x - pos.y, pos.z]
+    }
+
+    fn max_velocity(&self) -> [f32; 3] {
+        self.max_velocity
+    }
+
+    fn max_accel(&self) -> [f32; 3] {
+        self.max_accel
+    }
+
+    fn shift_axes(&self) -> [[f32; 2]; 2] {
+        [[0.5, 0.5], [0.5, -0.5]]
+    }
+}
+
+/// Linear delta: three carriages on towers at 210°, 330° and 90° around
+/// `center`, connected to the effector by arms of `arm_length`. The
+/// firmware splits moves into `segments_per_second` straight carriage
+/// moves, so the effective speed drops towards the edge of the bed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delta {
+    /// mm, DELTA_DIAGONAL_ROD
+    pub arm_length: f32,
+    /// mm, DELTA_RADIUS: horizontal distance from the center to the
+    /// carriage joints, minus the effector offset
+    pub radius: f32,
+    /// DELTA_SEGMENTS_PER_SECOND
+    pub segments_per_second: f32,
+    /// mm/s and mm/s^2 of a carriage
+    pub max_velocity: f32,
+    pub max_accel: f32,
+    /// mm, G-code coordinates of the bed center
+    pub center: [f32; 2],
+}
+
+impl Delta {
+    pub fn new(arm_length: f32, radius: f32) -> Self {
+        Self {
+            arm_length,
+            radius,
+            segments_per_second: 200.0,
+            max_velocity: 300.0,
+            max_accel: 3000.0,
+            center: [0.0; 2],
+        }
+    }
+}
+
+impl Kinematics for Delta {
+    fn actuators(&self, pos: Vector3<f32>) -> [f32; 3] {
+        [210f32, 330.0, 90.0].map(|angle| {
+            let (sin, cos) = angle.to_radians().sin_cos();
+            let dx = pos.x - self.center[0] - self.radius * cos;
+            let dy = pos.y - self.center[1] - self.radius * sin;
+            // out of reach: the arm lies flat
+            pos.z
+                + (self.arm_length.powi(2) - dx * dx - dy * dy)
+                    .max(0.0)
+                    .sqrt()
+        })
+    }
+
+    fn max_velocity(&self) -> [f32; 3] {
+        [self.max_velocity; 3]
+    }
+
+    fn max_accel(&self) -> [f32; 3] {
+        [self.max_accel; 3]
+    }
+
+    fn segments_per_second(&self) -> f32 {
+        self.segments_per_second
+    }
+}
+
+/// Polar: the bed rotates around `center` and the nozzle moves along a
+/// radius. Moves close to the center need fast rotations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Polar {
+    /// degrees/s of the bed
+    pub max_angular_velocity: f32,
+    /// mm/s of the radial and Z axes
+    pub max_velocity: f32,
+    pub segments_per_second: f32,
+    /// mm, G-code coordinates of the rotation axis
+    pub center: [f32; 2],
+}
+
+impl Default for Polar {
+    fn default() -> Self {
+        Self {
+            max_angular_velocity: 360.0,
+            max_velocity: 200.0,
+            segments_per_second: 200.0,
+            center: [0.0; 2],
+        }
+    }
+}
+
+impl Kinematics for Polar {
+    fn actuators(&self, pos: Vector3<f32>) -> [f32; 3] {
+        let x = pos.x - self.center[0];
+        let y = pos.y - self.center[1];
+        [y.atan2(x).to_degrees(), x.hypot(y), pos.z]
+    }
+
+    fn max_velocity(&self) -> [f32; 3] {
+        [
+            self.max_angular_velocity,
+            self.max_velocity,
+            self.max_velocity,
+        ]
+    }
+
+    fn segments_per_second(&self) -> f32 {
+        self.segments_per_second
+    }
+
+    fn actuator_delta(&self, from: [f32; 3], to: [f32; 3]) -> [f32; 3] {
+        // the short way around
+        let mut angle = to[0] - from[0];
+        if angle > 180.0 {
+            angle -= 360.0;
+        } else if angle < -180.0 {
+            angle += 360.0;
+        }
+        // the bed does not need to turn to pass through the center
+        if from[1] < 1e-3 || to[1] < 1e-3 {
+            angle = 0.0;
+        }
+        [angle, to[1] - from[1], to[2] - from[2]]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kinematics() {
+        let from = Vector3::new(0.0, 0.0, 0.2);
+        let to = Vector3::new(100.0, 0.0, 0.2);
+        let plain = move_time(100.0, 100.0, 1000.0, 5.0);
+        let t = Cartesian::default().move_time(from, to, 100.0, 1000.0, 5.0);
+        assert_eq!(t, plain);
+
+        // a diagonal move runs one CoreXY motor at twice the nozzle speed
+        let corexy = CoreXY {
+            max_velocity: [100.0, 100.0, 10.0],
+            ..Default::default()
+        };
+        assert_eq!(corexy.move_time(from, to, 100.0, 1000.0, 5.0), plain);
+        let diagonal = Vector3::new(50.0, 50.0, 0.2);
+        let t = corexy.move_time(from, diagonal, 100.0, 1000.0, 5.0);
+        let len = 50.0 * 2f32.sqrt();
+        assert!(t > move_time(len, 100.0, 1000.0, 5.0) * 1.3);
+
+        // carriages move faster near the edge of a delta
+        let delta = Delta {
+            max_velocity: 55.0,
+            ..Delta::new(250.0, 120.0)
+        };
+        let center = delta.move_time(
+            Vector3::new(-10.0, 0.0, 0.2),
+            Vector3::new(10.0, 0.0, 0.2),
+            100.0,
+            1000.0,
+            5.0,
+        );
+        let edge = delta.move_time(
+            Vector3::new(-10.0, 100.0, 0.2),
+            Vector3::new(10.0, 100.0, 0.2),
+            100.0,
+            1000.0,
+            5.0,
+        );
+        assert!(edge > center);
+
+        // a polar bed has to spin fast close to the center
+        let polar = Polar::default();
+        let near = polar.move_time(
+            Vector3::new(-5.0, 1.0, 0.2),
+            Vector3::new(5.0, 1.0, 0.2),
+            100.0,
+            1000.0,
+            5.0,
+        );
+        let far = polar.move_time(
+            Vector3::new(-5.0, 80.0, 0.2),
+            Vector3::new(5.0, 80.0, 0.2),
+            100.0,
+            1000.0,
+            5.0,
+        );
+        assert!(near > far * 2.0);
+    }
+}
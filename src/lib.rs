@@ -3,6 +3,7 @@ use log::*;
 use nalgebra::Vector3;
 use simple_stopwatch::Stopwatch;
 use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::rc::Rc;
 use std::sync::*;
 
@@ -30,9 +31,49 @@ pub use vdbvoxel::VDBVoxel;
 
 mod extrude;
 pub use extrude::*;
+mod artifact;
+mod bed;
+mod collision;
+mod compare;
+mod estimate;
+mod fan;
+mod flow;
 mod gcode;
+mod heater;
+mod iron;
+mod kinematics;
+mod mass;
+mod measure;
+mod motion;
+mod sag;
+mod seam;
+mod tool;
+mod transform;
+mod trimesh;
+mod voxelmeta;
+mod warp;
+pub use artifact::*;
+pub use bed::*;
 pub use cell::*;
+pub use collision::*;
+pub use compare::*;
+pub use estimate::*;
+pub use fan::*;
+pub use flow::*;
 pub use gcode::*;
+pub use heater::*;
+pub use iron::*;
+pub use kinematics::*;
+pub use mass::*;
+pub use measure::*;
+pub use motion::*;
+pub use sag::*;
+pub use seam::*;
+pub use tool::*;
+pub use transform::*;
+pub use trimesh::*;
+pub use voxelmeta::*;
+pub use warp::*;
 
 impl std::ops::Index<usize> for VoxelIdx {
     type Output = i32;
@@ -62,17 +103,23 @@ impl BoundingBox {
         }
         self.count += 1;
     }
+
+    // the bounds are left as they are
+    fn remove(&mut self) {
+        self.count = self.count.saturating_sub(1);
+    }
 }
 
 // internal use only
 pub const FPS: usize = 60;
 
 const FILAMENT_DIAMETER: f32 = 1.75f32;
-const FILAMENT_CROSS_SECION: f32 =
-    0.25f32 * std::f32::consts::PI * FILAMENT_DIAMETER * FILAMENT_DIAMETER;
 
 const NOZZLE_SIZE: f32 = 0.4f32;
 
+// cos(10°); layers tilted further than this are not filled from below
+const TILTED_LAYER_COS: f32 = 0.985;
+
 pub struct Parameters {
     pub unit: f32,
     pub layer_height: f32,
@@ -80,6 +127,35 @@ pub struct Parameters {
     // simulation parameters
     pub e_delta_min: f32,
     pub e_alpha: f32,
+    /// pressure advance (seconds) that fully compensates the `e_alpha` lag
+    pub pressure_advance_ref: f32,
+    /// scales every extrusion, on top of M221
+    pub extrusion_multiplier: f32,
+
+    /// °C
+    pub ambient: f32,
+    pub hotend: HeaterParams,
+    pub bed: HeaterParams,
+    /// seconds, time constant of nozzle pressure relaxing while stationary
+    pub ooze_time: f32,
+
+    /// mm/s^2, unless set by SET_VELOCITY_LIMIT
+    pub accel: f32,
+    /// mm/s, speed at the start and end of every move
+    pub square_corner_velocity: f32,
+    /// motion system, for move times and layer shifts
+    pub kinematics: Arc<dyn Kinematics>,
+
+    /// droop of unsupported extrusions
+    pub sag: SagParams,
+    /// part cooling fan
+    pub fan: FanParams,
+    /// bed surface and first-layer squish
+    pub bed_contact: BedParams,
+    /// blobs at the seams of outer walls
+    pub seam: SeamParams,
+    /// top surface reflow under ironing passes
+    pub ironing: IroningParams,
 }
 
 impl Default for Parameters {
@@ -91,19 +167,33 @@ impl Default for Parameters {
 
             e_delta_min: 0.01,
             e_alpha: 0.8,
+            pressure_advance_ref: 0.05,
+            extrusion_multiplier: 1.0,
+
+            ambient: 25.0,
+            hotend: HeaterParams::hotend(),
+            bed: HeaterParams::bed(),
+            ooze_time: 2.0,
+
+            accel: 1000.0,
+            square_corner_velocity: 5.0,
+            kinematics: Arc::new(Cartesian::default()),
+
+            sag: SagParams::default(),
+            fan: FanParams::default(),
+            bed_contact: BedParams::default(),
+            seam: SeamParams::default(),
+            ironing: IroningParams::default(),
         }
     }
 }
 
 impl Parameters {
     pub fn from_unit(unit: f32) -> Self {
-        let layer_height = unit * 2.0;
         Self {
             unit,
-            layer_height,
-
-            e_delta_min: 0.01,
-            e_alpha: 0.8,
+            layer_height: unit * 2.0,
+            ..Self::default()
         }
     }
 
@@ -143,6 +233,9 @@ pub trait Voxel: Default {
     fn add(&mut self, coord: VoxelIdx) -> bool;
     fn to_model(&mut self) -> Vec<Rc<Model>>;
 
+    /// Clears a voxel. Returns whether it was set.
+    fn remove(&mut self, coord: VoxelIdx) -> bool;
+
     fn write_binary<W: std::io::Write>(&mut self, _writer: W) -> Result<()> {
         Ok(())
     }
@@ -217,6 +310,39 @@ impl Model {
         self.add_face(coord, [0, -size, -size].into());
     }
 
+    /// A copy with every vertex (voxel units) moved by `f`. Quads become
+    /// triangles; normals are dropped.
+    pub fn deformed<F: Fn(Vector3<f32>) -> Vector3<f32>>(&self, f: F) -> Model {
+        let mut out = Model {
+            id: self.id,
+            ..Default::default()
+        };
+        let offset = Vector3::from(self.offset.f32());
+        out.raw_vertices = self
+            .vertices
+            .iter()
+            .map(|v| f(Vector3::from(v.f32()) + offset).into())
+            .collect();
+        out.raw_triangles = self
+            .faces
+            .iter()
+            .flat_map(|[i0, i1, i2, i3]| {
+                let [i0, i1, i2, i3] = [*i0, *i1, *i2, *i3].map(|i| i as u32);
+                [[i0, i2, i1], [i0, i3, i2]]
+            })
+            .collect();
+
+        let base = out.raw_vertices.len() as u32;
+        out.raw_vertices.extend(
+            self.raw_vertices
+                .iter()
+                .map(|v| <[f32; 3]>::from(f(Vector3::from(*v)))),
+        );
+        out.raw_triangles
+            .extend(self.raw_triangles.iter().map(|t| t.map(|i| i + base)));
+        out
+    }
+
     #[allow(unused)]
     fn serialize_raw(&self, path: &str) -> Result<()> {
         use std::io::Write;
@@ -238,18 +364,22 @@ impl Model {
     }
 }
 
-pub fn model_serialize_gltf(
+/// A named set of models, exported as one glTF node with its own material.
+pub struct ModelGroup {
+    pub name: String,
+    pub color: [f32; 4],
+    pub models: Vec<Rc<Model>>,
+}
+
+fn gltf_add_models(
+    builder: &mut mesh_tools::GltfBuilder,
     models: &[Rc<Model>],
-    path: &str,
-    offset: [f32; 3],
-    scale: f32,
-) -> Result<()> {
+    material: usize,
+    prefix: &str,
+) -> Vec<usize> {
     use mesh_tools::compat;
-    use mesh_tools::{GltfBuilder, Triangle};
-
-    let mut builder = GltfBuilder::new();
+    use mesh_tools::Triangle;
 
-    let material = builder.create_basic_material(Some("red".to_owned()), [1.0, 0.2, 0.2, 1.0]);
     let mut nodes = vec![];
 
     for (i, model) in models.iter().enumerate() {
@@ -271,7 +401,7 @@ pub fn model_serialize_gltf(
                 })
                 .collect::<Vec<_>>();
 
-            let name = format!("quads_{}", i);
+            let name = format!("{}quads_{}", prefix, i);
             let mesh = builder.create_custom_mesh(
                 Some(name.clone()),
                 positions.as_slice(),
@@ -318,7 +448,7 @@ pub fn model_serialize_gltf(
                 )
             };
 
-            let name = format!("raw_{}", i);
+            let name = format!("{}raw_{}", prefix, i);
             let mesh = builder.create_custom_mesh(
                 Some(name.clone()),
                 positions.as_slice(),
@@ -332,6 +462,16 @@ pub fn model_serialize_gltf(
         }
     }
 
+    nodes
+}
+
+fn gltf_export(
+    mut builder: mesh_tools::GltfBuilder,
+    nodes: Vec<usize>,
+    path: &str,
+    offset: [f32; 3],
+    scale: f32,
+) -> Result<()> {
     let root = builder.add_node_with_children(
         Some("root".to_owned()),
         None,
@@ -347,6 +487,52 @@ pub fn model_serialize_gltf(
     Ok(())
 }
 
+pub fn model_serialize_gltf(
+    models: &[Rc<Model>],
+    path: &str,
+    offset: [f32; 3],
+    scale: f32,
+) -> Result<()> {
+    use mesh_tools::GltfBuilder;
+
+    let mut builder = GltfBuilder::new();
+
+    let material = builder.create_basic_material(Some("red".to_owned()), [1.0, 0.2, 0.2, 1.0]);
+    let nodes = gltf_add_models(&mut builder, models, material, "");
+
+    gltf_export(builder, nodes, path, offset, scale)
+}
+
+/// Exports each group as a separate child node of the root, e.g. one per object.
+pub fn model_serialize_gltf_groups(
+    groups: &[ModelGroup],
+    path: &str,
+    offset: [f32; 3],
+    scale: f32,
+) -> Result<()> {
+    use mesh_tools::GltfBuilder;
+
+    let mut builder = GltfBuilder::new();
+
+    let mut nodes = vec![];
+    for group in groups {
+        let material = builder.create_basic_material(Some(group.name.clone()), group.color);
+        let prefix = format!("{}_", group.name);
+        let children = gltf_add_models(&mut builder, &group.models, material, &prefix);
+        let node = builder.add_node_with_children(
+            Some(group.name.clone()),
+            None,
+            None,
+            None,
+            None,
+            children,
+        );
+        nodes.push(node);
+    }
+
+    gltf_export(builder, nodes, path, offset, scale)
+}
+
 pub fn model_serialize(
     models: &[Rc<Model>],
     path: &str,
@@ -402,6 +588,54 @@ pub struct ExtrudeState<V: Voxel + Default> {
     last_sw: Stopwatch,
 
     dir: Vector3<f32>,
+
+    // SET_PRESSURE_ADVANCE, in seconds
+    pressure_advance: f32,
+    velocity_limit: VelocityLimit,
+
+    current_object: Option<String>,
+    excluded_objects: Vec<String>,
+    only_objects: Vec<String>,
+
+    meta: Option<VoxelMetaMap>,
+    split: SplitBy,
+
+    tool: usize,
+    tools: Vec<ToolParams>,
+    tool_overrides: Vec<ToolOverride>,
+    // ;TYPE: of the current moves
+    feature: Option<String>,
+    skip_purge: bool,
+
+    // M220/M221, as ratios
+    speed_factor: f32,
+    flow_factors: Vec<f32>,
+
+    hotends: Vec<Heater>,
+    bed: Heater,
+    fan: Fan,
+
+    // filament pushed into the nozzles, mm and mm^3
+    filament_length: f32,
+    filament_volume: f32,
+
+    mass: Option<MassReport>,
+    flow_limit: Option<FlowReport>,
+    collision: Option<CollisionChecker>,
+    artifacts: Option<ArtifactInjector>,
+    frame: Option<MachineFrame>,
+    warp: Option<WarpParams>,
+    // M290/SET_GCODE_OFFSET, mm on top of `BedParams::z_offset`
+    z_adjust: f32,
+    // mm extruded in mid-air since the last support
+    airborne: f32,
+    // voxels of the layer starting at `sag_bottom` which sagged below it;
+    // they do not support the rest of the layer
+    sag_bottom: i32,
+    sagged: ahash::AHashSet<VoxelIdx>,
+    seams: SeamTracker,
+    line: Option<usize>,
+    layer: Option<usize>,
 }
 
 impl<V: Voxel + Default> std::default::Default for ExtrudeState<V> {
@@ -426,6 +660,46 @@ impl<V: Voxel + Default> std::default::Default for ExtrudeState<V> {
             last_sw,
 
             dir: Vector3::new(0.0, 0.0, 0.0),
+
+            pressure_advance: 0.0,
+            velocity_limit: VelocityLimit::default(),
+
+            current_object: None,
+            excluded_objects: Vec::new(),
+            only_objects: Vec::new(),
+
+            meta: None,
+            split: SplitBy::None,
+
+            tool: 0,
+            tools: Vec::new(),
+            tool_overrides: Vec::new(),
+            feature: None,
+            skip_purge: false,
+
+            speed_factor: 1.0,
+            flow_factors: Vec::new(),
+
+            hotends: Vec::new(),
+            bed: Heater::new(Parameters::default().ambient),
+            fan: Fan::default(),
+
+            filament_length: 0.0,
+            filament_volume: 0.0,
+
+            mass: None,
+            flow_limit: None,
+            collision: None,
+            artifacts: None,
+            frame: None,
+            warp: None,
+            z_adjust: 0.0,
+            airborne: 0.0,
+            sag_bottom: 0,
+            sagged: Default::default(),
+            seams: SeamTracker::default(),
+            line: None,
+            layer: None,
         }
     }
 }
@@ -435,7 +709,25 @@ impl<V: Voxel + Default> ExtrudeState<V> {
         let last_dt = self.last_sw.ms();
 
         let sw = Stopwatch::start_new();
-        let model = self.mv.to_model();
+        let mut groups = self.model_groups();
+        let mut model = if groups.is_none() {
+            self.mv.to_model()
+        } else {
+            vec![]
+        };
+        if let (Some(params), Some(meta)) = (self.warp, self.meta.as_ref()) {
+            let field = WarpField::new(meta, params, self.params.unit, self.home);
+            let deform = |models: &[Rc<Model>]| {
+                models
+                    .iter()
+                    .map(|m| Rc::new(field.deform(m, meta)))
+                    .collect::<Vec<_>>()
+            };
+            model = deform(&model);
+            for group in groups.iter_mut().flatten() {
+                group.models = deform(&group.models);
+            }
+        }
         info!(
             "to_model: took={:.2}ms/{:.2}ms, wall: {:.0}s",
             last_dt,
@@ -447,7 +739,13 @@ impl<V: Voxel + Default> ExtrudeState<V> {
 
         let out_filename = if true {
             let filename = format!("{}/gcode_{}.glb", out_filename, postfix);
-            model_serialize_gltf(&model, &filename, [-90f32, -90f32, 0f32], self.params.unit)?;
+            let offset = [-90f32, -90f32, 0f32];
+            match groups {
+                Some(groups) => {
+                    model_serialize_gltf_groups(&groups, &filename, offset, self.params.unit)?
+                }
+                None => model_serialize_gltf(&model, &filename, offset, self.params.unit)?,
+            }
 
             {
                 let filename1 = format!("{}/gcode_{}.bin", out_filename, postfix);
@@ -474,6 +772,46 @@ impl<V: Voxel + Default> ExtrudeState<V> {
         Ok(())
     }
 
+    /// Per-voxel models for split exports, `None` when not splitting.
+    fn model_groups(&self) -> Option<Vec<ModelGroup>> {
+        let meta = self.meta.as_ref()?;
+        let groups = match self.split {
+            SplitBy::None => return None,
+            SplitBy::Object => meta
+                .split::<V, _, _>(|m| m.object)
+                .into_iter()
+                .enumerate()
+                .map(|(i, (id, mut v))| ModelGroup {
+                    name: meta.object_name(id).unwrap_or("none").to_string(),
+                    color: palette(i),
+                    models: v.to_model(),
+                })
+                .collect(),
+            SplitBy::Tool => meta
+                .split::<V, _, _>(|m| (m.purge, m.tool))
+                .into_iter()
+                .map(|((purge, tool), mut v)| {
+                    let mut color = self.tool_params(tool as usize).color;
+                    let name = if purge {
+                        // darker shade of the material
+                        for c in &mut color[..3] {
+                            *c *= 0.5;
+                        }
+                        format!("T{}_purge", tool)
+                    } else {
+                        format!("T{}", tool)
+                    };
+                    ModelGroup {
+                        name,
+                        color,
+                        models: v.to_model(),
+                    }
+                })
+                .collect(),
+        };
+        Some(groups)
+    }
+
     fn g_92(&mut self, cur: GCode1Coord) {
         if let Some(x) = cur.x {
             self.pos[0] = x;
@@ -494,20 +832,538 @@ impl<V: Voxel + Default> ExtrudeState<V> {
         }
     }
 
+    /// Everything but moves.
+    pub fn handle_command(&mut self, code: &GCode1) {
+        match code {
+            GCode1::Layer(idx) => {
+                self.layer = Some(*idx);
+                if let Some(artifacts) = self.artifacts.as_mut() {
+                    artifacts.layer(*idx);
+                }
+            }
+            GCode1::Miscellaneous(82) => self.e_relative = false,
+            GCode1::Miscellaneous(83) => self.e_relative = true,
+            GCode1::PressureAdvance(v) => self.pressure_advance = *v,
+            GCode1::VelocityLimit(limit) => {
+                let cur = &mut self.velocity_limit;
+                cur.velocity = limit.velocity.or(cur.velocity);
+                cur.accel = limit.accel.or(cur.accel);
+                cur.square_corner_velocity =
+                    limit.square_corner_velocity.or(cur.square_corner_velocity);
+                cur.minimum_cruise_ratio = limit.minimum_cruise_ratio.or(cur.minimum_cruise_ratio);
+            }
+            GCode1::ObjectStart(name) => self.current_object = Some(name.clone()),
+            GCode1::ObjectEnd => self.current_object = None,
+            GCode1::ExcludeObject(name) => {
+                if let Some(name) = name.clone().or(self.current_object.clone()) {
+                    info!("excluding object: {}", name);
+                    self.exclude_object(&name);
+                }
+            }
+            GCode1::ToolChange(tool) => {
+                // pressure built up in the previous nozzle is lost; the new
+                // one starts settled
+                self.tool = *tool;
+                self.e_delay = self.e;
+                self.e_top = self.e_top.max(self.e);
+            }
+            GCode1::ToolConfig(config) => self.tool_config(config),
+            GCode1::SpeedFactor(percent) => self.speed_factor = percent / 100.0,
+            GCode1::FlowFactor { percent, tool } => {
+                let tool = tool.unwrap_or(self.tool);
+                if self.flow_factors.len() <= tool {
+                    self.flow_factors.resize(tool + 1, 1.0);
+                }
+                self.flow_factors[tool] = percent / 100.0;
+            }
+            GCode1::Dwell(seconds) => {
+                self.dwell(*seconds);
+            }
+            GCode1::Temperature(t) => {
+                let wait = self.set_temperature(t);
+                if t.wait {
+                    self.dwell(wait);
+                }
+            }
+            // other fans cool the electronics or the chamber
+            GCode1::Fan { speed, fan } if fan.unwrap_or(0) == 0 => self.fan.set(*speed),
+            GCode1::ZOffset { z, relative } => {
+                if *relative {
+                    self.z_adjust += z;
+                } else {
+                    self.z_adjust = *z;
+                }
+            }
+            GCode1::Leveling { enable, fade } => {
+                let bed = &mut self.params.bed_contact;
+                bed.leveling = enable.unwrap_or(bed.leveling);
+                bed.fade_height = fade.unwrap_or(bed.fade_height);
+            }
+            // M400: moves are simulated without a planner queue, so there
+            // is nothing to wait for
+            GCode1::Miscellaneous(400) => {}
+            GCode1::TypedComment(prefix, value) if prefix == "TYPE" => {
+                self.end_path();
+                self.feature = Some(value.trim().to_string());
+            }
+            _ => {}
+        }
+    }
+
+    /// Applies per-tool settings ahead of the G-code which carries them, see
+    /// `scan_tool_config`.
+    pub fn preload_tool_config<'a>(&mut self, configs: impl IntoIterator<Item = &'a ToolConfig>) {
+        for config in configs {
+            self.tool_config(config);
+        }
+    }
+
+    fn tool_config(&mut self, config: &ToolConfig) {
+        match config {
+            ToolConfig::FilamentDiameter(values) => {
+                for (i, d) in values.iter().enumerate() {
+                    self.tool_params_mut(i).filament_diameter = *d;
+                }
+            }
+            ToolConfig::Color(values) => {
+                for (i, color) in values.iter().enumerate() {
+                    if let Some(color) = color {
+                        self.tool_params_mut(i).color = *color;
+                    }
+                }
+            }
+            ToolConfig::Offset(values) => {
+                for (i, offset) in values.iter().enumerate() {
+                    self.tool_params_mut(i).offset = *offset;
+                }
+            }
+        }
+    }
+
+    fn tool_params_mut(&mut self, tool: usize) -> &mut ToolParams {
+        while self.tools.len() <= tool {
+            self.tools.push(ToolParams::new(self.tools.len()));
+        }
+        &mut self.tools[tool]
+    }
+
+    /// Settings of `tool`, with user overrides applied.
+    pub fn tool_params(&self, tool: usize) -> ToolParams {
+        let mut params = self
+            .tools
+            .get(tool)
+            .copied()
+            .unwrap_or_else(|| ToolParams::new(tool));
+        if let Some(o) = self.tool_overrides.get(tool) {
+            o.apply(&mut params);
+        }
+        params
+    }
+
+    pub fn override_tool(&mut self, tool: usize, params: ToolOverride) {
+        if self.tool_overrides.len() <= tool {
+            self.tool_overrides
+                .resize(tool + 1, ToolOverride::default());
+        }
+        self.tool_overrides[tool] = params;
+    }
+
+    /// Do not deposit wipe/prime towers.
+    pub fn skip_purge(&mut self, skip: bool) {
+        self.skip_purge = skip;
+    }
+
+    fn heater_mut(&mut self, heater: HeaterId) -> &mut Heater {
+        match heater {
+            HeaterId::Bed => &mut self.bed,
+            HeaterId::Hotend(tool) => {
+                let tool = tool.unwrap_or(self.tool);
+                while self.hotends.len() <= tool {
+                    self.hotends.push(Heater::new(self.params.ambient));
+                }
+                &mut self.hotends[tool]
+            }
+        }
+    }
+
+    pub fn heater(&self, heater: HeaterId) -> Heater {
+        match heater {
+            HeaterId::Bed => self.bed,
+            HeaterId::Hotend(tool) => {
+                let tool = tool.unwrap_or(self.tool);
+                self.hotends
+                    .get(tool)
+                    .copied()
+                    .unwrap_or_else(|| Heater::new(self.params.ambient))
+            }
+        }
+    }
+
+    /// Sets a heater target; returns how long M109/M190 would wait for it.
+    pub fn set_temperature(&mut self, t: &SetTemperature) -> f32 {
+        let params = match t.heater {
+            HeaterId::Bed => self.params.bed,
+            HeaterId::Hotend(_) => self.params.hotend,
+        };
+        let ambient = self.params.ambient;
+        let heater = self.heater_mut(t.heater);
+        heater.target = Some(t.target);
+        heater.time_to_target(&params, ambient, t.wait_cooling)
+    }
+
+    fn advance_heaters(&mut self, seconds: f32) {
+        let ambient = self.params.ambient;
+        for heater in self.hotends.iter_mut() {
+            heater.advance(&self.params.hotend, ambient, seconds);
+        }
+        self.bed.advance(&self.params.bed, ambient, seconds);
+        self.fan.advance(&self.params.fan, seconds);
+    }
+
+    /// The part cooling fan.
+    pub fn fan(&self) -> Fan {
+        self.fan
+    }
+
+    /// Cooling (0 to 1) of freshly deposited material.
+    fn cooling(&self) -> f32 {
+        self.fan.cooling(self.params.sag.cooling)
+    }
+
+    /// Stands still for `seconds` (G4, heater waits); a hot nozzle keeps
+    /// oozing until its pressure has relaxed.
+    pub fn dwell(&mut self, seconds: f32) -> usize {
+        if seconds <= 0.0 {
+            return 0;
+        }
+        self.wall_seconds += seconds;
+        self.advance_heaters(seconds);
+
+        let gap = self.e - self.e_delay;
+        if gap <= 0.0 || self.is_excluded() || !self.heater(HeaterId::Hotend(None)).is_hot() {
+            return 0;
+        }
+        let released = gap * (1.0 - (-seconds / self.params.ooze_time).exp());
+        self.e_delay += released;
+        let e_delta = self.e_delay - self.e_top;
+        self.e_top = self.e_top.max(self.e_delay);
+        if e_delta <= 0.0 {
+            return 0;
+        }
+        // the oozed filament was accounted for when it was fed
+        let (deposited, dropped) = self.deposit(self.pos, self.dir, e_delta);
+        self.account_mass(0.0, deposited, dropped);
+        dropped
+    }
+
+    /// Collects commanded vs deposited volume from now on.
+    pub fn enable_mass_report(&mut self) {
+        if self.mass.is_none() {
+            self.mass = Some(MassReport::default());
+        }
+    }
+
+    pub fn mass_report(&self) -> Option<&MassReport> {
+        self.mass.as_ref()
+    }
+
+    /// Starves extrusions beyond the hotend's melting capacity from now on,
+    /// and reports where that happened.
+    pub fn enable_flow_limit(&mut self, limit: FlowLimit) {
+        self.flow_limit = Some(FlowReport::new(limit));
+    }
+
+    pub fn flow_report(&self) -> Option<&FlowReport> {
+        self.flow_limit.as_ref()
+    }
+
+    /// Fraction of `volume` (mm^3), commanded over `seconds`, which the
+    /// hotend manages to melt on the move to `dst`.
+    fn limit_flow(&mut self, dst: Vector3<f32>, volume: f32, seconds: f32) -> f32 {
+        let Some(report) = self.flow_limit.as_mut() else {
+            return 1.0;
+        };
+        if volume <= 0.0 || seconds <= 0.0 {
+            report.interrupt();
+            return 1.0;
+        }
+        let flow = volume / seconds;
+        let delivered = report.limit.delivered(flow);
+        report.add(FlowSample {
+            line: self.line,
+            layer: self.layer,
+            feature: self.feature.as_deref(),
+            from: self.pos,
+            to: dst,
+            flow,
+            starved: (flow - delivered) * seconds,
+        });
+        delivered / flow
+    }
+
+    /// Source line of the next command, for diagnostics.
+    pub fn set_line(&mut self, line: usize) {
+        self.line = Some(line);
+    }
+
+    /// Deforms exported meshes by thermal shrinkage and warping. Has to be
+    /// set before simulating, to record when each voxel was deposited.
+    pub fn enable_warp(&mut self, params: WarpParams) {
+        self.warp = Some(params);
+        if self.meta.is_none() {
+            self.meta = Some(VoxelMetaMap::default());
+        }
+    }
+
+    /// Checks every move against the printed material from now on.
+    pub fn enable_collision_check(&mut self, shape: ToolheadShape) {
+        self.collision = Some(CollisionChecker::new(shape, self.params.unit));
+    }
+
+    /// Moves the machine makes deviate from the commanded ones from now on.
+    pub fn enable_artifacts(&mut self, config: ArtifactConfig) {
+        let mut artifacts = ArtifactInjector::new(config);
+        artifacts.set_shift_axes(self.params.kinematics.shift_axes());
+        self.artifacts = Some(artifacts);
+    }
+
+    /// Machine axes which are not a plain Cartesian box, e.g. a belt
+    /// printer. Has to be set before simulating.
+    pub fn set_transform(&mut self, transform: MachineTransform) -> Result<()> {
+        self.frame = Some(MachineFrame::new(transform)?);
+        Ok(())
+    }
+
+    /// Layer shifts injected so far.
+    pub fn layer_shifts(&self) -> &[AppliedShift] {
+        match &self.artifacts {
+            Some(artifacts) => artifacts.applied(),
+            None => &[],
+        }
+    }
+
+    /// Seams of the closed outer wall loops printed so far.
+    pub fn seams(&self) -> &[Seam] {
+        self.seams.seams()
+    }
+
+    /// The current extrusion path ends; it may close a loop.
+    fn end_path(&mut self) {
+        let outer = self.feature.as_deref().is_none_or(is_outer_wall_feature);
+        self.seams.end(NOZZLE_SIZE, outer, self.layer, self.line);
+    }
+
+    /// A loop just ended and the filament retracts to `dst_e` on the move
+    /// to `dst`: part of the pressure left in the nozzle oozes out at the
+    /// seam, or along the first `SeamParams::wipe` mm of the move. The
+    /// position stays at the seam.
+    fn seam_ooze(&mut self, dst: Vector3<f32>, dst_e: f32) -> usize {
+        if self.is_excluded() {
+            return 0;
+        }
+        let params = self.params.seam;
+        let cross_section = self.tool_params(self.tool).cross_section() * self.flow();
+
+        let mut dropped = 0;
+        let gap = self.e - self.e_delay;
+        if gap > 0.0 {
+            self.e_delay += gap * params.ooze(self.e - dst_e);
+            let e_delta = self.e_delay - self.e_top;
+            self.e_top = self.e_top.max(self.e_delay);
+            if e_delta > 0.0 {
+                self.seams.add_ooze(e_delta * cross_section);
+                let seam = self.pos;
+                let len = (dst - seam).magnitude();
+                let end = if params.wipe > 0.0 && len > 0.0 {
+                    seam + (dst - seam) * (params.wipe / len).min(1.0)
+                } else {
+                    seam
+                };
+                // the oozed filament was accounted for when it was fed
+                let (deposited, blocks) = self.deposit(end, self.dir, e_delta);
+                self.pos = seam;
+                self.account_mass(0.0, deposited, blocks);
+                dropped = blocks;
+            }
+        }
+
+        if params.deficit > 0.0 {
+            // held back for good: the next extrusions come out short
+            self.e_top = self.e_top.max(self.e_delay) + params.deficit;
+            self.seams.add_deficit(params.deficit * cross_section);
+        }
+        dropped
+    }
+
+    pub fn collisions(&self) -> &[Collision] {
+        match &self.collision {
+            Some(checker) => checker.collisions(),
+            None => &[],
+        }
+    }
+
+    fn account_mass(&mut self, commanded: f32, deposited: usize, dropped: usize) {
+        let mass = match self.mass.as_mut() {
+            Some(mass) => mass,
+            None => return,
+        };
+        if commanded == 0.0 && deposited == 0 && dropped == 0 {
+            return;
+        }
+        let block_volume = self.params.unit.powi(3);
+        mass.add(MassSample {
+            line: self.line,
+            layer: self.layer,
+            feature: self.feature.as_deref(),
+            pos: self.pos,
+            commanded,
+            deposited: deposited as f32 * block_volume,
+            dropped: dropped as f32 * block_volume,
+        });
+    }
+
+    pub fn wall_seconds(&self) -> f32 {
+        self.wall_seconds
+    }
+
+    /// Seconds to move from `from` to `to` at the current feedrate.
+    fn move_time(&self, from: Vector3<f32>, to: Vector3<f32>) -> f32 {
+        let accel = self.velocity_limit.accel.unwrap_or(self.params.accel);
+        let junction = self
+            .velocity_limit
+            .square_corner_velocity
+            .unwrap_or(self.params.square_corner_velocity);
+        self.params
+            .kinematics
+            .move_time(from, to, self.feed_speed(self.f), accel, junction)
+    }
+
+    /// Net filament fed so far, in millimeters, including M221 and the
+    /// extrusion multiplier.
+    pub fn filament_length(&self) -> f32 {
+        self.filament_length
+    }
+
+    /// Net filament fed so far, in cubic millimeters.
+    pub fn filament_volume(&self) -> f32 {
+        self.filament_volume
+    }
+
+    /// Volume of the filled voxels, in cubic millimeters.
+    pub fn deposited_volume(&self) -> f32 {
+        self.mv.bounding_box().count as f32 * self.params.unit.powi(3)
+    }
+
+    /// M221 flow of the active tool times the extrusion multiplier.
+    fn flow(&self) -> f32 {
+        let m221 = self.flow_factors.get(self.tool).copied().unwrap_or(1.0);
+        m221 * self.params.extrusion_multiplier
+    }
+
+    fn is_ironing(&self) -> bool {
+        self.feature.as_deref().is_some_and(is_ironing_feature)
+    }
+
+    fn is_purge(&self) -> bool {
+        self.feature.as_deref().is_some_and(is_purge_feature)
+    }
+
+    pub fn exclude_object(&mut self, name: &str) {
+        if !self
+            .excluded_objects
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(name))
+        {
+            self.excluded_objects.push(name.to_string());
+        }
+    }
+
+    /// Simulate only the given objects; material outside of any object
+    /// (skirt, purge lines) is skipped as well.
+    pub fn only_object(&mut self, name: &str) {
+        self.only_objects.push(name.to_string());
+    }
+
+    /// Records per-voxel metadata from now on, and exports one group per
+    /// object or tool. Has to be set before simulating.
+    pub fn set_split(&mut self, split: SplitBy) {
+        self.split = split;
+        if self.meta.is_none() {
+            self.meta = Some(VoxelMetaMap::default());
+        }
+    }
+
+    pub fn meta(&self) -> Option<&VoxelMetaMap> {
+        self.meta.as_ref()
+    }
+
+    pub fn voxel(&self) -> &V {
+        &self.mv
+    }
+
+    /// Machine origin in voxel space, millimeters.
+    pub fn home(&self) -> Vector3<f32> {
+        self.home
+    }
+
+    fn is_excluded(&self) -> bool {
+        if self.skip_purge && self.is_purge() {
+            return true;
+        }
+        let matches = |list: &[String], cur: &str| list.iter().any(|o| o.eq_ignore_ascii_case(cur));
+        match &self.current_object {
+            Some(cur) => {
+                matches(&self.excluded_objects, cur)
+                    || (!self.only_objects.is_empty() && !matches(&self.only_objects, cur))
+            }
+            None => !self.only_objects.is_empty(),
+        }
+    }
+
+    /// Feedrate (mm/min) to mm/s, scaled by M220 and capped by
+    /// SET_VELOCITY_LIMIT.
+    fn feed_speed(&self, f: f32) -> f32 {
+        let v = f / 60.0 * self.speed_factor;
+        match self.velocity_limit.velocity {
+            Some(max) => v.min(max),
+            None => v,
+        }
+    }
+
+    /// Low-pass filter coefficient of the nozzle pressure model. Pressure
+    /// advance up to `pressure_advance_ref` compensates the lag.
+    fn e_alpha(&self) -> f32 {
+        let alpha = self.params.e_alpha;
+        let advance = self.pressure_advance / self.params.pressure_advance_ref;
+        (alpha + (1.0 - alpha) * advance).clamp(0.0, 1.0)
+    }
+
+    /// Filament (mm) the extruder leads by when advancing past
+    /// `pressure_advance_ref`, for a move feeding `e` mm in `seconds`: the
+    /// excess advance times the extrusion rate. It comes out as a bulge
+    /// where the extrusion starts and is missing where the next one does.
+    fn e_overshoot(&self, e: f32, seconds: f32) -> f32 {
+        let excess = self.pressure_advance - self.params.pressure_advance_ref;
+        if excess <= 0.0 || e <= 0.0 || seconds <= 0.0 {
+            return 0.0;
+        }
+        excess * e / seconds
+    }
+
     fn handle_gcode(&mut self, code: GCode1Coord) -> usize {
+        let code = match self.artifacts.as_mut() {
+            Some(artifacts) => artifacts.apply(code),
+            None => code,
+        };
+        let code = match self.frame.as_mut() {
+            Some(frame) => frame.apply(code),
+            None => code,
+        };
         if code.major == 92 {
             self.g_92(code);
             return 0;
         }
 
-        // unit: millimeters
-        // TODO: extract from gcode
-        let z_offset: i32 = (self.params.layer_height / self.params.unit) as i32;
-        let z_offset_up: i32 = 1;
-
-        // tunables
-        let inject_offset_z: f32 = 0.0; // LAYER_HEIGHT / 2.0;
-
         let mut dst = self.pos;
         let mut dst_e = self.e;
         let mut target_f = self.f;
@@ -534,22 +1390,61 @@ impl<V: Voxel + Default> ExtrudeState<V> {
         }
 
         self.f = target_f;
+
+        if self.is_excluded() {
+            // excluded objects are skipped by the firmware: track the
+            // position and extruder without depositing or spending time
+            self.e = dst_e;
+            self.e_delay = dst_e;
+            self.e_top = self.e_top.max(dst_e);
+            self.pos = dst;
+            return 0;
+        }
+
+        let from = self.pos + self.home + self.nozzle_offset(self.pos);
+        let to = dst + self.home + self.nozzle_offset(dst);
+        if let Some(checker) = self.collision.as_mut() {
+            let travel = dst_e <= self.e;
+            checker.check(from, to, self.home, self.line, self.layer, travel);
+        }
+
         let diff = dst - self.pos;
         // in millimeters
         let len = diff.magnitude();
-        let dir = diff.normalize();
-        self.dir = dir;
+        // moves without XYZ (primes) keep the last direction
+        if len > 0.0 {
+            self.dir = diff / len;
+        }
+        let dir = self.dir;
+
+        let mut dropped = 0;
+        if dst_e > self.e && len > 0.0 {
+            self.seams.extrude(self.pos, dst);
+        } else if len > 0.0 || dst_e < self.e {
+            self.end_path();
+            if dst_e < self.e && self.seams.retract() {
+                dropped += self.seam_ooze(dst, dst_e);
+            }
+        }
 
-        let seconds = len / (self.f / 60.0);
+        let seconds = self.move_time(self.pos, dst);
         self.wall_seconds += seconds;
+        self.advance_heaters(seconds);
+
+        let e_move = (dst_e - self.e) * self.flow();
+        let commanded = e_move * self.tool_params(self.tool).cross_section();
+        self.filament_length += e_move;
+        self.filament_volume += commanded;
+        let melted = self.limit_flow(dst, commanded, seconds);
 
         // pressure delay
         // delta_e, in centimeters
         let e_delta = {
             // First-Order Low-Pass Filter
             // let alpha = (self.params.e_alpha * seconds).clamp(0.0, 1.0);
-            let alpha = self.params.e_alpha; // * seconds).clamp(0.0, 1.0);
-            let e_delay = self.e_delay * (1.0 - alpha) + dst_e * alpha;
+            let alpha = self.e_alpha();
+            let target = dst_e + self.e_overshoot(dst_e - self.e, seconds);
+            let e_delay = self.e_delay * (1.0 - alpha) + target * alpha;
             let e_delta = e_delay - self.e_top;
 
             self.e = dst_e;
@@ -558,41 +1453,296 @@ impl<V: Voxel + Default> ExtrudeState<V> {
             e_delta
         };
 
+        if len > 0.0 && self.is_ironing() {
+            let (deposited, blocks) = self.iron(dst, e_delta.max(0.0) * melted);
+            self.account_mass(commanded, deposited, blocks);
+            return dropped + blocks;
+        }
+
         if e_delta <= 0f32 {
+            if len > 0.0 {
+                // travel: the next extrusion starts a new strand
+                self.airborne = 0.0;
+            }
             self.pos = dst;
-            return 0;
+            self.account_mass(commanded, 0, 0);
+            return dropped;
+        }
+
+        let (deposited, blocks) = self.deposit(dst, dir, e_delta * melted);
+        self.account_mass(commanded, deposited, blocks);
+        dropped + blocks
+    }
+
+    /// World direction from the layer below to a strand moving along `dir`:
+    /// the machine's layer normal, tilted along the slope of non-planar
+    /// moves.
+    fn layer_normal(&self, dir: Vector3<f32>) -> Vector3<f32> {
+        let base = self
+            .frame
+            .as_ref()
+            .map_or(Vector3::z(), |frame| frame.layer_normal());
+        let normal = base - dir * base.dot(&dir);
+        if normal.magnitude() < 1e-3 {
+            base
+        } else {
+            normal.normalize()
+        }
+    }
+
+    /// Nozzle tip relative to the commanded position `pos`: tool offset, Z
+    /// offset and bed leveling.
+    fn nozzle_offset(&self, pos: Vector3<f32>) -> Vector3<f32> {
+        let bed = &self.params.bed_contact;
+        let z_offset = bed.z_offset + self.z_adjust + bed.compensation(pos);
+        self.tool_params(self.tool).offset + Vector3::new(0.0, 0.0, z_offset)
+    }
+
+    /// Limits the Z range (voxels) of a strand at `pos` by the bed. Strands
+    /// within a layer (`layer` voxels) of the bed and not printed onto other
+    /// material touch it: they fill everything between the bed and the
+    /// nozzle, and are squished or stretched with the gap. Returns the range,
+    /// whether the strand touches the bed, and how far (voxels) its bottom
+    /// flares out.
+    fn bed_zrange(
+        &self,
+        zrange: std::ops::Range<i32>,
+        pos: Vector3<f32>,
+        layer: i32,
+    ) -> (std::ops::Range<i32>, bool, i32) {
+        let bed = &self.params.bed_contact;
+        // the lowest voxel row fully above the surface
+        let floor = self.params.intpos(bed.height_at(pos)) + 1;
+
+        let below = self
+            .params
+            .to_intpos(pos + self.home + self.nozzle_offset(pos));
+        let below = VoxelIdx::new([below[0], below[1], zrange.start - 1]);
+        let contact =
+            zrange.start < floor || (zrange.start < floor + layer && !self.mv.occupied(below));
+        let range = if contact {
+            // the tip is one row below the end of the range
+            floor..(zrange.end - 1).max(floor) + 1
+        } else {
+            zrange
+        };
+
+        let above = (range.start - floor) as f32 * self.params.unit;
+        let flare = self.params.intpos(bed.squish(above));
+        (range, contact, flare)
+    }
+
+    /// Sag (mm) along the strand from `from` to `to` (voxel space), sampled
+    /// every voxel. `bottom` is the lowest voxel layer of the new strand;
+    /// support is looked up in the layer below it. `None` when nothing sags.
+    fn sag_profile(
+        &mut self,
+        from: Vector3<f32>,
+        to: Vector3<f32>,
+        bottom: i32,
+    ) -> Option<Vec<f32>> {
+        let sag = SagParams {
+            cooling: self.cooling(),
+            ..self.params.sag
+        };
+        if !sag.is_enabled() {
+            return None;
+        }
+
+        // first layers rest on the bed
+        let z =
+            from.z.min(to.z) - self.home.z - self.params.bed_contact.height_at(from - self.home);
+        if z <= self.params.layer_height * 1.5 {
+            self.airborne = 0.0;
+            return None;
+        }
+
+        if bottom != self.sag_bottom {
+            self.sag_bottom = bottom;
+            self.sagged.clear();
+        }
+        let is_support = |p: VoxelIdx| self.mv.occupied(p) && !self.sagged.contains(&p);
+
+        let unit = self.params.unit;
+        let len = (to - from).magnitude();
+        let steps = (len / unit).ceil() as usize;
+        // full support within half an extrusion width, partial within two
+        let supported = NOZZLE_SIZE / 2.0;
+        let reach = (NOZZLE_SIZE * 2.0 / unit).ceil() as i32;
+        let overhang = (0..=steps)
+            .map(|i| {
+                let t = if steps > 0 {
+                    i as f32 / steps as f32
+                } else {
+                    0.0
+                };
+                let p = self.params.to_intpos(from + (to - from) * t);
+                let below = VoxelIdx::new([p[0], p[1], bottom - 1]);
+                if is_support(below) {
+                    return Some(0.0);
+                }
+                let mut nearest: Option<i32> = None;
+                for dx in -reach..=reach {
+                    for dy in -reach..=reach {
+                        let d2 = dx * dx + dy * dy;
+                        if d2 > reach * reach || nearest.is_some_and(|n| n <= d2) {
+                            continue;
+                        }
+                        if is_support(below + VoxelIdx::new([dx, dy, 0])) {
+                            nearest = Some(d2);
+                        }
+                    }
+                }
+                nearest.map(|d2| ((d2 as f32).sqrt() * unit - supported).max(0.0))
+            })
+            .collect::<Vec<_>>();
+
+        let step = if steps > 0 { len / steps as f32 } else { 0.0 };
+        let scale = sag.scale(self.feed_speed(self.f));
+        let (profile, airborne) = sag.profile(&overhang, step, self.airborne, scale);
+        self.airborne = airborne;
+        if profile.iter().all(|s| *s < unit / 2.0) {
+            return None;
         }
+        Some(profile)
+    }
+
+    /// Irons the surface along the segment from the current position to
+    /// `dst`, extruding `e_delta` millimeters of filament into it, then
+    /// moves there. Returns the net number of blocks added, and the number
+    /// of blocks which found no room.
+    fn iron(&mut self, dst: Vector3<f32>, e_delta: f32) -> (usize, usize) {
+        let params = self.params.ironing;
+        let unit = self.params.unit;
+        let dir = self.dir;
+        let from = self.pos + self.home + self.nozzle_offset(self.pos);
+        let to = dst + self.home + self.nozzle_offset(dst);
+
+        // columns under the flat face of the nozzle
+        let side = Vector3::new(dir.y, -dir.x, 0.0);
+        let steps = (params.width / unit).ceil() as i32;
+        let mut cells = vec![];
+        for i in -steps..=steps {
+            let offset = side * (i as f32 * params.width / 2.0 / steps.max(1) as f32);
+            let a = self.params.to_intpos(from + offset);
+            let b = self.params.to_intpos(to + offset);
+            line_cells(a, b, &mut cells);
+        }
+        let mut footprint = cells.iter().map(|c| [c[0], c[1]]).collect::<Vec<_>>();
+        footprint.sort();
+        footprint.dedup();
+
+        let tip = self.params.to_intpos(to)[2];
+        let floor = self.params.intpos(self.params.bed_contact.height_at(dst)) + 1;
+        let rows = [
+            self.params.intpos(params.reach).max(1),
+            self.params.intpos(params.depth).max(1),
+        ];
+        let tool = self.tool_params(self.tool);
+        let extruded = (e_delta * tool.cross_section() * self.flow() / unit.powi(3)) as usize;
+
+        let cur = VoxelMeta {
+            object: match self.meta.as_mut() {
+                Some(meta) => meta.object_id(self.current_object.as_deref()),
+                None => 0,
+            },
+            tool: self.tool.min(u8::MAX as usize) as u8,
+            purge: false,
+            layer: self.layer.unwrap_or(0) as u32,
+            time: self.wall_seconds,
+        };
+        let meta = &mut self.meta;
+        let reflow = reflow(
+            &mut self.mv,
+            &footprint,
+            tip,
+            rows,
+            floor,
+            extruded,
+            |pos, added| {
+                if let Some(meta) = meta.as_mut() {
+                    if added {
+                        meta.insert(pos, cur);
+                    } else {
+                        meta.remove(pos);
+                    }
+                }
+            },
+        );
+        debug!("ironing {:?} -> {:?}: {:?}", self.pos, dst, reflow);
 
-        let zrange = {
-            let z0 = self.params.intpos(self.pos[2]);
-            let z1 = self.params.intpos(dst[2]);
+        self.pos = dst;
+        (reflow.filled.saturating_sub(reflow.removed), reflow.left)
+    }
+
+    /// Extrudes `e_delta` millimeters of filament along the segment from the
+    /// current position to `dst`, then moves there. Returns the number of
+    /// blocks placed, and the number of blocks which did not fit.
+    fn deposit(&mut self, dst: Vector3<f32>, dir: Vector3<f32>, e_delta: f32) -> (usize, usize) {
+        // unit: millimeters
+        // TODO: extract from gcode
+        let z_offset: i32 = (self.params.layer_height / self.params.unit) as i32;
+        let z_offset_up: i32 = 1;
+
+        // tunables
+        let inject_offset_z: f32 = 0.0; // LAYER_HEIGHT / 2.0;
+
+        let tool = self.tool_params(self.tool);
+        let nozzle = self.nozzle_offset(dst);
+
+        // the strand rests on the layer below along the layer normal; on
+        // tilted layers (belt printers, non-planar moves) it is stacked along
+        // the normal instead of filling the rows straight below
+        let normal = self.layer_normal(dir);
+        let tilted = normal.z < TILTED_LAYER_COS;
+
+        let zrange = if tilted {
+            // set from the cells below
+            (0..0, false, 0)
+        } else {
+            let z0 = self
+                .params
+                .intpos(self.pos[2] + self.nozzle_offset(self.pos)[2]);
+            let z1 = self.params.intpos(dst[2] + nozzle[2]);
             let zmin = z0.min(z1);
             let zmax = z0.max(z1);
-            (zmin - z_offset)..(zmax + z_offset_up)
+            self.bed_zrange((zmin - z_offset)..(zmax + z_offset_up), dst, z_offset)
         };
+        let (mut zrange, bed_contact, flare) = zrange;
 
-        let oz = self.home + Vector3::new(0.0, 0.0, -inject_offset_z);
+        let oz = self.home + nozzle + Vector3::new(0.0, 0.0, -inject_offset_z);
+        // uncooled beads flow out sideways before they set
+        let width = NOZZLE_SIZE * self.params.fan.spread(self.cooling());
+        let side = if tilted {
+            normal.cross(&dir).normalize()
+        } else {
+            Vector3::new(dir.y, -dir.x, 0.0)
+        };
         let offsets = [
             oz + Vector3::new(0.0, 0.0, 0.0),
-            oz + Vector3::new(dir.y, -dir.x, 0.0) * NOZZLE_SIZE / 8.0,
-            oz + Vector3::new(-dir.y, dir.x, 0.0) * NOZZLE_SIZE / 8.0,
-            oz + Vector3::new(dir.y, -dir.x, 0.0) * NOZZLE_SIZE / 6.0,
-            oz + Vector3::new(-dir.y, dir.x, 0.0) * NOZZLE_SIZE / 6.0,
+            oz + side * width / 8.0,
+            oz - side * width / 8.0,
+            oz + side * width / 6.0,
+            oz - side * width / 6.0,
         ];
+        let layers = if tilted { z_offset } else { 0 };
 
         let gen_cells = |from: Vector3<f32>, to: Vector3<f32>| {
             let mut cells = vec![];
-            for offset in &offsets {
-                let pos = self.params.to_intpos(from + offset);
-                let next_pos = self.params.to_intpos(to + offset);
-                line_cells(pos, next_pos, &mut cells);
+            for k in 0..=layers {
+                let down = normal * (-k as f32 * self.params.unit);
+                for offset in &offsets {
+                    let pos = self.params.to_intpos(from + offset + down);
+                    let next_pos = self.params.to_intpos(to + offset + down);
+                    line_cells(pos, next_pos, &mut cells);
+                }
             }
             cells
         };
 
         // flow rate calculation
         // with 1.75mm filament, calculate volume, in millimeters
-        let filament_volume = e_delta * FILAMENT_CROSS_SECION;
+        let filament_volume = e_delta * tool.cross_section() * self.flow();
 
         // block volume in cubic millimeters
         let block_volume = self.params.unit.powi(3);
@@ -605,8 +1755,8 @@ impl<V: Voxel + Default> ExtrudeState<V> {
             "{:?} -> {:?}, len={}, e={:?}, blocks={}",
             self.pos,
             dst,
-            len,
-            dst_e - self.e,
+            (dst - self.pos).magnitude(),
+            e_delta,
             total_blocks
         );
 
@@ -638,18 +1788,227 @@ impl<V: Voxel + Default> ExtrudeState<V> {
         }
         */
 
+        let mut deposited = 0;
+
         // last segment
         if blocks > 0 {
-            let cells = gen_cells(cursor, dst);
-            let extrudeed = extrude_at(&mut self.mv, zrange.clone(), max_dist, &cells, blocks);
-            if extrudeed != blocks {
-                debug!("extrudeed != blocks, skipping: {} != {}", extrudeed, blocks);
+            let mut cells = gen_cells(cursor, dst);
+            if tilted {
+                // nothing goes into the bed
+                let floor = self.params.intpos(self.params.bed_contact.height_at(dst)) + 1;
+                cells.retain(|c| c[2] >= floor);
+                let top = cells.iter().map(|c| c[2] + 1).max().unwrap_or(floor);
+                zrange = cells.iter().map(|c| c[2]).min().unwrap_or(floor)..top;
             }
-            blocks -= extrudeed;
+            if bed_contact {
+                // fill from the bed up to the nozzle
+                cells = cells
+                    .iter()
+                    .flat_map(|c| zrange.clone().map(|z| VoxelIdx::new([c[0], c[1], z])))
+                    .collect();
+            }
+            if flare > 0 {
+                // elephant foot: the bottom row spreads out first
+                let side = Vector3::new(dir.y, -dir.x, 0.0) * (flare as f32 * self.params.unit);
+                for offset in [side, -side] {
+                    let from = self.params.to_intpos(cursor + oz + offset);
+                    let to = self.params.to_intpos(dst + oz + offset);
+                    let mut line = vec![];
+                    line_cells(from, to, &mut line);
+                    cells.extend(
+                        line.iter()
+                            .map(|c| VoxelIdx::new([c[0], c[1], zrange.start])),
+                    );
+                }
+            }
+
+            // cells grouped by how far (voxels) they sag; each group is
+            // deposited with its own Z range, so that the strand moves down
+            // instead of growing
+            let (from, to) = (cursor + oz, dst + oz);
+            let sag = if bed_contact {
+                None
+            } else {
+                self.sag_profile(from, to, zrange.start)
+            };
+            // sagging stops at the bed
+            let floor = self.params.intpos(self.params.bed_contact.height_at(dst)) + 1;
+            let groups = match sag {
+                Some(sag) => {
+                    let ab = to - from;
+                    let len2 = ab.magnitude_squared();
+                    let mut groups = std::collections::BTreeMap::<i32, Vec<VoxelIdx>>::new();
+                    for cell in cells {
+                        let p = Vector3::from(cell.f32()) * self.params.unit;
+                        let t = if len2 > 0.0 {
+                            ((p - from).dot(&ab) / len2).clamp(0.0, 1.0)
+                        } else {
+                            0.0
+                        };
+                        let s = sag[(t * (sag.len() - 1) as f32).round() as usize];
+                        let dz = self
+                            .params
+                            .intpos(s)
+                            .clamp(0, (zrange.start - floor).max(0));
+                        groups
+                            .entry(dz)
+                            .or_default()
+                            .push(cell - VoxelIdx::new([0, 0, dz]));
+                    }
+                    groups.into_iter().collect::<Vec<_>>()
+                }
+                None => vec![(0, cells)],
+            };
+            let total_cells = groups.iter().map(|(_, c)| c.len()).sum::<usize>();
+
+            let purge = self.is_purge();
+            let meta = &mut self.meta;
+            let collision = &mut self.collision;
+            let (sag_bottom, sagged) = (zrange.start, &mut self.sagged);
+            let cur = VoxelMeta {
+                object: match meta.as_mut() {
+                    Some(meta) => meta.object_id(self.current_object.as_deref()),
+                    None => 0,
+                },
+                tool: self.tool.min(u8::MAX as usize) as u8,
+                purge,
+                layer: self.layer.unwrap_or(0) as u32,
+                time: self.wall_seconds,
+            };
+            let mut on_add = |pos| {
+                if let Some(meta) = meta.as_mut() {
+                    meta.insert(pos, cur);
+                }
+                if let Some(collision) = collision.as_mut() {
+                    collision.record(pos);
+                }
+                if pos[2] < sag_bottom {
+                    sagged.insert(pos);
+                }
+            };
+
+            let mut shortfall = 0;
+            let mut assigned = 0;
+            for (i, (dz, cells)) in groups.iter().enumerate() {
+                // blocks in proportion to the cells, the rest to the last group
+                let share = if i + 1 == groups.len() {
+                    blocks - assigned
+                } else {
+                    blocks * cells.len() / total_cells
+                };
+                assigned += share;
+                let n = share + shortfall;
+                let zrange = (zrange.start - dz)..(zrange.end - dz);
+                let extrudeed =
+                    extrude_at_with(&mut self.mv, zrange, max_dist, cells, n, &mut on_add);
+                shortfall = n - extrudeed;
+                deposited += extrudeed;
+            }
+            if deposited != blocks {
+                debug!("extrudeed != blocks, skipping: {} != {}", deposited, blocks);
+            }
+            blocks -= deposited;
         }
 
         self.pos = dst;
-        blocks
+        (deposited, blocks)
+    }
+}
+
+/// Options for `generate_gcode` beyond input/output selection.
+#[derive(Clone, Debug, Default)]
+pub struct GenerateOptions {
+    pub parse_mode: ParseMode,
+    /// objects to skip, as in EXCLUDE_OBJECT
+    pub exclude_objects: Vec<String>,
+    /// when non-empty, only these objects are deposited
+    pub only_objects: Vec<String>,
+    pub split: SplitBy,
+    /// indexed by tool
+    pub tools: Vec<ToolOverride>,
+    pub skip_purge: bool,
+    /// overrides `Parameters::extrusion_multiplier`
+    pub extrusion_multiplier: Option<f32>,
+    /// collect a `MassReport`, written as mass_report.json next to the output
+    pub mass_report: bool,
+    /// hotend melting capacity; moves over it are written as
+    /// flow_report.json next to the output
+    pub max_flow: Option<FlowLimit>,
+    /// overrides `Parameters::sag`
+    pub sag: Option<SagParams>,
+    /// overrides `Parameters::seam`
+    pub seam: Option<SeamParams>,
+    /// deform the exported meshes by thermal shrinkage and warping
+    pub warp: Option<WarpParams>,
+    /// overrides `BedParams::z_offset`
+    pub z_offset: Option<f32>,
+    /// overrides `BedParams::surface`
+    pub bed_surface: Option<BedSurface>,
+    /// mechanical artifacts to inject; the applied layer shifts are written
+    /// as layer_shifts.json next to the output
+    pub artifacts: Option<ArtifactConfig>,
+    /// enables bed leveling from the start, fading out at this height (mm,
+    /// 0 to never fade)
+    pub leveling: Option<f32>,
+    /// machine to world coordinates, e.g. for belt printers
+    pub transform: Option<MachineTransform>,
+    /// overrides `Parameters::kinematics`
+    pub kinematics: Option<Arc<dyn Kinematics>>,
+}
+
+impl GenerateOptions {
+    fn apply<V: Voxel + Default>(&self, state: &mut ExtrudeState<V>) -> Result<()> {
+        for name in &self.exclude_objects {
+            state.exclude_object(name);
+        }
+        for name in &self.only_objects {
+            state.only_object(name);
+        }
+        if self.split != SplitBy::None {
+            state.set_split(self.split);
+        }
+        for (i, tool) in self.tools.iter().enumerate() {
+            state.override_tool(i, *tool);
+        }
+        state.skip_purge(self.skip_purge);
+        if let Some(m) = self.extrusion_multiplier {
+            state.params.extrusion_multiplier = m;
+        }
+        if self.mass_report {
+            state.enable_mass_report();
+        }
+        if let Some(limit) = self.max_flow {
+            state.enable_flow_limit(limit);
+        }
+        if let Some(sag) = self.sag {
+            state.params.sag = sag;
+        }
+        if let Some(seam) = self.seam {
+            state.params.seam = seam;
+        }
+        if let Some(warp) = self.warp {
+            state.enable_warp(warp);
+        }
+        if let Some(z_offset) = self.z_offset {
+            state.params.bed_contact.z_offset = z_offset;
+        }
+        if let Some(surface) = &self.bed_surface {
+            state.params.bed_contact.surface = surface.clone();
+        }
+        if let Some(fade_height) = self.leveling {
+            state.params.bed_contact.leveling = true;
+            state.params.bed_contact.fade_height = fade_height;
+        }
+        if let Some(kinematics) = &self.kinematics {
+            state.params.kinematics = kinematics.clone();
+        }
+        if let Some(config) = &self.artifacts {
+            state.enable_artifacts(config.clone());
+        }
+        if let Some(transform) = self.transform {
+            state.set_transform(transform)?;
+        }
+        Ok(())
     }
 }
 
@@ -658,54 +2017,57 @@ pub fn generate_gcode<V: Voxel + Default>(
     out_filename: &str,
     layer: usize,
     out_layers: bool,
+    options: &GenerateOptions,
 ) -> Result<()> {
     let mut state = ExtrudeState::<V>::default();
+    options.apply(&mut state)?;
 
     let sw = Stopwatch::start_new();
-    let parsed = parse_gcode(filename)?;
-
     if false {
+        let parsed =
+            GCodeReader::with_mode(BufReader::new(File::open(filename)?), options.parse_mode)
+                .collect::<Result<Vec<_>, _>>()?;
         let mut runner = ExtrudeRunner::<V>::new(parsed);
+        options.apply(&mut runner.state)?;
         info!("meta: {:?}", runner.meta);
         while !runner.step(1.0 / FPS as f32) {
             // runner.state.mv.debug1();
         }
         state = runner.state;
     } else {
-        for (_line, item) in parsed {
-            match item {
-                GCode1::Layer(layer_idx) => {
-                    if layer_idx == 0 {
-                        continue;
-                    }
-
-                    if layer_idx == layer {
-                        break;
-                    }
-
-                    if out_layers && layer_idx % 10 == 0 {
-                        let postfix = format!("{:03}", layer_idx);
-                        state.export(out_filename, &postfix)?;
-                    }
-                }
-                GCode1::Coord(coord) => {
-                    state.handle_gcode(coord);
-                }
-                GCode1::Miscellaneous(code) => {
-                    if code == 82 {
-                        state.e_relative = false;
-                    } else if code == 83 {
-                        state.e_relative = true;
-                    }
-                }
-                _ => {
-                    //
+        simulate_file_with(&mut state, filename, options.parse_mode, |state, item| {
+            match *item {
+                GCode1::Layer(0) => {}
+                GCode1::Layer(layer_idx) if layer_idx == layer => return Ok(false),
+                GCode1::Layer(layer_idx) if out_layers && layer_idx % 10 == 0 => {
+                    let postfix = format!("{:03}", layer_idx);
+                    state.export(out_filename, &postfix)?;
                 }
+                _ => {}
             }
-        }
+            Ok(true)
+        })?;
     }
     state.export(out_filename, "full")?;
 
+    if let Some(mass) = state.mass_report() {
+        let filename = format!("{}/mass_report.json", out_filename);
+        std::fs::write(&filename, mass.to_json()?)?;
+        info!("mass report: {}\n{}", filename, mass);
+    }
+    if let Some(flow) = state.flow_report() {
+        let filename = format!("{}/flow_report.json", out_filename);
+        std::fs::write(&filename, flow.to_json()?)?;
+        info!("flow report: {}\n{}", filename, flow);
+    }
+    if options.artifacts.is_some() {
+        let filename = format!("{}/layer_shifts.json", out_filename);
+        std::fs::write(
+            &filename,
+            serde_json::to_string_pretty(state.layer_shifts())?,
+        )?;
+    }
+
     let blocks = state.mv.bounding_box().count;
     info!(
         "voxel construction: took={:.2}ms, blocks={}/{}, bps={}, frames={}, {:.1} dirty / frame",
@@ -720,6 +2082,86 @@ pub fn generate_gcode<V: Voxel + Default>(
     Ok(())
 }
 
+/// Runs a whole file through `state`; returns the slicer metadata found in
+/// its comments.
+pub fn simulate_file<V: Voxel + Default>(
+    state: &mut ExtrudeState<V>,
+    filename: &str,
+    mode: ParseMode,
+) -> Result<GCodeMeta> {
+    simulate_file_with(state, filename, mode, |_, _| Ok(true))
+}
+
+/// As `simulate_file`; `on_item` sees every item right after `state` has
+/// handled it, and stops the run by returning false.
+pub fn simulate_file_with<V, F>(
+    state: &mut ExtrudeState<V>,
+    filename: &str,
+    mode: ParseMode,
+    on_item: F,
+) -> Result<GCodeMeta>
+where
+    V: Voxel + Default,
+    F: FnMut(&mut ExtrudeState<V>, &GCode1) -> Result<bool>,
+{
+    state.preload_tool_config(&scan_tool_config(BufReader::new(File::open(filename)?))?);
+    let parsed = GCodeReader::with_mode(BufReader::new(File::open(filename)?), mode);
+    simulate(state, parsed, on_item)
+}
+
+/// Runs G-code held in memory through `state`, as `simulate_file`.
+pub fn simulate_str<V: Voxel + Default>(
+    state: &mut ExtrudeState<V>,
+    gcode: &str,
+    mode: ParseMode,
+) -> Result<GCodeMeta> {
+    state.preload_tool_config(&scan_tool_config(gcode.as_bytes())?);
+    let parsed = GCodeReader::with_mode(gcode.as_bytes(), mode);
+    simulate(state, parsed, |_, _| Ok(true))
+}
+
+fn simulate<V, R, F>(
+    state: &mut ExtrudeState<V>,
+    mut parsed: GCodeReader<R>,
+    mut on_item: F,
+) -> Result<GCodeMeta>
+where
+    V: Voxel + Default,
+    R: BufRead,
+    F: FnMut(&mut ExtrudeState<V>, &GCode1) -> Result<bool>,
+{
+    let mut comments = vec![];
+    for item in parsed.by_ref() {
+        let (line, item) = item?;
+        state.set_line(line);
+        match &item {
+            GCode1::Coord(coord) => {
+                state.handle_gcode(*coord);
+            }
+            item => {
+                state.handle_command(item);
+                if let GCode1::TypedComment(prefix, value) = item {
+                    comments.push((prefix.clone(), value.clone()));
+                }
+            }
+        }
+        if !on_item(state, &item)? {
+            break;
+        }
+    }
+
+    let diagnostics = parsed.diagnostics();
+    if !diagnostics.is_empty() {
+        warn!("skipped {} malformed G-code lines", diagnostics.len());
+    }
+
+    let comments = comments
+        .iter()
+        .map(|(prefix, value)| (prefix.as_str(), value.as_str()))
+        .collect::<Vec<_>>();
+    Ok(GCodeMeta::from_comments(&comments))
+}
+
 pub fn simulate_gcode_layers<V: Voxel + Default>(
     codes: &[(usize, GCode1)],
     layers: usize,
@@ -735,14 +2177,9 @@ pub fn simulate_gcode_layers<V: Voxel + Default>(
             GCode1::Coord(coord) => {
                 state.handle_gcode(coord);
             }
-            GCode1::Miscellaneous(code) => {
-                if code == 82 {
-                    state.e_relative = false;
-                } else if code == 83 {
-                    state.e_relative = true;
-                }
+            ref item => {
+                state.handle_command(item);
             }
-            _ => {}
         }
     }
     state.mv.bounding_box().count
@@ -753,6 +2190,9 @@ pub struct ExtrudeRunner<V: Voxel> {
     pub state: ExtrudeState<V>,
 
     pendings: Vec<(usize, GCode1)>,
+    /// position as commanded by the G-code, before artifacts and the
+    /// machine frame; moves are split here
+    commanded: Vector3<f32>,
 }
 
 impl<V: Voxel + Default> ExtrudeRunner<V> {
@@ -771,6 +2211,11 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
             GCodeMeta::from_comments(&comments)
         };
         let mut state = ExtrudeState::<V>::default();
+        // PrusaSlicer writes its config block after the print
+        state.preload_tool_config(pendings.iter().filter_map(|(_, code)| match code {
+            GCode1::ToolConfig(config) => Some(config),
+            _ => None,
+        }));
 
         if let Some((min, max)) = meta.bounding_box {
             // check if coordinate space is center-zero
@@ -785,6 +2230,7 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
 
         Self {
             meta,
+            commanded: state.pos,
             state,
             pendings,
         }
@@ -795,7 +2241,7 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
     }
 
     fn speed(&self) -> Vector3<f32> {
-        self.state.dir * (self.state.f / 60.0)
+        self.state.dir * self.state.feed_speed(self.state.f)
     }
 
     pub fn step(&mut self, mut dt: f32) -> bool {
@@ -817,9 +2263,9 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
     fn g_0_1(&mut self, mut cur: GCode1Coord, dt: f32) -> (Option<GCode1Coord>, f32) {
         let mut prev = GCode1Coord {
             major: cur.major,
-            x: Some(self.state.pos[0]),
-            y: Some(self.state.pos[1]),
-            z: Some(self.state.pos[2]),
+            x: Some(self.commanded[0]),
+            y: Some(self.commanded[1]),
+            z: Some(self.commanded[2]),
             e: Some(self.state.e),
             f: Some(self.state.f),
         };
@@ -837,10 +2283,10 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
         let diff = Vector3::new(dx, dy, dz);
 
         let len = diff.magnitude();
-        let step_len = next.f.unwrap_or(1800.0) / 60.0 * dt;
+        let step_len = self.state.feed_speed(next.f.unwrap_or(1800.0)) * dt;
         if step_len >= len {
             // no need to split
-            self.state.handle_gcode(next);
+            self.move_to(next);
             return (None, dt * len / step_len);
         }
 
@@ -854,7 +2300,7 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
         next.z = Some(prev.z.unwrap_or(0.0) + dz);
         next.e = Some(prev.e.unwrap_or(0.0) + de);
 
-        self.state.handle_gcode(next);
+        self.move_to(next);
 
         if e_relative {
             if let Some(ref mut e) = cur.e {
@@ -864,11 +2310,23 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
         (Some(cur), dt)
     }
 
+    /// Hands a commanded move or G92 to the state, which applies artifacts
+    /// and the machine frame.
+    fn move_to(&mut self, code: GCode1Coord) {
+        for (i, v) in [code.x, code.y, code.z].iter().enumerate() {
+            if let Some(v) = v {
+                self.commanded[i] = *v;
+            }
+        }
+        self.state.handle_gcode(code);
+    }
+
     fn step0(&mut self, dt: f32) -> (bool, f32) {
         match self.pendings.pop() {
             Some((line, GCode1::Coord(cur))) => {
+                self.state.set_line(line);
                 if cur.major == 92 {
-                    self.state.g_92(cur);
+                    self.move_to(cur);
                     (false, 0.0)
                 } else if [0, 1].contains(&cur.major) {
                     match self.g_0_1(cur, dt) {
@@ -882,8 +2340,24 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
                     (false, 0f32)
                 }
             }
+            Some((line, GCode1::Dwell(seconds))) => {
+                let step_dt = seconds.min(dt);
+                self.state.dwell(step_dt);
+                if seconds > step_dt {
+                    self.pendings.push((line, GCode1::Dwell(seconds - step_dt)));
+                }
+                (false, step_dt)
+            }
+            Some((line, GCode1::Temperature(t))) => {
+                let wait = self.state.set_temperature(&t);
+                if t.wait && wait > 0.0 {
+                    self.pendings.push((line, GCode1::Dwell(wait)));
+                }
+                (false, 0.0)
+            }
             Some((_, GCode1::Layer(layer_idx))) => {
                 info!("layer {}", layer_idx);
+                self.state.handle_command(&GCode1::Layer(layer_idx));
                 /*
                 if layer_idx > 0 && layer_idx % 10 == 0 {
                     let postfix = format!("{:03}", layer_idx);
@@ -892,15 +2366,10 @@ impl<V: Voxel + Default> ExtrudeRunner<V> {
                 */
                 (false, 0.0)
             }
-            Some((_, GCode1::Miscellaneous(code))) => {
-                if code == 82 {
-                    self.state.e_relative = false;
-                } else if code == 83 {
-                    self.state.e_relative = true;
-                }
+            Some((_, code)) => {
+                self.state.handle_command(&code);
                 (false, 0.0)
             }
-            Some(_) => (false, 0.0),
             None => (true, 0.0),
         }
     }
@@ -963,6 +2432,20 @@ mod wrapper {
         let data: &[u16] = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
         String::from_utf16(data).ok()
     }
+
+    pub fn from_utf8<'a>(ptr: *const u8, len: u64) -> Option<&'a str> {
+        if ptr.is_null() {
+            return None;
+        }
+        let data: &[u8] = unsafe { std::slice::from_raw_parts(ptr, len as usize) };
+        std::str::from_utf8(data).ok()
+    }
+
+    pub fn into_ptr(runner: FFIRunner<FFIVoxel>) -> *const u8 {
+        let wrapper: RunnerWrapper = Arc::new(RwLock::new(Some(runner)));
+        let ptr = RunnerWrapper::into_raw(wrapper);
+        ptr as *const u8
+    }
 }
 
 use wrapper::*;
@@ -980,10 +2463,33 @@ pub unsafe extern "C" fn runner_new(filename_ptr: *const u16, filename_len: u32)
         }
     };
     let runner = FFIRunner::new(ExtrudeRunner::<FFIVoxel>::new(parsed));
+    into_ptr(runner)
+}
+
+pub type RunnerNewFromBufferFn = unsafe extern "C" fn(*const u8, u64) -> *const u8;
 
-    let wrapper: RunnerWrapper = Arc::new(RwLock::new(Some(runner)));
-    let ptr = RunnerWrapper::into_raw(wrapper);
-    std::mem::transmute(ptr)
+/// Creates a runner from an in-memory, UTF-8 encoded G-code buffer.
+///
+/// # Safety
+/// `gcode_ptr` must point to `gcode_len` readable bytes. Returns null when the
+/// buffer is not valid UTF-8 or cannot be parsed.
+#[no_mangle]
+pub unsafe extern "C" fn runner_new_from_buffer(gcode_ptr: *const u8, gcode_len: u64) -> *const u8 {
+    let gcode = match from_utf8(gcode_ptr, gcode_len) {
+        Some(gcode) => gcode,
+        None => {
+            return std::ptr::null();
+        }
+    };
+
+    let parsed = match parse_gcode_str(gcode) {
+        Ok(parsed) => parsed,
+        Err(_e) => {
+            return std::ptr::null();
+        }
+    };
+    let runner = FFIRunner::new(ExtrudeRunner::<FFIVoxel>::new(parsed));
+    into_ptr(runner)
 }
 
 pub type RunnerDeleteFn = unsafe extern "C" fn(*const u8);
@@ -1069,6 +2575,7 @@ pub struct Tdp1Binding {
     pub runner_retrieve: RunnerRetrieveFn,
     pub runner_set_params: RunnerSetParamsFn,
     pub runner_set_write_options: RunnerSetWriteOptionsFn,
+    pub runner_new_from_buffer: RunnerNewFromBufferFn,
 }
 
 #[no_mangle]
@@ -1080,5 +2587,174 @@ pub extern "C" fn tdp1_get_binding() -> Tdp1Binding {
         runner_retrieve,
         runner_set_params,
         runner_set_write_options,
+        runner_new_from_buffer,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Simulates `src` on a default state, after `setup` adjusted it.
+    pub(crate) fn simulated<F: FnOnce(&mut ExtrudeState<MonotonicVoxel>)>(
+        src: &str,
+        setup: F,
+    ) -> ExtrudeState<MonotonicVoxel> {
+        let mut state = ExtrudeState::default();
+        setup(&mut state);
+        simulate_str(&mut state, src, ParseMode::Strict).unwrap();
+        state
+    }
+
+    /// Every occupied voxel.
+    pub(crate) fn occupied_cells<V: Voxel>(state: &ExtrudeState<V>) -> BTreeSet<VoxelIdx> {
+        let bb = state.voxel().bounding_box();
+        let mut cells = BTreeSet::new();
+        for x in bb.bound_min[0]..=bb.bound_max[0] {
+            for y in bb.bound_min[1]..=bb.bound_max[1] {
+                for z in bb.bound_min[2]..=bb.bound_max[2] {
+                    let idx = VoxelIdx::new([x, y, z]);
+                    if state.voxel().occupied(idx) {
+                        cells.insert(idx);
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    /// Occupied voxel rows at `x`, `y` (mm), bottom up.
+    pub(crate) fn column<V: Voxel>(state: &ExtrudeState<V>, x: f32, y: f32) -> Vec<i32> {
+        let bb = state.voxel().bounding_box();
+        let idx = state
+            .params
+            .to_intpos(Vector3::new(x, y, 0.0) + state.home());
+        (bb.bound_min[2]..=bb.bound_max[2])
+            .filter(|z| state.voxel().occupied(VoxelIdx::new([idx[0], idx[1], *z])))
+            .collect()
+    }
+
+    /// Runs `src` through `ExtrudeRunner` in small time steps.
+    fn stepped<F: FnOnce(&mut ExtrudeState<MonotonicVoxel>)>(
+        src: &str,
+        setup: F,
+    ) -> ExtrudeState<MonotonicVoxel> {
+        let mut runner = ExtrudeRunner::<MonotonicVoxel>::new(parse_gcode_str(src).unwrap());
+        setup(&mut runner.state);
+        while !runner.step(0.05) {}
+        runner.state
+    }
+
+    /// Whether stepping through `src` prints what simulating it does.
+    fn assert_stepped_matches<F: Fn(&mut ExtrudeState<MonotonicVoxel>)>(src: &str, setup: F) {
+        let whole = simulated(src, &setup);
+        let steps = stepped(src, &setup);
+        let (a, b) = (whole.voxel().bounding_box(), steps.voxel().bounding_box());
+        for i in 0..3 {
+            assert!(
+                (a.bound_min[i] - b.bound_min[i]).abs() <= 2,
+                "{:?} {:?}",
+                a,
+                b
+            );
+            assert!(
+                (a.bound_max[i] - b.bound_max[i]).abs() <= 2,
+                "{:?} {:?}",
+                a,
+                b
+            );
+        }
+        let (a, b) = (whole.deposited_volume(), steps.deposited_volume());
+        assert!(a > 0.0 && (a - b).abs() < a * 0.05, "{} {}", a, b);
+    }
+
+    // two 20mm lines, the second one after G92 moved the commanded frame
+    const RUNNER_SRC: &str = "M83\nG1 X10 Y5 Z10 F1200\nG1 X30 E0.67\n\
+                              G92 X0\nG1 Y5.4\nG1 X-20 E0.67\n";
+
+    #[test]
+    fn test_runner_transform() {
+        assert_stepped_matches(RUNNER_SRC, |state| {
+            state.params.e_alpha = 1.0;
+            state.params.sag = SagParams::disabled();
+            state.set_transform(MachineTransform::belt(45.0)).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_runner_artifacts() {
+        let config = ArtifactConfig::parse(
+            "
+            [wobble]
+            amplitude = [2.0, 0.0]
+            phase = 90.0
+
+            [[shifts]]
+            z = 5.0
+            offset = [0.0, 1.0]
+            ",
+        )
+        .unwrap();
+        assert_stepped_matches(RUNNER_SRC, |state| {
+            state.params.e_alpha = 1.0;
+            state.enable_artifacts(config.clone());
+        });
+    }
+
+    #[test]
+    fn test_pressure_advance() {
+        // a 40mm line, 0.4mm wide and 0.2mm high, in 2mm moves
+        let mut src = "M83\nG1 X10 Y10 Z0.2 F1200\n".to_string();
+        for i in 1..=20 {
+            src += &format!("G1 X{} E0.0665\n", 10 + i * 2);
+        }
+
+        // voxels in the first and in a middle 2mm of the line, and in total
+        let run = |advance: Option<f32>| {
+            let prefix = advance.map_or(String::new(), |advance| {
+                format!("SET_PRESSURE_ADVANCE ADVANCE={}\n", advance)
+            });
+            let state = simulated(&(prefix + &src), |_| {});
+            let cells = occupied_cells(&state);
+            let len = state.params.intpos(2.0);
+            let x0 = state.voxel().bounding_box().bound_min[0];
+            let count = |x: i32| {
+                cells
+                    .iter()
+                    .filter(|c| (x..x + len).contains(&c[0]))
+                    .count() as f32
+            };
+            (
+                count(x0),
+                count(x0 + len * 9),
+                state.deposited_volume(),
+                state.filament_volume,
+            )
+        };
+        let reference = Parameters::default().pressure_advance_ref;
+        // without advance, the nozzle lags behind and the line starts thin
+        let (lagging, _, _, _) = run(None);
+
+        // the reference advance cancels the lag
+        let (start, middle, deposited, commanded) = run(Some(reference));
+        assert!(start > lagging, "{} {}", start, lagging);
+        assert!(start > middle * 0.85, "{} {}", start, middle);
+        assert!(
+            (deposited - commanded).abs() < commanded * 0.02,
+            "{} {}",
+            deposited,
+            commanded
+        );
+
+        // more advance bulges at the start, without adding material
+        let (start, middle, deposited, commanded) = run(Some(reference + 0.05));
+        assert!(start > middle * 1.1, "{} {}", start, middle);
+        assert!(
+            (deposited - commanded).abs() < commanded * 0.05,
+            "{} {}",
+            deposited,
+            commanded
+        );
     }
 }
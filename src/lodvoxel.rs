@@ -68,6 +68,10 @@ impl Voxel for LodVoxel {
         self.base.add(coord)
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        self.base.remove(coord)
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let mut models = vec![];
 
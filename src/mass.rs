@@ -0,0 +1,219 @@
+use super::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Commanded vs deposited material, in cubic millimeters.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct MassBucket {
+    pub moves: usize,
+    /// filament fed into the nozzle
+    pub commanded: f32,
+    /// filled voxels
+    pub deposited: f32,
+    /// volume the filler could not place within `max_dist`
+    pub dropped: f32,
+}
+
+impl MassBucket {
+    /// Material neither deposited nor dropped: still in the nozzle (pressure
+    /// lag), retracted, or lost to rounding to whole voxels.
+    pub fn pending(&self) -> f32 {
+        self.commanded - self.deposited - self.dropped
+    }
+
+    /// deposited / commanded
+    pub fn ratio(&self) -> f32 {
+        if self.commanded > 0.0 {
+            self.deposited / self.commanded
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LayerMass {
+    pub layer: Option<usize>,
+    #[serde(flatten)]
+    pub mass: MassBucket,
+}
+
+/// A move where the filler ran out of room and dropped material.
+#[derive(Clone, Debug, Serialize)]
+pub struct MassDrop {
+    /// source line, when known
+    pub line: Option<usize>,
+    pub layer: Option<usize>,
+    pub feature: Option<String>,
+    /// end of the move, millimeters
+    pub pos: [f32; 3],
+    pub dropped: f32,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MassReport {
+    pub total: MassBucket,
+    pub features: BTreeMap<String, MassBucket>,
+    pub layers: Vec<LayerMass>,
+    pub drops: Vec<MassDrop>,
+}
+
+/// One move's worth of accounting.
+pub(crate) struct MassSample<'a> {
+    pub line: Option<usize>,
+    pub layer: Option<usize>,
+    pub feature: Option<&'a str>,
+    pub pos: Vector3<f32>,
+    pub commanded: f32,
+    pub deposited: f32,
+    pub dropped: f32,
+}
+
+impl MassReport {
+    pub(crate) fn add(&mut self, s: MassSample) {
+        let add = |b: &mut MassBucket| {
+            b.moves += 1;
+            b.commanded += s.commanded;
+            b.deposited += s.deposited;
+            b.dropped += s.dropped;
+        };
+
+        add(&mut self.total);
+        add(self
+            .features
+            .entry(s.feature.unwrap_or("none").to_string())
+            .or_default());
+
+        if self.layers.last().map(|l| l.layer) != Some(s.layer) {
+            self.layers.push(LayerMass {
+                layer: s.layer,
+                mass: MassBucket::default(),
+            });
+        }
+        if let Some(last) = self.layers.last_mut() {
+            add(&mut last.mass);
+        }
+
+        if s.dropped > 0.0 {
+            self.drops.push(MassDrop {
+                line: s.line,
+                layer: s.layer,
+                feature: s.feature.map(|f| f.to_string()),
+                pos: [s.pos[0], s.pos[1], s.pos[2]],
+                dropped: s.dropped,
+            });
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl std::fmt::Display for MassReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let row = |f: &mut std::fmt::Formatter, name: &str, b: &MassBucket| {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>12.1} {:>12.1} {:>10.1} {:>10.1} {:>6.1}%",
+                name,
+                b.moves,
+                b.commanded,
+                b.deposited,
+                b.dropped,
+                b.pending(),
+                b.ratio() * 100.0
+            )
+        };
+        let header = |f: &mut std::fmt::Formatter, name: &str| {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>12} {:>12} {:>10} {:>10} {:>7}",
+                name, "moves", "commanded", "deposited", "dropped", "pending", "ratio"
+            )
+        };
+
+        header(f, "feature")?;
+        for (name, b) in &self.features {
+            row(f, name, b)?;
+        }
+        row(f, "total", &self.total)?;
+
+        writeln!(f)?;
+        header(f, "layer")?;
+        for layer in &self.layers {
+            let name = layer.layer.map_or("start".to_string(), |l| l.to_string());
+            row(f, &name, &layer.mass)?;
+        }
+
+        if !self.drops.is_empty() {
+            let mut drops = self.drops.iter().collect::<Vec<_>>();
+            drops.sort_by(|a, b| b.dropped.total_cmp(&a.dropped));
+
+            writeln!(f)?;
+            writeln!(
+                f,
+                "{} moves dropped material, largest (mm3):",
+                self.drops.len()
+            )?;
+            for d in drops.iter().take(10) {
+                writeln!(
+                    f,
+                    "  line {:>7} layer {:>4} {:<16} at ({:.2}, {:.2}, {:.2}): {:.3}",
+                    d.line.map_or("-".to_string(), |l| l.to_string()),
+                    d.layer.map_or("-".to_string(), |l| l.to_string()),
+                    d.feature.as_deref().unwrap_or("none"),
+                    d.pos[0],
+                    d.pos[1],
+                    d.pos[2],
+                    d.dropped
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mass() {
+        let src = "M83\n\
+                   ;LAYER:0\n\
+                   G1 X0 Y0 Z0.2 F1200\n\
+                   ;TYPE:WALL-OUTER\n\
+                   G1 X20 E1\n\
+                   G1 Y1\n\
+                   G1 X0 E1\n\
+                   ;TYPE:FILL\n\
+                   G1 X0.5 E20\n\
+                   G1 Y10\n\
+                   G1 X10\n";
+        let mut state = ExtrudeState::<MonotonicVoxel>::default();
+        state.enable_mass_report();
+        simulate_str(&mut state, src, ParseMode::Strict).unwrap();
+        let report = state.mass_report().unwrap();
+
+        let total = report.total;
+        assert_eq!(total.commanded, state.filament_volume());
+        assert!((total.deposited - state.deposited_volume()).abs() < 1e-3);
+        // only the pressure left in the nozzle is unaccounted for
+        assert!(total.pending().abs() < total.commanded * 0.02, "{}", report);
+        let sum = report.features.values().map(|b| b.commanded).sum::<f32>();
+        assert!((sum - total.commanded).abs() < 1e-3);
+        assert_eq!(report.layers.len(), 1);
+        assert_eq!(report.layers[0].layer, Some(0));
+
+        // 20mm of filament on half a millimeter does not fit
+        let fill = &report.features["FILL"];
+        assert!(fill.dropped > fill.commanded * 0.8);
+        let drop = report.drops.iter().find(|d| d.line == Some(9)).unwrap();
+        assert_eq!(drop.feature.as_deref(), Some("FILL"));
+        assert_eq!(drop.layer, Some(0));
+        assert!(drop.dropped > 30.0);
+        let dropped = report.drops.iter().map(|d| d.dropped).sum::<f32>();
+        assert!((dropped - total.dropped).abs() < 1e-3);
+    }
+}
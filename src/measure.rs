@@ -0,0 +1,414 @@
+use super::*;
+use serde::Serialize;
+
+/// Axis-aligned box in millimeters.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Extents {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Extents {
+    pub fn size(&self) -> [f32; 3] {
+        [0, 1, 2].map(|i| self.max[i] - self.min[i])
+    }
+}
+
+/// Horizontal slice of the part, as calipers would see it.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct CrossSection {
+    pub z: f32,
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+    /// filled area, mm^2
+    pub area: f32,
+}
+
+impl CrossSection {
+    pub fn size(&self) -> [f32; 2] {
+        [self.max[0] - self.min[0], self.max[1] - self.min[1]]
+    }
+}
+
+/// Filled span along a scan line, millimeters.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Run {
+    pub start: f32,
+    pub end: f32,
+}
+
+impl Run {
+    pub fn width(&self) -> f32 {
+        self.end - self.start
+    }
+}
+
+/// Measurements over any voxel backend. Voxel `i` covers
+/// `[(i - 0.5) * unit, (i + 0.5) * unit)`; positions are relative to
+/// `origin`, so that with the machine home they read as G-code coordinates.
+pub struct Measure<'a, V: Voxel> {
+    voxel: &'a V,
+    unit: f32,
+    origin: Vector3<f32>,
+    min: VoxelIdx,
+    max: VoxelIdx,
+}
+
+impl<'a, V: Voxel> Measure<'a, V> {
+    /// Measures everything within the voxel's bounding box.
+    pub fn new(voxel: &'a V, unit: f32) -> Self {
+        let bb = voxel.bounding_box();
+        Self {
+            voxel,
+            unit,
+            origin: Vector3::zeros(),
+            min: bb.bound_min,
+            max: bb.bound_max,
+        }
+    }
+
+    pub fn with_origin(mut self, origin: Vector3<f32>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Restricts measurements to a box (millimeters, relative to the
+    /// origin), e.g. to leave out the skirt.
+    pub fn with_region(mut self, min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        let lo = self.index(min);
+        let hi = self.index(max);
+        self.min = self.min.bb_max(&lo.bb_min(&hi));
+        self.max = self.max.bb_min(&lo.bb_max(&hi));
+        self
+    }
+
+    fn index(&self, pos: Vector3<f32>) -> VoxelIdx {
+        let p = (pos + self.origin) / self.unit;
+        VoxelIdx::new([p.x.round() as i32, p.y.round() as i32, p.z.round() as i32])
+    }
+
+    fn pos(&self, idx: i32, axis: usize) -> f32 {
+        idx as f32 * self.unit - self.origin[axis]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.voxel.bounding_box().count == 0 || (0..3).any(|i| self.min[i] > self.max[i])
+    }
+
+    /// Occupied voxels of plane `k`, as (x, y) indices.
+    fn plane(&self, k: i32) -> impl Iterator<Item = (i32, i32)> + '_ {
+        (self.min[1]..=self.max[1]).flat_map(move |j| {
+            (self.min[0]..=self.max[0])
+                .filter(move |i| self.voxel.occupied(VoxelIdx::new([*i, j, k])))
+                .map(move |i| (i, j))
+        })
+    }
+
+    /// Outer dimensions of everything in the region.
+    pub fn extents(&self) -> Option<Extents> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut bounds: Option<(VoxelIdx, VoxelIdx)> = None;
+        for k in self.min[2]..=self.max[2] {
+            for (i, j) in self.plane(k) {
+                let p = VoxelIdx::new([i, j, k]);
+                bounds = Some(match bounds {
+                    Some((lo, hi)) => (lo.bb_min(&p), hi.bb_max(&p)),
+                    None => (p, p),
+                });
+            }
+        }
+        let (lo, hi) = bounds?;
+        let half = self.unit / 2.0;
+        Some(Extents {
+            min: [0, 1, 2].map(|i| self.pos(lo[i], i) - half),
+            max: [0, 1, 2].map(|i| self.pos(hi[i], i) + half),
+        })
+    }
+
+    /// X and Y dimensions of the slice at height `z`.
+    pub fn cross_section(&self, z: f32) -> Option<CrossSection> {
+        if self.is_empty() {
+            return None;
+        }
+        let k = self.index(Vector3::new(0.0, 0.0, z))[2];
+        if k < self.min[2] || k > self.max[2] {
+            return None;
+        }
+
+        let mut count = 0;
+        let mut bounds: Option<([i32; 2], [i32; 2])> = None;
+        for (i, j) in self.plane(k) {
+            count += 1;
+            bounds = Some(match bounds {
+                Some((lo, hi)) => ([lo[0].min(i), lo[1].min(j)], [hi[0].max(i), hi[1].max(j)]),
+                None => ([i, j], [i, j]),
+            });
+        }
+        let (lo, hi) = bounds?;
+        let half = self.unit / 2.0;
+        Some(CrossSection {
+            z,
+            min: [0, 1].map(|a| self.pos(lo[a], a) - half),
+            max: [0, 1].map(|a| self.pos(hi[a], a) + half),
+            area: count as f32 * self.unit * self.unit,
+        })
+    }
+
+    /// Filled runs along `axis` (0: X, 1: Y) at height `z`, on the line
+    /// through `at` on the other horizontal axis. The first and the last run
+    /// are the outer walls.
+    pub fn runs(&self, z: f32, axis: usize, at: f32) -> Vec<Run> {
+        assert!(axis < 2, "runs are horizontal");
+        if self.is_empty() {
+            return vec![];
+        }
+        let other = 1 - axis;
+        let mut pos = Vector3::new(0.0, 0.0, z);
+        pos[other] = at;
+        let base = self.index(pos);
+        if base[other] < self.min[other] || base[other] > self.max[other] {
+            return vec![];
+        }
+
+        let half = self.unit / 2.0;
+        let mut runs = vec![];
+        let mut start = None;
+        for i in self.min[axis]..=self.max[axis] + 1 {
+            let mut idx = [base[0], base[1], base[2]];
+            idx[axis] = i;
+            let filled = i <= self.max[axis] && self.voxel.occupied(VoxelIdx::new(idx));
+            match (filled, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    runs.push(Run {
+                        start: self.pos(s, axis) - half,
+                        end: self.pos(i - 1, axis) + half,
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        runs
+    }
+
+    /// Thickness of the two outer walls along `axis`, see `runs`.
+    pub fn wall_thickness(&self, z: f32, axis: usize, at: f32) -> Option<[f32; 2]> {
+        let runs = self.runs(z, axis, at);
+        Some([runs.first()?.width(), runs.last()?.width()])
+    }
+}
+
+/// Thickness of the outer walls on a scan line through the middle of the part.
+#[derive(Clone, Debug, Serialize)]
+pub struct WallMeasurement {
+    pub z: f32,
+    /// 0: X, 1: Y
+    pub axis: usize,
+    pub at: f32,
+    pub thickness: [f32; 2],
+}
+
+/// Calibration print dimensions, millimeters.
+#[derive(Clone, Debug, Serialize)]
+pub struct MeasureReport {
+    pub nominal: [f32; 3],
+    pub extents: Extents,
+    pub sections: Vec<CrossSection>,
+    pub walls: Vec<WallMeasurement>,
+}
+
+impl MeasureReport {
+    /// Measured minus nominal size, per axis.
+    pub fn deviation(&self) -> [f32; 3] {
+        let size = self.extents.size();
+        [0, 1, 2].map(|i| size[i] - self.nominal[i])
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Report<'a> {
+            #[serde(flatten)]
+            report: &'a MeasureReport,
+            size: [f32; 3],
+            deviation: [f32; 3],
+        }
+
+        let report = Report {
+            report: self,
+            size: self.extents.size(),
+            deviation: self.deviation(),
+        };
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+}
+
+impl std::fmt::Display for MeasureReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let size = self.extents.size();
+        let deviation = self.deviation();
+
+        writeln!(
+            f,
+            "{:<6} {:>10} {:>10} {:>10} {:>8}",
+            "axis", "measured", "nominal", "delta", ""
+        )?;
+        for (i, name) in ["X", "Y", "Z"].iter().enumerate() {
+            writeln!(
+                f,
+                "{:<6} {:>8.3}mm {:>8.3}mm {:>+8.3}mm {:>+7.2}%",
+                name,
+                size[i],
+                self.nominal[i],
+                deviation[i],
+                deviation[i] / self.nominal[i] * 100.0
+            )?;
+        }
+
+        if !self.sections.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "{:>8} {:>10} {:>10} {:>12}", "z", "X", "Y", "area")?;
+            for s in &self.sections {
+                let size = s.size();
+                writeln!(
+                    f,
+                    "{:>6.2}mm {:>8.3}mm {:>8.3}mm {:>9.1}mm2",
+                    s.z, size[0], size[1], s.area
+                )?;
+            }
+        }
+
+        if !self.walls.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "outer walls:")?;
+            for w in &self.walls {
+                let (axis, other) = if w.axis == 0 { ("X", "Y") } else { ("Y", "X") };
+                writeln!(
+                    f,
+                    "  along {} at {}={:.2} z={:.2}: {:.3}mm / {:.3}mm",
+                    axis, other, w.at, w.z, w.thickness[0], w.thickness[1]
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Simulates `gcode` and measures the printed objects (skirts and purge lines
+/// are left out when the file labels its objects). Cross-sections are taken
+/// at `heights` (G-code Z), or at a quarter, half and three quarters of the
+/// part's height if empty.
+pub fn measure_gcode<V: Voxel + Default>(
+    gcode: &str,
+    options: &GenerateOptions,
+    nominal: [f32; 3],
+    heights: &[f32],
+) -> Result<MeasureReport> {
+    let mut state = ExtrudeState::<V>::default();
+    options.apply(&mut state)?;
+    state.set_split(SplitBy::Object);
+    simulate_file(&mut state, gcode, options.parse_mode)?;
+
+    let unit = state.params.unit;
+    let home = state.home();
+    let mut measure = Measure::new(state.voxel(), unit).with_origin(home);
+
+    // bounding box of the labelled objects
+    let objects = state
+        .meta()
+        .into_iter()
+        .flat_map(|m| m.iter())
+        .filter(|(_, m)| m.object != 0)
+        .fold(None, |acc: Option<(VoxelIdx, VoxelIdx)>, (p, _)| {
+            Some(match acc {
+                Some((lo, hi)) => (lo.bb_min(p), hi.bb_max(p)),
+                None => (*p, *p),
+            })
+        });
+    if let Some((lo, hi)) = objects {
+        let pos = |p: VoxelIdx| Vector3::from(p.f32()) * unit - home;
+        measure = measure.with_region(pos(lo), pos(hi));
+    }
+
+    let extents = measure
+        .extents()
+        .ok_or_else(|| anyhow::anyhow!("nothing printed in {}", gcode))?;
+
+    let heights = if heights.is_empty() {
+        let (z0, z1) = (extents.min[2], extents.max[2]);
+        [0.25, 0.5, 0.75].map(|t| z0 + (z1 - z0) * t).to_vec()
+    } else {
+        heights.to_vec()
+    };
+    let sections = heights
+        .iter()
+        .filter_map(|z| measure.cross_section(*z))
+        .collect::<Vec<_>>();
+
+    let z = (extents.min[2] + extents.max[2]) / 2.0;
+    let walls = (0..2)
+        .filter_map(|axis| {
+            let other = 1 - axis;
+            let at = (extents.min[other] + extents.max[other]) / 2.0;
+            let thickness = measure.wall_thickness(z, axis, at)?;
+            Some(WallMeasurement {
+                z,
+                axis,
+                at,
+                thickness,
+            })
+        })
+        .collect();
+
+    Ok(MeasureReport {
+        nominal,
+        extents,
+        sections,
+        walls,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_measure() {
+        // hollow 10x6x4 box with 2 voxel walls
+        let mut voxel = RangeSetVoxel::default();
+        for x in 0..10 {
+            for y in 0..6 {
+                for z in 0..4 {
+                    if !(2..8).contains(&x) || !(2..4).contains(&y) {
+                        voxel.add(VoxelIdx::new([x, y, z]));
+                    }
+                }
+            }
+        }
+
+        let measure = Measure::new(&voxel, 0.5);
+        let e = measure.extents().unwrap();
+        assert_eq!(e.size(), [5.0, 3.0, 2.0]);
+        assert_eq!(e.min, [-0.25, -0.25, -0.25]);
+
+        let s = measure.cross_section(1.0).unwrap();
+        assert_eq!(s.size(), [5.0, 3.0]);
+        assert_eq!(s.area, 48.0 * 0.25);
+        assert!(measure.cross_section(5.0).is_none());
+
+        assert_eq!(measure.wall_thickness(1.0, 0, 1.5), Some([1.0, 1.0]));
+        assert_eq!(measure.runs(1.0, 1, 2.0).len(), 2);
+        assert_eq!(
+            measure.runs(1.0, 1, 0.5),
+            vec![Run {
+                start: -0.25,
+                end: 2.75
+            }]
+        );
+
+        let measure = measure.with_region(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 3.0, 2.0));
+        assert_eq!(measure.extents().unwrap().size(), [1.5, 3.0, 2.0]);
+    }
+}
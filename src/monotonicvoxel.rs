@@ -73,6 +73,35 @@ impl Voxel for MonotonicVoxel {
         true
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        let z = coord[2];
+        let Some(ranges) = self.ranges.get_mut(&[coord[0], coord[1]]) else {
+            return false;
+        };
+        let Some(idx) = ranges.iter().position(|r| r.contains(&z)) else {
+            return false;
+        };
+
+        let r = ranges[idx].clone();
+        if r.start == z {
+            ranges[idx].start += 1;
+        } else if r.end == z + 1 {
+            ranges[idx].end -= 1;
+        } else {
+            ranges[idx].end = z;
+            ranges.insert(idx + 1, (z + 1)..r.end);
+        }
+        if ranges[idx].is_empty() {
+            ranges.remove(idx);
+        }
+        if ranges.is_empty() {
+            self.ranges.remove(&[coord[0], coord[1]]);
+        }
+
+        self.bb.remove();
+        true
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let models = self
             .ranges
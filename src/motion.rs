@@ -0,0 +1,40 @@
+/// Duration of a straight move with a trapezoidal velocity profile: it
+/// enters and leaves at `junction` (mm/s), accelerates at `accel` (mm/s^2)
+/// and cruises at `velocity` if the move is long enough.
+pub fn move_time(len: f32, velocity: f32, accel: f32, junction: f32) -> f32 {
+    if len <= 0.0 || velocity <= 0.0 {
+        return 0.0;
+    }
+    let junction = junction.min(velocity);
+    if accel <= 0.0 {
+        return len / velocity;
+    }
+
+    // distance needed to accelerate from junction to cruise speed, and back
+    let ramp = (velocity * velocity - junction * junction) / accel;
+    if ramp <= len {
+        (len - ramp) / velocity + 2.0 * (velocity - junction) / accel
+    } else {
+        // triangle profile: peak speed is never reached
+        let peak = (junction * junction + accel * len).sqrt();
+        2.0 * (peak - junction) / accel
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_move_time() {
+        // 100mm at 50mm/s, 1000mm/s^2 from standstill: 1.25mm ramps each way
+        let t = move_time(100.0, 50.0, 1000.0, 0.0);
+        assert!((t - (97.5 / 50.0 + 0.1)).abs() < 1e-4);
+
+        // too short to reach cruise speed
+        let t = move_time(1.0, 50.0, 1000.0, 0.0);
+        assert!((t - 2.0 * (1.0f32 / 1000.0).sqrt()).abs() < 1e-4);
+
+        assert_eq!(move_time(10.0, 50.0, 0.0, 0.0), 0.2);
+    }
+}
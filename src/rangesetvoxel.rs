@@ -32,6 +32,17 @@ impl Voxel for RangeSetVoxel {
         true
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        if !self.occupied(coord) {
+            return false;
+        }
+
+        let end = coord + VoxelIdx::new([0, 0, 1]);
+        self.ranges.remove(coord..end);
+        self.bb.remove();
+        true
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let mut model = Model::default();
 
@@ -0,0 +1,188 @@
+/// Sagging of material extruded without full support below. Strands reaching
+/// past the edge of the layer below droop with the overhang distance; where
+/// nothing is below at all, bridges sag as a parabola between their anchors
+/// and free ends droop quadratically with the distance from the last
+/// support.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SagParams {
+    /// 1/mm, midspan sag of a bridge is `bridge * span^2`
+    pub bridge: f32,
+    /// 1/mm, droop of a free end is `droop * distance^2`
+    pub droop: f32,
+    /// mm of droop per mm a strand overhangs the support edge
+    pub overhang: f32,
+    /// mm
+    pub max: f32,
+    /// mm/s; slower moves give the strand more time to sag before it sets
+    pub reference_speed: f32,
+    /// part cooling, 0 to 1; full cooling halves the sag
+    pub cooling: f32,
+}
+
+impl Default for SagParams {
+    fn default() -> Self {
+        Self {
+            bridge: 0.003,
+            droop: 0.02,
+            overhang: 0.5,
+            max: 1.0,
+            reference_speed: 25.0,
+            cooling: 1.0,
+        }
+    }
+}
+
+impl SagParams {
+    /// No sagging at all.
+    pub fn disabled() -> Self {
+        Self {
+            bridge: 0.0,
+            droop: 0.0,
+            overhang: 0.0,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.bridge > 0.0 || self.droop > 0.0 || self.overhang > 0.0
+    }
+
+    /// Sag multiplier for a move at `speed` (mm/s).
+    pub fn scale(&self, speed: f32) -> f32 {
+        let speed = (self.reference_speed / speed.max(1e-3)).clamp(0.25, 4.0);
+        speed * (1.0 - 0.5 * self.cooling.clamp(0.0, 1.0))
+    }
+
+    /// Sag (mm) at samples `step` mm apart along a move. `overhang` is, per
+    /// sample, how far (mm) the strand reaches past the support below, or
+    /// `None` in mid-air. `carried` is the mid-air distance at the start of
+    /// the move, left over from the previous one. Returns the sag profile
+    /// and the mid-air distance at the end of the move.
+    pub fn profile(
+        &self,
+        overhang: &[Option<f32>],
+        step: f32,
+        carried: f32,
+        scale: f32,
+    ) -> (Vec<f32>, f32) {
+        let mut sag = vec![0.0; overhang.len()];
+        let mut before = carried;
+        let mut i = 0;
+        while i < overhang.len() {
+            if let Some(d) = overhang[i] {
+                sag[i] = (self.overhang * d * scale).min(self.max);
+                before = 0.0;
+                i += 1;
+                continue;
+            }
+
+            // mid-air run [i, end)
+            let end = (i..overhang.len())
+                .find(|j| overhang[*j].is_some())
+                .unwrap_or(overhang.len());
+            let start = before;
+            for (k, sag) in sag.iter_mut().enumerate().take(end).skip(i) {
+                let x = start + (k - i + 1) as f32 * step;
+                let s = if end < overhang.len() {
+                    // bridge, anchored at both ends
+                    let after = (end - k) as f32 * step;
+                    4.0 * self.bridge * x * after
+                } else {
+                    // free end, still in the air at the end of the move
+                    self.droop * x * x
+                };
+                *sag = (s * scale).min(self.max);
+            }
+            before = start + (end - i) as f32 * step;
+            i = end;
+        }
+        let carried = match overhang.last() {
+            Some(None) => before,
+            _ => 0.0,
+        };
+        (sag, carried)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+    use crate::*;
+
+    #[test]
+    fn test_profile() {
+        let params = SagParams {
+            bridge: 0.01,
+            droop: 0.1,
+            overhang: 0.5,
+            max: 10.0,
+            ..SagParams::default()
+        };
+
+        // a 10mm bridge: deepest in the middle
+        let mut overhang = vec![None; 9];
+        overhang.insert(0, Some(0.0));
+        overhang.push(Some(0.0));
+        let (sag, carried) = params.profile(&overhang, 1.0, 0.0, 1.0);
+        assert_eq!(carried, 0.0);
+        assert_eq!(sag[0], 0.0);
+        assert_eq!(sag[10], 0.0);
+        assert!((sag[5] - 0.01 * 100.0).abs() < 1e-5);
+        assert!((sag[4] - sag[6]).abs() < 1e-5);
+
+        // a free end keeps drooping into the next move
+        let (sag, carried) = params.profile(&[Some(0.2), None, None], 1.0, 0.0, 1.0);
+        assert_eq!(carried, 2.0);
+        assert!((sag[0] - 0.1).abs() < 1e-5);
+        assert!((sag[2] - 0.4).abs() < 1e-5);
+        let (sag, _) = params.profile(&[None], 1.0, carried, 1.0);
+        assert!((sag[0] - 0.9).abs() < 1e-5);
+
+        assert!(params.scale(10.0) > params.scale(50.0));
+    }
+
+    /// Lowest material (mm) at `x`, `y` (mm).
+    fn bottom_at(state: &ExtrudeState<MonotonicVoxel>, x: f32, y: f32) -> Option<f32> {
+        column(state, x, y)
+            .first()
+            .map(|z| *z as f32 * state.params.unit - state.home().z)
+    }
+
+    #[test]
+    fn test_bridge() {
+        // two 2x2mm pillars 1mm high, bridged 18mm apart at 1.2mm
+        let mut src = "M83\nG1 F1200\n".to_string();
+        for layer in 1..=5 {
+            src += &format!("G1 Z{:.1}\n", layer as f32 * 0.2);
+            for x0 in [0.0, 18.0] {
+                for i in 0..=5 {
+                    let y = i as f32 * 0.4;
+                    src += &format!("G1 X{} Y{:.1}\nG1 X{} E0.07\n", x0, y, x0 + 2.0);
+                }
+            }
+        }
+        src += "G1 Z1.2\nG1 X1 Y1\nG1 X19 E0.6\n";
+
+        let run = |sag: SagParams| {
+            simulated(&src, |state| {
+                state.params.sag = sag;
+                // no pressure lag, so nothing strings across the gap
+                state.params.e_alpha = 1.0;
+            })
+        };
+        let flat = run(SagParams::disabled());
+        let sagged = run(SagParams::default());
+
+        // the pillars are the same
+        assert_eq!(bottom_at(&flat, 1.0, 1.0), bottom_at(&sagged, 1.0, 1.0));
+        // the middle of the bridge hangs below the top layer of the pillars
+        let top_layer = 0.8;
+        let flat_mid = bottom_at(&flat, 10.0, 1.0).unwrap();
+        let sagged_mid = bottom_at(&sagged, 10.0, 1.0).unwrap();
+        assert!(flat_mid > top_layer, "{}", flat_mid);
+        assert!(sagged_mid < top_layer, "{}", sagged_mid);
+        // and sags most in the middle
+        assert!(bottom_at(&sagged, 4.0, 1.0).unwrap() > sagged_mid);
+    }
+}
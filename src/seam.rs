@@ -0,0 +1,274 @@
+use nalgebra::Vector3;
+use serde::Serialize;
+
+/// Outer walls, as annotated by `;TYPE:` comments (Cura, PrusaSlicer,
+/// OrcaSlicer, Simplify3D).
+pub fn is_outer_wall_feature(feature: &str) -> bool {
+    let feature = feature.to_ascii_lowercase();
+    [
+        "wall-outer",
+        "outer wall",
+        "external perimeter",
+        "outer perimeter",
+    ]
+    .iter()
+    .any(|name| feature.contains(name))
+}
+
+/// Oozing at the seams of outer wall loops. When a loop ends, the pressure
+/// left in the nozzle leaks out as a blob while the filament retracts; the
+/// nozzle is short of that material when the next loop starts.
+///
+/// Coasting is not modeled here: slicers write it into the G-code as moves
+/// without extrusion, along which the pressure model drains the nozzle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SeamParams {
+    /// fraction of the pressure left in the nozzle which oozes out at the
+    /// end of a loop
+    pub ooze: f32,
+    /// mm of retraction which cuts the ooze to 1/e
+    pub retract: f32,
+    /// mm of a retracting move over which the nozzle wipes the ooze off;
+    /// 0 leaves it as a blob at the seam
+    pub wipe: f32,
+    /// mm of filament the melt fails to refill after a retraction at a
+    /// seam, missing from the start of the next loop
+    pub deficit: f32,
+}
+
+impl Default for SeamParams {
+    fn default() -> Self {
+        Self {
+            ooze: 0.6,
+            retract: 1.0,
+            wipe: 0.0,
+            deficit: 0.0,
+        }
+    }
+}
+
+impl SeamParams {
+    /// No seam blobs.
+    pub fn disabled() -> Self {
+        Self {
+            ooze: 0.0,
+            deficit: 0.0,
+            ..Self::default()
+        }
+    }
+
+    /// Fraction of the remaining pressure which oozes out during a
+    /// retraction of `retract` mm.
+    pub fn ooze(&self, retract: f32) -> f32 {
+        if self.retract <= 0.0 {
+            return 0.0;
+        }
+        self.ooze.clamp(0.0, 1.0) * (-retract.max(0.0) / self.retract).exp()
+    }
+}
+
+/// Where a closed loop ended and started again.
+#[derive(Clone, Debug, Serialize)]
+pub struct Seam {
+    pub layer: Option<usize>,
+    /// source line of the last extrusion of the loop
+    pub line: Option<usize>,
+    /// mm, G-code coordinates
+    pub pos: [f32; 3],
+    /// mm, length of the loop
+    pub length: f32,
+    /// mm^3 oozed out at the seam
+    pub ooze: f32,
+    /// mm^3 missing from the start of the next loop
+    pub deficit: f32,
+}
+
+/// Follows extrusion paths to find closed loops.
+#[derive(Clone, Debug, Default)]
+pub struct SeamTracker {
+    // start, end and length of the current path
+    path: Option<(Vector3<f32>, Vector3<f32>, f32)>,
+    // a loop ended and has not been retracted yet
+    pending: bool,
+    seams: Vec<Seam>,
+}
+
+impl SeamTracker {
+    /// An extrusion from `from` to `to`.
+    pub fn extrude(&mut self, from: Vector3<f32>, to: Vector3<f32>) {
+        let len = (to - from).magnitude();
+        self.path = Some(match self.path {
+            Some((start, _, length)) => (start, to, length + len),
+            None => (from, to, len),
+        });
+        self.pending = false;
+    }
+
+    /// The path ends; records a seam if it closed on itself within `close`
+    /// mm and `outer` says it is an outer wall. Returns whether it did.
+    pub fn end(
+        &mut self,
+        close: f32,
+        outer: bool,
+        layer: Option<usize>,
+        line: Option<usize>,
+    ) -> bool {
+        let Some((start, end, length)) = self.path.take() else {
+            return false;
+        };
+        let gap = (end - start).xy().magnitude();
+        if !outer || gap > close || length < close * 4.0 {
+            return false;
+        }
+        self.seams.push(Seam {
+            layer,
+            line,
+            pos: end.into(),
+            length,
+            ooze: 0.0,
+            deficit: 0.0,
+        });
+        self.pending = true;
+        true
+    }
+
+    /// The filament retracts: returns whether a loop just ended, then
+    /// forgets it.
+    pub fn retract(&mut self) -> bool {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Adds `volume` (mm^3) oozed at the last seam.
+    pub fn add_ooze(&mut self, volume: f32) {
+        if let Some(seam) = self.seams.last_mut() {
+            seam.ooze += volume;
+        }
+    }
+
+    /// Adds `volume` (mm^3) the restart after the last seam is short of.
+    pub fn add_deficit(&mut self, volume: f32) {
+        if let Some(seam) = self.seams.last_mut() {
+            seam.deficit += volume;
+        }
+    }
+
+    pub fn seams(&self) -> &[Seam] {
+        &self.seams
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+    use crate::*;
+
+    #[test]
+    fn test_seam() {
+        assert!(is_outer_wall_feature("WALL-OUTER"));
+        assert!(is_outer_wall_feature("External perimeter"));
+        assert!(!is_outer_wall_feature("WALL-INNER"));
+
+        let mut tracker = SeamTracker::default();
+        let square = [
+            [0.0, 0.0],
+            [10.0, 0.0],
+            [10.0, 10.0],
+            [0.0, 10.0],
+            [0.0, 0.1],
+        ]
+        .map(|[x, y]| Vector3::new(x, y, 0.2));
+        for w in square.windows(2) {
+            tracker.extrude(w[0], w[1]);
+        }
+        assert!(tracker.end(0.4, true, Some(0), Some(5)));
+        assert_eq!(tracker.seams().len(), 1);
+        assert!((tracker.seams()[0].length - 39.9).abs() < 1e-4);
+        assert!(tracker.retract());
+        assert!(!tracker.retract());
+
+        // an open line
+        tracker.extrude(square[0], square[1]);
+        assert!(!tracker.end(0.4, true, None, None));
+        // an inner wall
+        for w in square.windows(2) {
+            tracker.extrude(w[0], w[1]);
+        }
+        assert!(!tracker.end(0.4, false, None, None));
+        assert_eq!(tracker.seams().len(), 1);
+
+        let params = SeamParams::default();
+        assert!((params.ooze(0.0) - 0.6).abs() < 1e-6);
+        assert!(params.ooze(2.0) < params.ooze(1.0));
+        assert_eq!(SeamParams::disabled().ooze(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_retract() {
+        // one outer wall loop, then a retraction and a travel away from it,
+        // either in one move or in two
+        let square = "M83\nG1 X10 Y10 Z0.2 F1200\n\
+            G1 X20 E0.33\nG1 Y20 E0.33\nG1 X10 E0.33\nG1 Y10.1 E0.33\n";
+        let run = |retract: &str, seam: SeamParams| {
+            let src = format!(
+                "{}{}G1 E1 F1200\nG1 X40 E0.33\nG1 Y40 E0.33\nG1 X30 E0.33\n",
+                square, retract
+            );
+            simulated(&src, |state| state.params.seam = seam)
+        };
+        let printed =
+            |state: &ExtrudeState<MonotonicVoxel>, x: f32, y: f32| !column(state, x, y).is_empty();
+
+        let combined = run("G1 X30 Y30 E-1 F6000\n", SeamParams::default());
+        let split = run("G1 E-1 F6000\nG1 X30 Y30\n", SeamParams::default());
+        // the travel takes as long either way
+        let travel = 20.0 * 2f32.sqrt() / 100.0;
+        assert!((combined.wall_seconds() - split.wall_seconds()).abs() < 0.01);
+        assert!(split.wall_seconds() > 4.0 * 10.0 / 20.0 + travel);
+        // and the blob is at the seam, not along the travel
+        for state in [&combined, &split] {
+            let seams = state.seams();
+            assert_eq!(seams.len(), 1);
+            assert!(seams[0].ooze > 0.0);
+            assert!(printed(state, 10.0, 10.0));
+            assert!(!printed(state, 15.0, 15.0));
+        }
+        assert!((combined.seams()[0].ooze - split.seams()[0].ooze).abs() < 1e-4);
+        assert!((combined.deposited_volume() - split.deposited_volume()).abs() < 0.01);
+
+        // a wipe smears it along the move
+        let wipe = SeamParams {
+            wipe: 8.0,
+            ..SeamParams::default()
+        };
+        let wiped = run("G1 X30 Y30 E-1 F6000\n", wipe);
+        // printed anywhere between `a` and `b` mm along the travel
+        let along = |state: &ExtrudeState<MonotonicVoxel>, a: f32, b: f32| {
+            let dir = Vector3::new(20.0, 19.9, 0.0).normalize();
+            let steps = ((b - a) / 0.05) as usize;
+            (0..=steps).any(|i| {
+                let p = Vector3::new(10.0, 10.1, 0.0) + dir * (a + i as f32 * 0.05);
+                printed(state, p.x, p.y)
+            })
+        };
+        assert!(!along(&combined, 1.0, 10.0));
+        assert!(along(&wiped, 1.0, 8.0));
+        assert!(!along(&wiped, 8.5, 12.0));
+
+        // the next line starts short
+        let deficit = SeamParams {
+            deficit: 0.2,
+            ..SeamParams::default()
+        };
+        let short = run("G1 X30 Y30 E-1 F6000\n", deficit);
+        let volume = short.seams()[0].deficit;
+        assert!(volume > 0.4);
+        let missing = combined.deposited_volume() - short.deposited_volume();
+        assert!(
+            (missing - volume).abs() < volume * 0.2,
+            "{} {}",
+            missing,
+            volume
+        );
+    }
+}
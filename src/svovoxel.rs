@@ -260,6 +260,17 @@ impl Voxel for SVOVoxel {
         }
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        let Some(coord1) = to_voxel_idx(coord) else {
+            return false;
+        };
+        if !self.occupied(coord) {
+            return false;
+        }
+        self.bb.remove();
+        self.inner.insert(coord1, false).is_ok()
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let mut model = Model::default();
 
@@ -0,0 +1,186 @@
+use super::*;
+
+/// Physical properties of one extruder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToolParams {
+    /// nozzle position relative to the commanded position, in millimeters;
+    /// slicers which compensate offsets themselves (PrusaSlicer's
+    /// `extruder_offset`) emit coordinates for tool 0
+    pub offset: Vector3<f32>,
+    /// millimeters
+    pub filament_diameter: f32,
+    pub color: [f32; 4],
+}
+
+impl ToolParams {
+    pub fn new(tool: usize) -> Self {
+        Self {
+            offset: Vector3::new(0.0, 0.0, 0.0),
+            filament_diameter: FILAMENT_DIAMETER,
+            color: palette(tool),
+        }
+    }
+
+    /// square millimeters
+    pub fn cross_section(&self) -> f32 {
+        0.25f32 * std::f32::consts::PI * self.filament_diameter * self.filament_diameter
+    }
+}
+
+/// User supplied tool settings, which take precedence over settings found in
+/// the G-code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ToolOverride {
+    pub offset: Option<Vector3<f32>>,
+    pub filament_diameter: Option<f32>,
+    pub color: Option<[f32; 4]>,
+}
+
+impl ToolOverride {
+    pub fn apply(&self, params: &mut ToolParams) {
+        if let Some(offset) = self.offset {
+            params.offset = offset;
+        }
+        if let Some(d) = self.filament_diameter {
+            params.filament_diameter = d;
+        }
+        if let Some(color) = self.color {
+            params.color = color;
+        }
+    }
+}
+
+/// Per-tool settings from PrusaSlicer's config block, one value per tool.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ToolConfig {
+    /// `; filament_diameter = 1.75,2.85`
+    FilamentDiameter(Vec<f32>),
+    /// `; filament_colour = #FF8000;#00FF00`, `None` for empty entries
+    Color(Vec<Option<[f32; 4]>>),
+    /// `; extruder_offset = 0x0,20x0`
+    Offset(Vec<Vector3<f32>>),
+}
+
+impl ToolConfig {
+    pub fn parse(comment: &str) -> Option<Self> {
+        let (key, value) = comment.split_once('=')?;
+        let value = value.trim();
+        match key.trim() {
+            "filament_diameter" => value
+                .split(',')
+                .map(|v| v.trim().parse::<f32>().ok())
+                .collect::<Option<Vec<_>>>()
+                .map(ToolConfig::FilamentDiameter),
+            "filament_colour" => Some(ToolConfig::Color(
+                value
+                    .split(';')
+                    .map(|v| parse_color(v.trim().trim_matches('"')))
+                    .collect(),
+            )),
+            "extruder_offset" => value
+                .split(',')
+                .map(|v| {
+                    let (x, y) = v.trim().split_once('x')?;
+                    Some(Vector3::new(x.parse().ok()?, y.parse().ok()?, 0.0))
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(ToolConfig::Offset),
+            _ => None,
+        }
+    }
+}
+
+/// Per-tool settings from the config comments of a whole file. PrusaSlicer
+/// writes its config block after the print, so they have to be read before
+/// the first move is simulated.
+pub fn scan_tool_config<R: BufRead>(reader: R) -> Result<Vec<ToolConfig>> {
+    let mut out = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if let Some(config) = line
+            .trim_start()
+            .strip_prefix(';')
+            .and_then(ToolConfig::parse)
+        {
+            out.push(config);
+        }
+    }
+    Ok(out)
+}
+
+/// `#RRGGBB` to RGBA in 0..1.
+pub fn parse_color(s: &str) -> Option<[f32; 4]> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| -> Option<f32> {
+        let v = u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()?;
+        Some(v as f32 / 255.0)
+    };
+    Some([channel(0)?, channel(2)?, channel(4)?, 1.0])
+}
+
+/// Wipe and prime towers, as annotated by `;TYPE:` comments.
+pub fn is_purge_feature(feature: &str) -> bool {
+    feature.to_ascii_lowercase().contains("tower")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_trailing_config() {
+        let src = "M83\n\
+                   G1 X0 Y0 Z0.2 F600\n\
+                   G1 X10 E1\n\
+                   T1\n\
+                   G1 X10 Y10 E1\n\
+                   ; filament_diameter = 1.75,2.85\n\
+                   ; extruder_offset = 0x0,20x0\n";
+        assert_eq!(scan_tool_config(src.as_bytes()).unwrap().len(), 2);
+
+        let state = simulated(src, |_| {});
+        let expected = ToolParams::new(0).cross_section()
+            + ToolParams {
+                filament_diameter: 2.85,
+                ..ToolParams::new(1)
+            }
+            .cross_section();
+        assert!((state.filament_volume() - expected).abs() < 1e-3);
+
+        // T1 printed 20mm to the right of where it was commanded
+        let unit = state.params.unit;
+        let max_x = state.voxel().bounding_box().bound_max[0] as f32 * unit - state.home().x;
+        assert!(max_x > 29.0 && max_x < 31.0, "{}", max_x);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let lines = "SET_VELOCITY_LIMIT ACCEL=0\n\
+                     M83\n\
+                     G1 X0 Y0 Z0.2 F1200\n\
+                     G1 X20 E1\n\
+                     G1 Y1\n\
+                     G1 X0 E1\n\
+                     G1 Y2\n\
+                     G1 X20 E1\n";
+        let run = |prefix: &str| simulated(&format!("{}{}", prefix, lines), |_| {});
+        let base = run("");
+        let count = base.voxel().bounding_box().count as f32;
+
+        let half = run("M221 S50\n");
+        let ratio = half.voxel().bounding_box().count as f32 / count;
+        assert!(ratio > 0.4 && ratio < 0.6, "{}", ratio);
+        assert!((half.filament_volume() * 2.0 - base.filament_volume()).abs() < 1e-3);
+        // for another tool only
+        let other = run("M221 S50 T1\n");
+        assert_eq!(other.voxel().bounding_box().count as f32, count);
+
+        let slow = run("M220 S50\n");
+        assert!((slow.wall_seconds() - base.wall_seconds() * 2.0).abs() < 1e-4);
+        assert_eq!(slow.filament_volume(), base.filament_volume());
+    }
+}
@@ -0,0 +1,196 @@
+use super::*;
+use anyhow::anyhow;
+use nalgebra::Matrix3;
+
+/// Where the machine axes point, for printers which are not a plain
+/// Cartesian box. Layers are planes of constant machine Z; the simulation
+/// runs in world coordinates with the bed (or belt) at Z=0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MachineTransform {
+    /// degrees; belt printer with infinite Z: machine Z runs along the
+    /// belt, Y up the gantry which leans back over the printed part at this
+    /// angle to the belt
+    pub belt_angle: Option<f32>,
+    /// axis skew: X moves by `[0]` per mm of Y and by `[1]` per mm of Z, Y
+    /// by `[2]` per mm of Z
+    pub shear: [f32; 3],
+}
+
+impl MachineTransform {
+    pub fn belt(angle: f32) -> Self {
+        Self {
+            belt_angle: Some(angle),
+            ..Self::default()
+        }
+    }
+
+    /// Machine to world coordinates.
+    pub fn matrix(&self) -> Matrix3<f32> {
+        let belt = match self.belt_angle {
+            Some(angle) => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                Matrix3::new(1.0, 0.0, 0.0, 0.0, -cos, 1.0, 0.0, sin, 0.0)
+            }
+            None => Matrix3::identity(),
+        };
+        let [xy, xz, yz] = self.shear;
+        let shear = Matrix3::new(1.0, xy, xz, 0.0, 1.0, yz, 0.0, 0.0, 1.0);
+        shear * belt
+    }
+}
+
+/// Rewrites commanded coordinates into world coordinates. Sits between the
+/// parsed G-code and `ExtrudeState::handle_gcode`, after artifacts.
+#[derive(Clone, Debug)]
+pub struct MachineFrame {
+    matrix: Matrix3<f32>,
+    normal: Vector3<f32>,
+    // commanded position
+    pos: Vector3<f32>,
+}
+
+impl MachineFrame {
+    pub fn new(transform: MachineTransform) -> Result<Self> {
+        let matrix = transform.matrix();
+        let inverse = matrix
+            .try_inverse()
+            .ok_or_else(|| anyhow!("degenerate machine transform: {:?}", transform))?;
+        // gradient of machine Z in world coordinates
+        let normal = inverse.transpose() * Vector3::z();
+        Ok(Self {
+            matrix,
+            normal: normal.normalize(),
+            pos: Vector3::zeros(),
+        })
+    }
+
+    /// World direction from one layer to the next.
+    pub fn layer_normal(&self) -> Vector3<f32> {
+        self.normal
+    }
+
+    /// The world coordinates for the commanded `code`; moves always get all
+    /// of X, Y and Z.
+    pub fn apply(&mut self, code: GCode1Coord) -> GCode1Coord {
+        if ![0, 1, 92].contains(&code.major) {
+            return code;
+        }
+        let given = [code.x, code.y, code.z];
+        for (i, v) in given.iter().enumerate() {
+            if let Some(v) = v {
+                self.pos[i] = *v;
+            }
+        }
+        if code.major == 92 && given.iter().all(|v| v.is_none()) {
+            return code;
+        }
+
+        let world = self.matrix * self.pos;
+        GCode1Coord {
+            x: Some(world.x),
+            y: Some(world.y),
+            z: Some(world.z),
+            ..code
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_transform() {
+        let mut frame = MachineFrame::new(MachineTransform::belt(45.0)).unwrap();
+        let n = frame.layer_normal();
+        let s = 0.5f32.sqrt();
+        assert!((n - Vector3::new(0.0, s, s)).magnitude() < 1e-5);
+
+        // up the gantry: back over the part and away from the belt
+        let out = frame.apply(GCode1Coord {
+            major: 1,
+            y: Some(10.0),
+            z: Some(0.0),
+            ..Default::default()
+        });
+        assert!((out.y.unwrap() + 10.0 * s).abs() < 1e-4);
+        assert!((out.z.unwrap() - 10.0 * s).abs() < 1e-4);
+        // along the belt, X is kept
+        let out = frame.apply(GCode1Coord {
+            major: 1,
+            x: Some(3.0),
+            z: Some(5.0),
+            ..Default::default()
+        });
+        assert_eq!(out.x, Some(3.0));
+        assert!((out.y.unwrap() - (5.0 - 10.0 * s)).abs() < 1e-4);
+
+        let shear = MachineTransform {
+            shear: [0.0, 0.01, 0.0],
+            ..Default::default()
+        };
+        let mut frame = MachineFrame::new(shear).unwrap();
+        let out = frame.apply(GCode1Coord {
+            major: 0,
+            z: Some(100.0),
+            ..Default::default()
+        });
+        assert!((out.x.unwrap() - 1.0).abs() < 1e-5);
+        // skewed, but the layers stay flat
+        assert!((frame.layer_normal() - Vector3::z()).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn test_belt_print() {
+        // lines along X, up the gantry and clear of the belt; one layer
+        // along the belt per 0.2mm
+        let layer = |z: f32| format!("G1 X10 Y5 Z{:.3}\nG1 X30 E0.67\n", z);
+        let dz = 0.2 / 45f32.to_radians().sin();
+        let run = |layers: usize| {
+            let mut src = "M83\nG1 F1200\n".to_string();
+            for i in 0..layers {
+                src += &layer(10.0 + i as f32 * dz);
+            }
+            let state = simulated(&src, |state| {
+                state.params.e_alpha = 1.0;
+                state.params.sag = SagParams::disabled();
+                state.set_transform(MachineTransform::belt(45.0)).unwrap();
+            });
+            (occupied_cells(&state), state.params.unit)
+        };
+        let centroid = |cells: &[&VoxelIdx], unit: f32| {
+            cells
+                .iter()
+                .map(|c| Vector3::from(c.f32()))
+                .sum::<Vector3<f32>>()
+                * unit
+                / cells.len() as f32
+        };
+
+        let (first, unit) = run(1);
+        let (both, _) = run(2);
+        let second = both.difference(&first).collect::<Vec<_>>();
+        assert!(
+            second.len() * 2 > first.len(),
+            "{} {}",
+            second.len(),
+            first.len()
+        );
+
+        // each layer hangs off the nozzle along the normal of the belt,
+        // not straight down
+        let s = 0.5f32.sqrt();
+        let nozzle = Vector3::new(20.0, 10.0 - 5.0 * s, 5.0 * s);
+        let below = centroid(&first.iter().collect::<Vec<_>>(), unit) - nozzle;
+        assert!(
+            below.y < -0.02 && (below.y - below.z).abs() < 0.03,
+            "{:?}",
+            below
+        );
+        // and the next one lies on it, one layer further along
+        let shift = centroid(&second, unit) - centroid(&first.iter().collect::<Vec<_>>(), unit);
+        let normal = Vector3::new(0.0, s, s);
+        assert!((shift.dot(&normal) - 0.2).abs() < 0.05, "{:?}", shift);
+    }
+}
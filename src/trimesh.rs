@@ -0,0 +1,172 @@
+use anyhow::{anyhow, bail, Context, Result};
+use nalgebra::Vector3;
+
+/// Triangle soup loaded from STL or OBJ, in millimeters.
+#[derive(Clone, Debug, Default)]
+pub struct TriMesh {
+    pub triangles: Vec<[Vector3<f32>; 3]>,
+}
+
+impl TriMesh {
+    /// Loads by extension: `.stl` (binary or ASCII) or `.obj`.
+    pub fn load(path: &str) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("stl") => Self::from_stl(&data),
+            Some("obj") => Self::from_obj(std::str::from_utf8(&data)?),
+            _ => bail!("unsupported mesh format: {}", path),
+        }
+    }
+
+    pub fn from_stl(data: &[u8]) -> Result<Self> {
+        // binary files may start with "solid" too, trust the size instead
+        if data.len() >= 84 {
+            let n = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+            if data.len() == 84 + n * 50 {
+                return Ok(Self::from_binary_stl(&data[84..], n));
+            }
+        }
+        Self::from_ascii_stl(std::str::from_utf8(data)?)
+    }
+
+    fn from_binary_stl(data: &[u8], n: usize) -> Self {
+        let f = |o: usize| f32::from_le_bytes([data[o], data[o + 1], data[o + 2], data[o + 3]]);
+        let triangles = (0..n)
+            .map(|i| {
+                // skip the normal
+                let base = i * 50 + 12;
+                let v = |k: usize| {
+                    let o = base + k * 12;
+                    Vector3::new(f(o), f(o + 4), f(o + 8))
+                };
+                [v(0), v(1), v(2)]
+            })
+            .collect();
+        Self { triangles }
+    }
+
+    fn from_ascii_stl(src: &str) -> Result<Self> {
+        let mut triangles = vec![];
+        let mut cur = vec![];
+        for line in src.lines() {
+            let mut tokens = line.split_whitespace();
+            if tokens.next() != Some("vertex") {
+                continue;
+            }
+            cur.push(parse_vec3(&mut tokens).ok_or_else(|| anyhow!("invalid vertex: {}", line))?);
+            if cur.len() == 3 {
+                triangles.push([cur[0], cur[1], cur[2]]);
+                cur.clear();
+            }
+        }
+        Ok(Self { triangles })
+    }
+
+    pub fn from_obj(src: &str) -> Result<Self> {
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+        for line in src.lines() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => vertices.push(
+                    parse_vec3(&mut tokens).ok_or_else(|| anyhow!("invalid vertex: {}", line))?,
+                ),
+                Some("f") => {
+                    let face = tokens
+                        .map(|t| {
+                            // v, v/vt, v/vt/vn or v//vn; negative indices count from the end
+                            let idx: i64 = t.split('/').next()?.parse().ok()?;
+                            let idx = if idx < 0 {
+                                vertices.len() as i64 + idx
+                            } else {
+                                idx - 1
+                            };
+                            vertices.get(usize::try_from(idx).ok()?).copied()
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| anyhow!("invalid face: {}", line))?;
+                    // fan triangulation
+                    for i in 1..face.len().saturating_sub(1) {
+                        triangles.push([face[0], face[i], face[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { triangles })
+    }
+
+    pub fn bounding_box(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let mut vertices = self.triangles.iter().flatten();
+        let first = *vertices.next()?;
+        Some(vertices.fold((first, first), |(min, max), v| (min.inf(v), max.sup(v))))
+    }
+
+    pub fn translate(&mut self, offset: Vector3<f32>) {
+        for v in self.triangles.iter_mut().flatten() {
+            *v += offset;
+        }
+    }
+}
+
+fn parse_vec3<'a, I: Iterator<Item = &'a str>>(tokens: &mut I) -> Option<Vector3<f32>> {
+    let mut next = || tokens.next()?.parse::<f32>().ok();
+    Some(Vector3::new(next()?, next()?, next()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TRIANGLE: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 2.0, 0.5]];
+
+    fn binary_stl(header: &str) -> Vec<u8> {
+        let mut data = header.as_bytes().to_vec();
+        data.resize(80, 0);
+        data.extend(2u32.to_le_bytes());
+        for _ in 0..2 {
+            // normal, vertices and the attribute byte count
+            data.extend([0.0f32; 3].iter().flat_map(|v| v.to_le_bytes()));
+            for v in TRIANGLE.iter().flatten() {
+                data.extend(v.to_le_bytes());
+            }
+            data.extend([0, 0]);
+        }
+        data
+    }
+
+    fn assert_triangles(mesh: &TriMesh, n: usize) {
+        assert_eq!(mesh.triangles.len(), n);
+        for t in &mesh.triangles {
+            let expected = TRIANGLE.map(Vector3::from);
+            assert_eq!(*t, expected);
+        }
+    }
+
+    #[test]
+    fn test_stl() {
+        let mesh = TriMesh::from_stl(&binary_stl("exported")).unwrap();
+        assert_triangles(&mesh, 2);
+        // some exporters start binary files with "solid" as well
+        let mesh = TriMesh::from_stl(&binary_stl("solid part")).unwrap();
+        assert_triangles(&mesh, 2);
+
+        let ascii = "solid part
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 0 2 0.5
+    endloop
+  endfacet
+endsolid part
+";
+        let mesh = TriMesh::from_stl(ascii.as_bytes()).unwrap();
+        assert_triangles(&mesh, 1);
+        assert!(TriMesh::from_ascii_stl("vertex 0 x 0").is_err());
+    }
+}
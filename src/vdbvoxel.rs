@@ -101,6 +101,14 @@ impl Voxel for VDBVoxel {
         true
     }
 
+    fn remove(&mut self, coord: VoxelIdx) -> bool {
+        if !self.occupied(coord) {
+            return false;
+        }
+        self.grid.set(coord[0], coord[1], coord[2], 0);
+        true
+    }
+
     fn to_model(&mut self) -> Vec<Rc<Model>> {
         let mut models = vec![];
 
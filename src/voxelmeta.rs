@@ -0,0 +1,185 @@
+use super::*;
+use ahash::AHashMap;
+
+/// Attributes of a deposited voxel, recorded when it is first filled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VoxelMeta {
+    /// index into `VoxelMetaMap::objects`, 0 when outside of any object
+    pub object: u16,
+    /// extruder which deposited the voxel
+    pub tool: u8,
+    /// part of a wipe/prime tower
+    pub purge: bool,
+    /// layer marker in effect, 0 before the first one
+    pub layer: u32,
+    /// seconds since the start of the print
+    pub time: f32,
+}
+
+/// Distinct colors for exported groups; the first one matches the default material.
+pub fn palette(i: usize) -> [f32; 4] {
+    const COLORS: [[f32; 4]; 6] = [
+        [1.0, 0.2, 0.2, 1.0],
+        [0.2, 0.5, 1.0, 1.0],
+        [0.2, 0.8, 0.3, 1.0],
+        [1.0, 0.8, 0.1, 1.0],
+        [0.7, 0.3, 0.9, 1.0],
+        [0.9, 0.9, 0.9, 1.0],
+    ];
+    COLORS[i % COLORS.len()]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SplitBy {
+    #[default]
+    None,
+    Object,
+    /// one group per extruder, purge towers separately
+    Tool,
+}
+
+/// Side table of per-voxel attributes. Voxel backends only store occupancy,
+/// so anything exported per object has to be tracked here.
+pub struct VoxelMetaMap {
+    objects: Vec<String>,
+    map: AHashMap<VoxelIdx, VoxelMeta>,
+}
+
+impl Default for VoxelMetaMap {
+    fn default() -> Self {
+        Self {
+            objects: vec![String::new()],
+            map: AHashMap::default(),
+        }
+    }
+}
+
+impl VoxelMetaMap {
+    /// Interns an object name; `None` maps to 0. Objects beyond the first
+    /// 65535 are not told apart and map to 0 as well.
+    pub fn object_id(&mut self, name: Option<&str>) -> u16 {
+        let name = match name {
+            Some(name) => name,
+            None => return 0,
+        };
+        if let Some(idx) = self.objects.iter().position(|o| o == name) {
+            return u16::try_from(idx).unwrap_or(0);
+        }
+        match u16::try_from(self.objects.len()) {
+            Ok(id) => {
+                self.objects.push(name.to_string());
+                id
+            }
+            Err(_) => {
+                warn!("too many objects, {} is not tracked separately", name);
+                0
+            }
+        }
+    }
+
+    pub fn object_name(&self, id: u16) -> Option<&str> {
+        match id {
+            0 => None,
+            id => self.objects.get(id as usize).map(|s| s.as_str()),
+        }
+    }
+
+    pub fn insert(&mut self, coord: VoxelIdx, meta: VoxelMeta) {
+        self.map.insert(coord, meta);
+    }
+
+    pub fn remove(&mut self, coord: VoxelIdx) {
+        self.map.remove(&coord);
+    }
+
+    pub fn get(&self, coord: VoxelIdx) -> Option<&VoxelMeta> {
+        self.map.get(&coord)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&VoxelIdx, &VoxelMeta)> {
+        self.map.iter()
+    }
+
+    /// Rebuilds one voxel set per distinct key, in order of the keys.
+    pub fn split<V: Voxel, K: Ord + Copy, F: Fn(&VoxelMeta) -> K>(&self, key: F) -> Vec<(K, V)> {
+        let mut out = std::collections::BTreeMap::<K, V>::new();
+        for (coord, meta) in self.map.iter() {
+            out.entry(key(meta)).or_default().add(*coord);
+        }
+        out.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    // two lines, cube_a along Y=0 and cube_b along Y=20
+    const OBJECTS: &str = "M83\n\
+                           G1 X0 Y0 Z0.2 F1200\n\
+                           ;MESH:cube_a\n\
+                           G1 X10 E0.5\n\
+                           ;MESH:NONMESH\n\
+                           G1 X0 Y20\n\
+                           EXCLUDE_OBJECT_START NAME=cube_b\n\
+                           G1 X10 E0.5\n\
+                           EXCLUDE_OBJECT_END NAME=cube_b\n";
+
+    /// Whether anything was deposited at `x`, `y` (mm).
+    fn printed_at(state: &ExtrudeState<MonotonicVoxel>, x: f32, y: f32) -> bool {
+        !column(state, x, y).is_empty()
+    }
+
+    #[test]
+    fn test_objects() {
+        let all = simulated(OBJECTS, |_| {});
+        let count = all.voxel().bounding_box().count;
+        assert!(printed_at(&all, 5.0, 0.0) && printed_at(&all, 5.0, 20.0));
+
+        let excluded = simulated(OBJECTS, |s| s.exclude_object("CUBE_B"));
+        assert!(printed_at(&excluded, 5.0, 0.0) && !printed_at(&excluded, 5.0, 20.0));
+        let partial = excluded.voxel().bounding_box().count;
+        assert!(partial > 0 && partial < count);
+
+        // excluded by the G-code itself, as a host would
+        let src = format!("EXCLUDE_OBJECT NAME=cube_b\n{}", OBJECTS);
+        let excluded = simulated(&src, |_| {});
+        assert_eq!(excluded.voxel().bounding_box().count, partial);
+
+        let only = simulated(OBJECTS, |s| s.only_object("cube_b"));
+        assert!(!printed_at(&only, 5.0, 0.0) && printed_at(&only, 5.0, 20.0));
+
+        let split = simulated(OBJECTS, |s| s.set_split(SplitBy::Object));
+        assert_eq!(split.voxel().bounding_box().count, count);
+        let groups = split.model_groups().unwrap();
+        let names = groups.iter().map(|g| g.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["none", "cube_a", "cube_b"]);
+        assert!(groups.iter().all(|g| !g.models.is_empty()));
+    }
+
+    #[test]
+    fn test_object_id() {
+        let mut meta = VoxelMetaMap::default();
+        assert_eq!(meta.object_id(None), 0);
+        assert_eq!(meta.object_id(Some("a")), 1);
+        assert_eq!(meta.object_id(Some("b")), 2);
+        assert_eq!(meta.object_id(Some("a")), 1);
+        assert_eq!(meta.object_name(2), Some("b"));
+
+        // all ids taken: new objects are not told apart
+        meta.objects.resize(u16::MAX as usize + 1, String::new());
+        meta.objects[u16::MAX as usize] = "last".to_string();
+        assert_eq!(meta.object_id(Some("last")), u16::MAX);
+        assert_eq!(meta.object_id(Some("c")), 0);
+        assert_eq!(meta.objects.len(), u16::MAX as usize + 1);
+    }
+}
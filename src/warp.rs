@@ -0,0 +1,299 @@
+use super::*;
+use ahash::AHashMap;
+
+/// Simplified thermal contraction of a part as it cools. Every layer
+/// shrinks onto the one below, by as much as that one has cooled since it
+/// was laid down; the mismatch bends the stack like a bimetal strip, most
+/// while it is still thin, and lifts the corners off the bed once the bed
+/// can no longer hold them. Material close to the bed is kept warm and held
+/// in place, so the part also draws in above its base.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WarpParams {
+    /// linear shrinkage from the glass transition to room temperature
+    pub shrink: f32,
+    /// seconds, time constant of a fresh layer cooling down
+    pub cool_time: f32,
+    /// fraction of the shrinkage the bed prevents at its surface
+    pub bed_hold: f32,
+    /// mm, height over which the bed's hold fades out
+    pub bed_depth: f32,
+    /// fraction of the layer mismatch which ends up bending the part
+    pub curl: f32,
+    /// mm of lift the bed adhesion absorbs before a corner comes off
+    pub adhesion: f32,
+}
+
+impl WarpParams {
+    pub fn pla() -> Self {
+        Self {
+            shrink: 0.003,
+            cool_time: 4.0,
+            bed_hold: 0.9,
+            bed_depth: 2.0,
+            curl: 0.02,
+            adhesion: 0.2,
+        }
+    }
+
+    pub fn petg() -> Self {
+        Self {
+            shrink: 0.004,
+            cool_time: 6.0,
+            bed_hold: 0.9,
+            bed_depth: 2.0,
+            curl: 0.03,
+            adhesion: 0.1,
+        }
+    }
+
+    pub fn abs() -> Self {
+        Self {
+            shrink: 0.007,
+            cool_time: 10.0,
+            bed_hold: 0.9,
+            bed_depth: 2.0,
+            curl: 0.05,
+            adhesion: 0.05,
+        }
+    }
+
+    /// Material preset by name: pla, petg, abs (also asa).
+    pub fn preset(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "pla" => Some(Self::pla()),
+            "petg" => Some(Self::petg()),
+            "abs" | "asa" => Some(Self::abs()),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the shrinkage the bed prevents at `z` mm.
+    fn hold(&self, z: f32) -> f32 {
+        if self.bed_depth <= 0.0 {
+            return 0.0;
+        }
+        self.bed_hold.clamp(0.0, 1.0) * (-z.max(0.0) / self.bed_depth).exp()
+    }
+
+    /// Curvature (1/mm) of a stack of layers, each given as (top Z in mm,
+    /// seconds when it was deposited), bottom first. The first layer lies
+    /// on the bed and only carries the rest.
+    pub fn curvature(&self, layers: &[(f32, f32)]) -> f32 {
+        let mut kappa = 0.0;
+        for w in layers.windows(2) {
+            let ((z0, t0), (z1, t1)) = (w[0], w[1]);
+            let thickness = z1 - z0;
+            if thickness <= 0.0 {
+                continue;
+            }
+            let cooled = 1.0 - (-(t1 - t0).max(0.0) / self.cool_time.max(1e-3)).exp();
+            let mismatch = self.shrink * cooled * (1.0 - self.hold(z1));
+            // Stoney: a film on a substrate as thick as the stack below
+            kappa += 6.0 * mismatch * thickness / (z0.max(thickness) * z0.max(thickness));
+        }
+        kappa * self.curl
+    }
+}
+
+struct WarpObject {
+    // mm, footprint center
+    center: [f32; 2],
+    curvature: f32,
+}
+
+/// Displacement of the printed material, per object.
+pub struct WarpField {
+    params: WarpParams,
+    unit: f32,
+    home: Vector3<f32>,
+    objects: AHashMap<u16, WarpObject>,
+}
+
+impl WarpField {
+    /// From the deposition time and layer of every voxel; purge towers stay
+    /// in place.
+    pub fn new(meta: &VoxelMetaMap, params: WarpParams, unit: f32, home: Vector3<f32>) -> Self {
+        #[derive(Default)]
+        struct Sums {
+            xy: [f64; 2],
+            count: usize,
+            // per layer: highest voxel, time sum, voxel count
+            layers: std::collections::BTreeMap<u32, (i32, f64, usize)>,
+        }
+
+        let mut sums = AHashMap::<u16, Sums>::default();
+        for (pos, m) in meta.iter().filter(|(_, m)| !m.purge) {
+            let s = sums.entry(m.object).or_default();
+            s.xy[0] += pos[0] as f64;
+            s.xy[1] += pos[1] as f64;
+            s.count += 1;
+            let layer = s.layers.entry(m.layer).or_insert((pos[2], 0.0, 0));
+            layer.0 = layer.0.max(pos[2]);
+            layer.1 += m.time as f64;
+            layer.2 += 1;
+        }
+
+        let objects = sums
+            .into_iter()
+            .map(|(id, s)| {
+                let n = s.count as f64;
+                let center = [
+                    (s.xy[0] / n) as f32 * unit - home.x,
+                    (s.xy[1] / n) as f32 * unit - home.y,
+                ];
+                let layers = s
+                    .layers
+                    .values()
+                    .map(|(top, t, n)| {
+                        let z = (*top as f32 + 0.5) * unit - home.z;
+                        (z, (*t / *n as f64) as f32)
+                    })
+                    .collect::<Vec<_>>();
+                let curvature = params.curvature(&layers);
+                (id, WarpObject { center, curvature })
+            })
+            .collect();
+
+        Self {
+            params,
+            unit,
+            home,
+            objects,
+        }
+    }
+
+    /// Lift (mm) of the bottom of an object at (x, y).
+    pub fn lift(&self, object: u16, x: f32, y: f32) -> f32 {
+        let Some(o) = self.objects.get(&object) else {
+            return 0.0;
+        };
+        let r2 = (x - o.center[0]).powi(2) + (y - o.center[1]).powi(2);
+        (o.curvature * r2 / 2.0 - self.params.adhesion).max(0.0)
+    }
+
+    /// Displacement (mm) of material of `object` at `pos` (mm, G-code
+    /// coordinates).
+    pub fn displacement(&self, object: u16, pos: Vector3<f32>) -> Vector3<f32> {
+        let Some(o) = self.objects.get(&object) else {
+            return Vector3::zeros();
+        };
+        let shrink = self.params.shrink * (1.0 - self.params.hold(pos.z));
+        Vector3::new(
+            -shrink * (pos.x - o.center[0]),
+            -shrink * (pos.y - o.center[1]),
+            self.lift(object, pos.x, pos.y),
+        )
+    }
+
+    /// Moves the vertices of `model`, which are in voxel units, with the
+    /// material around them.
+    pub fn deform(&self, model: &Model, meta: &VoxelMetaMap) -> Model {
+        model.deformed(|v| {
+            // a vertex is the corner of up to 8 voxels
+            let corner = VoxelIdx::new([v.x as i32, v.y as i32, v.z as i32]);
+            let neighbours = (0..8).map(|i| corner - VoxelIdx::new([i & 1, (i >> 1) & 1, i >> 2]));
+            let Some(m) = neighbours.filter_map(|p| meta.get(p)).find(|m| !m.purge) else {
+                return v;
+            };
+            let pos = v * self.unit - self.home;
+            v + self.displacement(m.object, pos) / self.unit
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_warp() {
+        // 0.2mm layers, 20s apart
+        let layers = (0..=50)
+            .map(|i| (i as f32 * 0.2, i as f32 * 20.0))
+            .collect::<Vec<_>>();
+        let abs = WarpParams::abs().curvature(&layers);
+        let pla = WarpParams::pla().curvature(&layers);
+        assert!(abs > pla * 2.0);
+        // a layer printed on a still warm one hardly pulls
+        let fast = layers
+            .iter()
+            .map(|(z, t)| (*z, t / 20.0))
+            .collect::<Vec<_>>();
+        assert!(WarpParams::abs().curvature(&fast) < abs / 2.0);
+
+        let mut meta = VoxelMetaMap::default();
+        for x in 0..200 {
+            for y in 0..200 {
+                for z in 0..20 {
+                    let m = VoxelMeta {
+                        layer: z as u32 / 2,
+                        time: z as f32 * 10.0,
+                        ..Default::default()
+                    };
+                    meta.insert(VoxelIdx::new([x, y, z]), m);
+                }
+            }
+        }
+        let field = WarpField::new(&meta, WarpParams::abs(), 0.1, Vector3::zeros());
+        // corners lift, the middle stays down
+        assert!(field.lift(0, 0.0, 0.0) > 0.0);
+        assert_eq!(field.lift(0, 10.0, 10.0), 0.0);
+        let d = field.displacement(0, Vector3::new(20.0, 10.0, 1.9));
+        assert!(d.x < 0.0 && d.y.abs() < -d.x / 100.0);
+    }
+
+    #[test]
+    fn test_warp_print() {
+        // a 40x2mm strip, 10 layers, 20s each
+        let mut src = "M83\nG1 F3000\n".to_string();
+        for layer in 1..=10 {
+            src += &format!(";LAYER:{}\nG1 Z{:.1}\n", layer - 1, layer as f32 * 0.2);
+            for i in 0..5 {
+                let y = 10.0 + i as f32 * 0.4;
+                src += &format!("G1 X10 Y{:.1}\nG1 X50 E1.33\n", y);
+            }
+            src += "G4 S20\n";
+        }
+
+        let run = |warp: Option<WarpParams>| {
+            simulated(&src, |state| {
+                state.params.e_alpha = 1.0;
+                if let Some(warp) = warp {
+                    state.enable_warp(warp);
+                }
+            })
+        };
+
+        let mut plain = run(None);
+        assert!(plain.meta.is_none());
+        let mut state = run(Some(WarpParams::abs()));
+        let meta = state.meta.as_ref().unwrap();
+        let field = WarpField::new(meta, WarpParams::abs(), state.params.unit, state.home);
+        // the ends of the strip come off the bed
+        assert!(field.lift(0, 10.0, 12.0) > 0.0);
+        assert_eq!(field.lift(0, 30.0, 12.0), 0.0);
+
+        // the same voxels, but the exported mesh curls up at the ends
+        let model = state.mv.to_model();
+        assert_eq!(model.len(), plain.mv.to_model().len());
+        let unit = state.params.unit;
+        let lowest_at_end = |vertices: &[[f32; 3]]| {
+            vertices
+                .iter()
+                .filter(|v| v[0] * unit - state.home.x < 11.0)
+                .map(|v| v[2] * unit - state.home.z)
+                .fold(f32::MAX, f32::min)
+        };
+        let before = model
+            .iter()
+            .map(|m| lowest_at_end(&m.deformed(|v| v).raw_vertices))
+            .fold(f32::MAX, f32::min);
+        let after = model
+            .iter()
+            .map(|m| lowest_at_end(&field.deform(m, meta).raw_vertices))
+            .fold(f32::MAX, f32::min);
+        assert!(before < 0.15, "{}", before);
+        assert!(after > before + 0.05, "{} {}", before, after);
+    }
+}